@@ -8,6 +8,9 @@
 #include "mbedtls/debug.h"
 #include "mbedtls/ctr_drbg.h"
 #include "psa/crypto_values.h"
+#include "mbedtls/error.h"
+#include "mbedtls/version.h"
+#include "mbedtls/x509_csr.h"
 
 // Provides a function prototype to generate bindings for mbedtls_mpi_exp_mod_soft()
 #if defined(MBEDTLS_MPI_EXP_MOD_ALT_FALLBACK)
@@ -948,6 +948,8 @@ pub const MBEDTLS_CTR_DRBG_MAX_REQUEST: u32 = 1024;
 pub const MBEDTLS_CTR_DRBG_MAX_SEED_INPUT: u32 = 384;
 pub const MBEDTLS_CTR_DRBG_PR_OFF: u32 = 0;
 pub const MBEDTLS_CTR_DRBG_PR_ON: u32 = 1;
+pub const MBEDTLS_ERR_ERROR_GENERIC_ERROR: i32 = -1;
+pub const MBEDTLS_ERR_ERROR_CORRUPTION_DETECTED: i32 = -110;
 pub const MBEDTLS_CTR_DRBG_ENTROPY_NONCE_LEN: u32 = 0;
 pub type int_least64_t = i64;
 pub type uint_least64_t = u64;
@@ -22492,6 +22494,268 @@ extern "C" {
     /// \return              \c 1 on failure.
     pub fn mbedtls_ctr_drbg_self_test(verbose: crate::c_types::c_int) -> crate::c_types::c_int;
 }
+extern "C" {
+    /// \brief Translate an Mbed TLS error code into a string representation.
+    ///        The result is truncated if necessary and always includes a
+    ///        terminating null byte.
+    ///
+    /// \param errnum    error code
+    /// \param buffer    buffer to place representation in
+    /// \param buflen    length of the buffer
+    pub fn mbedtls_strerror(
+        errnum: crate::c_types::c_int,
+        buffer: *mut crate::c_types::c_char,
+        buflen: usize,
+    );
+}
+extern "C" {
+    /// \brief Translate the high-level part of an Mbed TLS error code into a string
+    ///        representation.
+    ///
+    /// This function returns a const pointer to an un-modifiable string. The caller
+    /// must not try to modify the string. It is intended to be used mostly for
+    /// logging purposes.
+    ///
+    /// \param error_code    error code
+    ///
+    /// \return The string representation of the error code, or \c NULL if the error
+    ///         code is unknown.
+    pub fn mbedtls_high_level_strerr(
+        error_code: crate::c_types::c_int,
+    ) -> *const crate::c_types::c_char;
+}
+extern "C" {
+    /// \brief Translate the low-level part of an Mbed TLS error code into a string
+    ///        representation.
+    ///
+    /// This function returns a const pointer to an un-modifiable string. The caller
+    /// must not try to modify the string. It is intended to be used mostly for
+    /// logging purposes.
+    ///
+    /// \param error_code    error code
+    ///
+    /// \return The string representation of the error code, or \c NULL if the error
+    ///         code is unknown.
+    pub fn mbedtls_low_level_strerr(
+        error_code: crate::c_types::c_int,
+    ) -> *const crate::c_types::c_char;
+}
+extern "C" {
+    /// Get the version number.
+    ///
+    /// \return          The constructed version number in the format
+    ///                  MMNNPP00 (Major, Minor, Patch).
+    pub fn mbedtls_version_get_number() -> crate::c_types::c_uint;
+}
+extern "C" {
+    /// Get the version string ("x.y.z").
+    ///
+    /// \param string    The string that will receive the value.
+    ///                  (Should be at least 9 bytes in size)
+    pub fn mbedtls_version_get_string(string: *mut crate::c_types::c_char);
+}
+extern "C" {
+    /// Get the full version string ("mbed TLS x.y.z").
+    ///
+    /// \param string    The string that will receive the value. The mbed TLS version
+    ///                  string will use 18 bytes AT MOST including a terminating
+    ///                  null byte.
+    ///                  (So the buffer should be at least 18 bytes to receive this
+    ///                  version string).
+    pub fn mbedtls_version_get_string_full(string: *mut crate::c_types::c_char);
+}
+extern "C" {
+    /// \brief           Check if support for a feature was compiled into this
+    ///                  Mbed TLS binary. This allows you to see at runtime if the
+    ///                  library was for instance compiled with or without
+    ///                  Multi-threading support.
+    ///
+    /// \note            only checks against defines in the sections "System
+    ///                  support", "Mbed TLS modules" and "Mbed TLS feature
+    ///                  support" in mbedtls_config.h
+    ///
+    /// \param feature   The string for the define to check (e.g. "MBEDTLS_AES_C")
+    ///
+    /// \return          0 if the feature is present,
+    ///                  -1 if the feature is not present and
+    ///                  -2 if support for feature checking as a whole was not
+    ///                  compiled in.
+    pub fn mbedtls_version_check_feature(
+        feature: *const crate::c_types::c_char,
+    ) -> crate::c_types::c_int;
+}
+/// Container for writing a CSR
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct mbedtls_x509write_csr {
+    pub private_key: *mut mbedtls_pk_context,
+    pub private_subject: *mut mbedtls_asn1_named_data,
+    pub private_md_alg: mbedtls_md_type_t,
+    pub private_extensions: *mut mbedtls_asn1_named_data,
+}
+extern "C" {
+    /// \brief           CSR context initialization
+    ///
+    /// \param ctx       CSR context to initialize
+    pub fn mbedtls_x509write_csr_init(ctx: *mut mbedtls_x509write_csr);
+}
+extern "C" {
+    /// \brief           Set the subject name for a CSR
+    ///                  Subject names should contain a comma-separated list
+    ///                  of OID types and values:
+    ///                  e.g. "C=UK,O=ARM,CN=mbed TLS Server 1"
+    ///
+    /// \param ctx           CSR context to use
+    /// \param subject_name  subject name to set
+    ///
+    /// \return          0 if subject name was parsed successfully, or
+    ///                  a specific error code
+    pub fn mbedtls_x509write_csr_set_subject_name(
+        ctx: *mut mbedtls_x509write_csr,
+        subject_name: *const crate::c_types::c_char,
+    ) -> crate::c_types::c_int;
+}
+extern "C" {
+    /// \brief           Set the key for a CSR (public key will be included,
+    ///                  private key used to sign the CSR when writing it)
+    ///
+    /// \param ctx       CSR context to use
+    /// \param key       Asymmetric key to include
+    pub fn mbedtls_x509write_csr_set_key(
+        ctx: *mut mbedtls_x509write_csr,
+        key: *mut mbedtls_pk_context,
+    );
+}
+extern "C" {
+    /// \brief           Set the MD algorithm to use for the signature
+    ///                  (e.g. MBEDTLS_MD_SHA1)
+    ///
+    /// \param ctx       CSR context to use
+    /// \param md_alg    MD algorithm to use
+    pub fn mbedtls_x509write_csr_set_md_alg(
+        ctx: *mut mbedtls_x509write_csr,
+        md_alg: mbedtls_md_type_t,
+    );
+}
+extern "C" {
+    /// \brief           Set the Key Usage Extension flags
+    ///                  (e.g. MBEDTLS_X509_KU_DIGITAL_SIGNATURE | MBEDTLS_X509_KU_KEY_CERT_SIGN)
+    ///
+    /// \param ctx       CSR context to use
+    /// \param key_usage key usage flags to set
+    ///
+    /// \return          0 if successful, or MBEDTLS_ERR_X509_ALLOC_FAILED
+    ///
+    /// \note            The <code>decipherOnly</code> flag from the Key Usage
+    ///                  extension is represented by bit 8 (i.e.
+    ///                  <code>0x8000</code>), which cannot typically be represented
+    ///                  in an unsigned char. Therefore, the flag
+    ///                  <code>decipherOnly</code> (i.e.
+    ///                  #MBEDTLS_X509_KU_DECIPHER_ONLY) cannot be set using this
+    ///                  function.
+    pub fn mbedtls_x509write_csr_set_key_usage(
+        ctx: *mut mbedtls_x509write_csr,
+        key_usage: crate::c_types::c_uchar,
+    ) -> crate::c_types::c_int;
+}
+extern "C" {
+    /// \brief           Set the Netscape Cert Type flags
+    ///                  (e.g. MBEDTLS_X509_NS_CERT_TYPE_SSL_CLIENT | MBEDTLS_X509_NS_CERT_TYPE_EMAIL)
+    ///
+    /// \param ctx           CSR context to use
+    /// \param ns_cert_type  Netscape Cert Type flags to set
+    ///
+    /// \return          0 if successful, or MBEDTLS_ERR_X509_ALLOC_FAILED
+    pub fn mbedtls_x509write_csr_set_ns_cert_type(
+        ctx: *mut mbedtls_x509write_csr,
+        ns_cert_type: crate::c_types::c_uchar,
+    ) -> crate::c_types::c_int;
+}
+extern "C" {
+    /// \brief           Generic function to add to or replace an extension in the
+    ///                  CSR
+    ///
+    /// \param ctx       CSR context to use
+    /// \param oid       OID of the extension
+    /// \param oid_len   length of the OID
+    /// \param critical  Set to 1 to mark the extension as critical, 0 otherwise.
+    /// \param val       value of the extension OCTET STRING
+    /// \param val_len   length of the value data
+    ///
+    /// \return          0 if successful, or a MBEDTLS_ERR_X509_ALLOC_FAILED
+    pub fn mbedtls_x509write_csr_set_extension(
+        ctx: *mut mbedtls_x509write_csr,
+        oid: *const crate::c_types::c_char,
+        oid_len: usize,
+        critical: crate::c_types::c_int,
+        val: *const crate::c_types::c_uchar,
+        val_len: usize,
+    ) -> crate::c_types::c_int;
+}
+extern "C" {
+    /// \brief           Free the contents of a CSR context
+    ///
+    /// \param ctx       CSR context to free
+    pub fn mbedtls_x509write_csr_free(ctx: *mut mbedtls_x509write_csr);
+}
+extern "C" {
+    /// \brief           Write a CSR (Certificate Signing Request) to a
+    ///                  DER structure
+    ///                  Note: data is written at the end of the buffer! Use the
+    ///                        return value to determine where you should start
+    ///                        using the buffer
+    ///
+    /// \param ctx       CSR to write away
+    /// \param buf       buffer to write to
+    /// \param size      size of the buffer
+    /// \param f_rng     RNG function. This must not be \c NULL.
+    /// \param p_rng     RNG parameter
+    ///
+    /// \return          length of data written if successful, or a specific
+    ///                  error code
+    ///
+    /// \note            \p f_rng is used for the signature operation.
+    pub fn mbedtls_x509write_csr_der(
+        ctx: *mut mbedtls_x509write_csr,
+        buf: *mut crate::c_types::c_uchar,
+        size: usize,
+        f_rng: ::core::option::Option<
+            unsafe extern "C" fn(
+                arg1: *mut crate::c_types::c_void,
+                arg2: *mut crate::c_types::c_uchar,
+                arg3: usize,
+            ) -> crate::c_types::c_int,
+        >,
+        p_rng: *mut crate::c_types::c_void,
+    ) -> crate::c_types::c_int;
+}
+extern "C" {
+    /// \brief           Write a CSR (Certificate Signing Request) to a
+    ///                  PEM string
+    ///
+    /// \param ctx       CSR to write away
+    /// \param buf       buffer to write to
+    /// \param size      size of the buffer
+    /// \param f_rng     RNG function. This must not be \c NULL.
+    /// \param p_rng     RNG parameter
+    ///
+    /// \return          0 if successful, or a specific error code
+    ///
+    /// \note            \p f_rng is used for the signature operation.
+    pub fn mbedtls_x509write_csr_pem(
+        ctx: *mut mbedtls_x509write_csr,
+        buf: *mut crate::c_types::c_uchar,
+        size: usize,
+        f_rng: ::core::option::Option<
+            unsafe extern "C" fn(
+                arg1: *mut crate::c_types::c_void,
+                arg2: *mut crate::c_types::c_uchar,
+                arg3: usize,
+            ) -> crate::c_types::c_int,
+        >,
+        p_rng: *mut crate::c_types::c_void,
+    ) -> crate::c_types::c_int;
+}
 extern "C" {
     pub fn mbedtls_mpi_exp_mod_soft(
         X: *mut mbedtls_mpi,
@@ -0,0 +1,205 @@
+use core::cell::{Cell, RefCell};
+
+use super::*;
+
+/// In-memory cache of the sessions of a server, so that returning clients can resume their
+/// session instead of performing a full handshake
+///
+/// Register it with `with_session_cache()` on a server session. Several sessions can share a
+/// cache, e.g. all the sessions of a server.
+///
+/// The cache holds up to `N` sessions. Once it is full, the least recently used session is
+/// evicted. Each session is kept serialized in `ENTRY_SIZE` bytes, a session that doesn't fit is
+/// not cached. A session takes about 130 bytes, plus the certificate of the client if it sent
+/// one. Each entry needs `ENTRY_SIZE` + 64 bytes of memory.
+///
+/// Only TLS 1.2 sessions are cached, which are identified by their session ID. TLS 1.3 clients
+/// resume with tickets instead, which mbedtls doesn't look up in the cache.
+///
+/// # Examples
+/// ```
+/// fn seconds() -> u64 {
+///     embassy_time::Instant::now().as_secs()
+/// }
+///
+/// let cache = make_static!(SessionCache::<8>::new().with_ttl(3600, seconds));
+/// let session = Session::new(socket, "", Mode::Server, TlsVersion::Tls1_2, certificates)?
+///     .with_session_cache(cache);
+/// ```
+pub struct SessionCache<const N: usize, const ENTRY_SIZE: usize = 256> {
+    entries: RefCell<[CacheEntry<ENTRY_SIZE>; N]>,
+    ttl: Option<(u64, fn() -> u64)>,
+    // Incremented on every use of an entry, to find the least recently used one
+    tick: Cell<u64>,
+}
+
+struct CacheEntry<const ENTRY_SIZE: usize> {
+    id: [u8; 32],
+    // 0 if the entry is empty
+    id_len: usize,
+    created: u64,
+    last_used: u64,
+    len: usize,
+    data: [u8; ENTRY_SIZE],
+}
+
+impl<const ENTRY_SIZE: usize> CacheEntry<ENTRY_SIZE> {
+    const EMPTY: Self = Self {
+        id: [0; 32],
+        id_len: 0,
+        created: 0,
+        last_used: 0,
+        len: 0,
+        data: [0; ENTRY_SIZE],
+    };
+
+    fn matches(&self, id: &[u8]) -> bool {
+        self.id_len != 0 && &self.id[..self.id_len] == id
+    }
+
+    /// Empties the entry, erasing the session it held
+    fn invalidate(&mut self) {
+        self.id_len = 0;
+        self.len = 0;
+        unsafe { mbedtls_platform_zeroize(self.data.as_mut_ptr(), ENTRY_SIZE as u32) };
+    }
+}
+
+impl<const N: usize, const ENTRY_SIZE: usize> SessionCache<N, ENTRY_SIZE> {
+    /// Create an empty cache, whose sessions don't expire
+    pub const fn new() -> Self {
+        Self {
+            entries: RefCell::new([CacheEntry::EMPTY; N]),
+            ttl: None,
+            tick: Cell::new(0),
+        }
+    }
+
+    /// Let cached sessions expire `ttl` after they were created.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a session can be resumed, in the unit of `clock`
+    /// * `clock` - Returns the current time, e.g. in seconds
+    pub const fn with_ttl(mut self, ttl: u64, clock: fn() -> u64) -> Self {
+        self.ttl = Some((ttl, clock));
+        self
+    }
+
+    /// Remove all sessions from the cache, e.g. after the certificate of the server changed
+    pub fn clear(&self) {
+        // The entries are only borrowed while mbedtls calls the getter or the setter, which
+        // can't happen during this call as the cache isn't shared between threads
+        for entry in self.entries.borrow_mut().iter_mut() {
+            entry.invalidate();
+        }
+    }
+
+    fn next_tick(&self) -> u64 {
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+        tick
+    }
+
+    fn expired(&self, entry: &CacheEntry<ENTRY_SIZE>) -> bool {
+        match self.ttl {
+            Some((ttl, clock)) => clock().saturating_sub(entry.created) > ttl,
+            None => false,
+        }
+    }
+
+    /// Registers the cache with `ssl_config`
+    pub(crate) unsafe fn configure(&'static self, ssl_config: *mut mbedtls_ssl_config) {
+        mbedtls_ssl_conf_session_cache(
+            ssl_config,
+            self as *const _ as *mut c_void,
+            Some(Self::get),
+            Some(Self::set),
+        );
+    }
+
+    unsafe extern "C" fn get(
+        data: *mut c_void,
+        session_id: *const c_uchar,
+        session_id_len: usize,
+        session: *mut mbedtls_ssl_session,
+    ) -> c_int {
+        let cache = &*(data as *const Self);
+        let id = core::slice::from_raw_parts(session_id, session_id_len);
+        let mut entries = match cache.entries.try_borrow_mut() {
+            Ok(entries) => entries,
+            Err(_) => return NOT_FOUND,
+        };
+        let entry = match entries.iter_mut().find(|entry| entry.matches(id)) {
+            Some(entry) => entry,
+            None => return NOT_FOUND,
+        };
+        if cache.expired(entry) {
+            entry.invalidate();
+            return NOT_FOUND;
+        }
+
+        entry.last_used = cache.next_tick();
+        mbedtls_ssl_session_load(session, entry.data.as_ptr(), entry.len)
+    }
+
+    unsafe extern "C" fn set(
+        data: *mut c_void,
+        session_id: *const c_uchar,
+        session_id_len: usize,
+        session: *const mbedtls_ssl_session,
+    ) -> c_int {
+        let cache = &*(data as *const Self);
+        let id = core::slice::from_raw_parts(session_id, session_id_len);
+        if id.is_empty() || id.len() > 32 {
+            return MBEDTLS_ERR_SSL_BAD_INPUT_DATA;
+        }
+        let mut entries = match cache.entries.try_borrow_mut() {
+            Ok(entries) => entries,
+            Err(_) => return MBEDTLS_ERR_SSL_INTERNAL_ERROR,
+        };
+
+        // The entry of the same session, else an empty or expired one, else the least recently
+        // used one
+        let entry = entries.iter_mut().min_by_key(|entry| {
+            if entry.matches(id) {
+                (0, 0)
+            } else if entry.id_len == 0 || cache.expired(entry) {
+                (1, 0)
+            } else {
+                (2, entry.last_used)
+            }
+        });
+        let entry = match entry {
+            Some(entry) => entry,
+            // A cache without entries
+            None => return MBEDTLS_ERR_SSL_ALLOC_FAILED,
+        };
+
+        // Erase the evicted session, a shorter one wouldn't overwrite all of it
+        entry.invalidate();
+        let mut len = 0;
+        let res = mbedtls_ssl_session_save(session, entry.data.as_mut_ptr(), ENTRY_SIZE, &mut len);
+        if res != 0 {
+            // Erase the part of the session that was written
+            entry.invalidate();
+            return res;
+        }
+
+        entry.id[..id.len()].copy_from_slice(id);
+        entry.id_len = id.len();
+        entry.created = cache.ttl.map(|(_, clock)| clock()).unwrap_or(0);
+        entry.last_used = cache.next_tick();
+        entry.len = len;
+        0
+    }
+}
+
+impl<const N: usize, const ENTRY_SIZE: usize> Default for SessionCache<N, ENTRY_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returned by the getter of the cache if there is no session, mbedtls only checks for nonzero
+const NOT_FOUND: c_int = 1;
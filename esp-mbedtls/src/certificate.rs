@@ -0,0 +1,324 @@
+use super::*;
+
+/// A chain of parsed X509 certificates
+///
+/// # Examples
+/// Verify that every certificate of a CA bundle could be loaded
+/// ```
+/// const BUNDLE: &[u8] = concat!(include_str!("ca_bundle.pem"), "\0").as_bytes();
+/// let bundle = Certificate::parse(X509::pem(BUNDLE).unwrap()).unwrap();
+/// log::info!("Loaded {} CA certificates", bundle.count());
+/// ```
+pub struct Certificate {
+    crt: *mut mbedtls_x509_crt,
+}
+
+impl Certificate {
+    /// Parses all the certificates contained in `x509` into a chain.
+    ///
+    /// A PEM [X509] can hold several concatenated certificates (e.g. a CA bundle), in which case
+    /// all of them are parsed in order. Certificates that mbedtls doesn't support are skipped,
+    /// use [Certificate::count] to find out how many were loaded.
+    ///
+    /// # Errors
+    ///
+    /// This returns [TlsError::OutOfMemory] if the chain couldn't be allocated, or a
+    /// [TlsError::MbedTlsError] if none of the certificates could be parsed.
+    pub fn parse(x509: X509) -> Result<Self, TlsError> {
+        unsafe {
+            let crt = calloc(1, size_of::<mbedtls_x509_crt>() as u32) as *mut mbedtls_x509_crt;
+            if crt.is_null() {
+                return Err(TlsError::OutOfMemory);
+            }
+            mbedtls_x509_crt_init(crt);
+
+            // Frees the chain if parsing fails
+            let certificate = Self { crt };
+            let failed = parse_x509_chain(crt, &x509)?;
+            if failed > 0 {
+                log::warn!("{failed} certificates could not be parsed");
+            }
+
+            Ok(certificate)
+        }
+    }
+
+    /// Parses a single DER certificate and appends it to the chain.
+    ///
+    /// This builds a chain from certificates stored separately, e.g. in several flash regions,
+    /// without first copying them into one contiguous buffer: parse the first certificate with
+    /// [Certificate::parse] and push the others one after another. mbedtls copies each
+    /// certificate to the heap, so `der` doesn't have to outlive the chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [TlsError::MbedTlsError] if the certificate couldn't be parsed, in which case
+    /// the chain is left unchanged.
+    pub fn push_der(&mut self, der: &[u8]) -> Result<(), TlsError> {
+        let res = unsafe { mbedtls_x509_crt_parse_der(self.crt, der.as_ptr(), der.len()) };
+        if res != 0 {
+            Err(TlsError::MbedTlsError(res))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the number of certificates in the chain
+    pub fn count(&self) -> usize {
+        let mut count = 0;
+        let mut crt = self.crt as *const mbedtls_x509_crt;
+        unsafe {
+            // An empty chain consists of a single certificate without any data
+            while !crt.is_null() && (*crt).raw.len != 0 {
+                count += 1;
+                crt = (*crt).next;
+            }
+        }
+        count
+    }
+
+    /// Returns the Subject Alternative Names of the first certificate of the chain.
+    ///
+    /// Entries other than DNS names, URIs and IP addresses are skipped, as are names that aren't
+    /// valid UTF-8. A certificate without the extension yields no entries.
+    pub fn subject_alt_names(&self) -> impl Iterator<Item = SanEntry<'_>> {
+        unsafe { subject_alt_names(self.crt) }
+    }
+
+    /// Returns the common components of the subject of the first certificate of the chain.
+    pub fn subject(&self) -> NameInfo<'_> {
+        unsafe { NameInfo::from_mbed_tls(&(*self.crt).subject) }
+    }
+
+    /// Returns the common components of the issuer of the first certificate of the chain.
+    pub fn issuer(&self) -> NameInfo<'_> {
+        unsafe { NameInfo::from_mbed_tls(&(*self.crt).issuer) }
+    }
+
+    /// Returns the first certificate of the chain as a [TrustAnchor], e.g. to extract the anchor
+    /// of a root certificate once and store only the anchor.
+    pub fn trust_anchor(&self) -> TrustAnchor<'_> {
+        unsafe {
+            TrustAnchor {
+                subject_dn: buf_bytes(&(*self.crt).subject_raw),
+                spki: buf_bytes(&(*self.crt).pk_raw),
+            }
+        }
+    }
+}
+
+/// The common components of a distinguished name, such as the subject or the issuer of a
+/// certificate
+///
+/// Components that are missing or whose value isn't valid UTF-8 are [None]. If a component
+/// appears several times, the first one is used.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NameInfo<'a> {
+    /// Common Name (CN)
+    pub common_name: Option<&'a str>,
+    /// Organization (O)
+    pub organization: Option<&'a str>,
+    /// Organizational Unit (OU)
+    pub organizational_unit: Option<&'a str>,
+    /// Country (C)
+    pub country: Option<&'a str>,
+}
+
+impl<'a> NameInfo<'a> {
+    // DER encoded OIDs of the attribute types, 2.5.4.x
+    const OID_CN: &'static [u8] = &[0x55, 0x04, 0x03];
+    const OID_C: &'static [u8] = &[0x55, 0x04, 0x06];
+    const OID_O: &'static [u8] = &[0x55, 0x04, 0x0a];
+    const OID_OU: &'static [u8] = &[0x55, 0x04, 0x0b];
+
+    unsafe fn from_mbed_tls(name: &'a mbedtls_x509_name) -> Self {
+        let mut info = Self::default();
+        let mut entry: *const mbedtls_x509_name = name;
+        while !entry.is_null() {
+            let oid = &(*entry).oid;
+            let val = &(*entry).val;
+            entry = (*entry).next;
+            if oid.p.is_null() || val.p.is_null() {
+                continue;
+            }
+
+            let component = match core::slice::from_raw_parts(oid.p as *const u8, oid.len) {
+                Self::OID_CN => &mut info.common_name,
+                Self::OID_C => &mut info.country,
+                Self::OID_O => &mut info.organization,
+                Self::OID_OU => &mut info.organizational_unit,
+                _ => continue,
+            };
+            if component.is_none() {
+                let data = core::slice::from_raw_parts(val.p as *const u8, val.len);
+                *component = core::str::from_utf8(data).ok();
+            }
+        }
+        info
+    }
+}
+
+/// An entry of the Subject Alternative Names of a certificate
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SanEntry<'a> {
+    /// A DNS name, e.g. `example.com` or `*.example.com`
+    Dns(&'a str),
+    /// A URI
+    Uri(&'a str),
+    /// The 4 (IPv4) or 16 (IPv6) bytes of an IP address
+    Ip(&'a [u8]),
+}
+
+impl<'a> SanEntry<'a> {
+    unsafe fn from_mbed_tls(buf: &'a mbedtls_x509_buf) -> Option<Self> {
+        if buf.p.is_null()
+            || buf.tag as u32 & MBEDTLS_ASN1_TAG_CLASS_MASK != MBEDTLS_ASN1_CONTEXT_SPECIFIC
+        {
+            return None;
+        }
+        let data = core::slice::from_raw_parts(buf.p as *const u8, buf.len);
+        match buf.tag as u32 & MBEDTLS_ASN1_TAG_VALUE_MASK {
+            MBEDTLS_X509_SAN_DNS_NAME => core::str::from_utf8(data).ok().map(SanEntry::Dns),
+            MBEDTLS_X509_SAN_UNIFORM_RESOURCE_IDENTIFIER => {
+                core::str::from_utf8(data).ok().map(SanEntry::Uri)
+            }
+            MBEDTLS_X509_SAN_IP_ADDRESS => Some(SanEntry::Ip(data)),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the Subject Alternative Names of `crt`, see [Certificate::subject_alt_names]
+pub(crate) unsafe fn subject_alt_names<'a>(
+    crt: *const mbedtls_x509_crt,
+) -> impl Iterator<Item = SanEntry<'a>> {
+    let mut entry: *const mbedtls_x509_sequence = &(*crt).subject_alt_names;
+    core::iter::from_fn(move || {
+        while !entry.is_null() {
+            let buf = &(*entry).buf;
+            entry = (*entry).next;
+            if let Some(san) = SanEntry::from_mbed_tls(buf) {
+                return Some(san);
+            }
+        }
+        None
+    })
+}
+
+/// A trusted root, reduced to its name and public key
+///
+/// This saves storing the whole root certificate, as only the name and the key are needed to
+/// verify the certificates issued by the root. Use [Certificate::trust_anchor] to get the anchor
+/// of a root certificate.
+///
+/// A peer is trusted if the last certificate of the chain it sends was issued by one of the
+/// anchors, i.e. its issuer is `subject_dn` and its signature can be verified with `spki`.
+/// Properties of the root certificate itself, such as its validity period or name constraints,
+/// aren't checked. As for the certificates of the CA chain, the signature has to use SHA-256 or
+/// a stronger hash, and the key of the anchor has to be an RSA key of at least 2048 bits or an
+/// elliptic curve key on a curve of at least 256 bits.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TrustAnchor<'a> {
+    /// The DER encoded subject of the root (the `Name` sequence)
+    pub subject_dn: &'a [u8],
+    /// The DER encoded `SubjectPublicKeyInfo` of the root, as written by
+    /// `openssl pkey -pubin -outform DER`
+    pub spki: &'a [u8],
+}
+
+impl<'a> TrustAnchor<'a> {
+    /// Returns whether `crt` was issued by this anchor
+    pub(crate) unsafe fn is_issuer_of(&self, crt: &mbedtls_x509_crt) -> bool {
+        if buf_bytes(&crt.issuer_raw) != self.subject_dn {
+            return false;
+        }
+
+        let md_info = mbedtls_md_info_from_type(crt.private_sig_md);
+        if md_info.is_null() {
+            return false;
+        }
+        let mut hash = [0u8; MBEDTLS_MD_MAX_SIZE as usize];
+        if mbedtls_md(md_info, crt.tbs.p, crt.tbs.len, hash.as_mut_ptr()) != 0 {
+            return false;
+        }
+
+        let mut pk: mbedtls_pk_context = core::mem::zeroed();
+        mbedtls_pk_init(&mut pk);
+        let verified = mbedtls_pk_parse_public_key(&mut pk, self.spki.as_ptr(), self.spki.len())
+            == 0
+            && allowed_by_profile(crt.private_sig_md, &pk)
+            && mbedtls_pk_verify_ext(
+                crt.private_sig_pk,
+                crt.private_sig_opts,
+                &mut pk,
+                crt.private_sig_md,
+                hash.as_ptr(),
+                mbedtls_md_get_size(md_info) as usize,
+                crt.private_sig.p,
+                crt.private_sig.len,
+            ) == 0;
+        mbedtls_pk_free(&mut pk);
+        verified
+    }
+}
+
+/// Returns whether the default profile of mbedtls, which it enforces on the CA chain, allows
+/// signatures with `md` by the issuer key `pk`
+unsafe fn allowed_by_profile(md: mbedtls_md_type_t, pk: *const mbedtls_pk_context) -> bool {
+    // MBEDTLS_X509_ID_FLAG
+    let flag = |id: u32| if id == 0 { 0 } else { 1u32 << (id - 1) };
+    let profile = &mbedtls_x509_crt_profile_default;
+    let pk_type = mbedtls_pk_get_type(pk);
+    if profile.allowed_mds & flag(md) == 0 || profile.allowed_pks & flag(pk_type) == 0 {
+        return false;
+    }
+    match pk_type {
+        mbedtls_pk_type_t_MBEDTLS_PK_RSA | mbedtls_pk_type_t_MBEDTLS_PK_RSASSA_PSS => {
+            mbedtls_pk_get_bitlen(pk) >= profile.rsa_min_bitlen as usize
+        }
+        mbedtls_pk_type_t_MBEDTLS_PK_ECKEY
+        | mbedtls_pk_type_t_MBEDTLS_PK_ECKEY_DH
+        | mbedtls_pk_type_t_MBEDTLS_PK_ECDSA => {
+            let keypair = (*pk).private_pk_ctx as *const mbedtls_ecp_keypair;
+            profile.allowed_curves & flag((*keypair).private_grp.id) != 0
+        }
+        _ => false,
+    }
+}
+
+/// Returns the bytes of an mbedtls buffer
+unsafe fn buf_bytes(buf: &mbedtls_x509_buf) -> &[u8] {
+    if buf.p.is_null() {
+        &[]
+    } else {
+        core::slice::from_raw_parts(buf.p as *const u8, buf.len)
+    }
+}
+
+impl Drop for Certificate {
+    fn drop(&mut self) {
+        unsafe {
+            mbedtls_x509_crt_free(self.crt);
+            free(self.crt as *const _);
+        }
+    }
+}
+
+/// Parses every certificate contained in `x509` and appends it to the `crt` chain.
+///
+/// Returns the number of certificates that couldn't be parsed, or an error if none of them
+/// could be parsed.
+pub(crate) unsafe fn parse_x509_chain(
+    crt: *mut mbedtls_x509_crt,
+    x509: &X509,
+) -> Result<usize, TlsError> {
+    let res = mbedtls_x509_crt_parse(crt, x509.as_ptr(), x509.len());
+    if res < 0 {
+        Err(TlsError::MbedTlsError(res))
+    } else {
+        Ok(res as usize)
+    }
+}
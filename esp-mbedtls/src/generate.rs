@@ -0,0 +1,408 @@
+use super::*;
+
+/// Type of a key generated on the device
+///
+/// Only elliptic curve keys can be generated. RSA keys can't, as the precompiled mbedtls is built
+/// without `MBEDTLS_GENPRIME`, which RSA key generation needs.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyType {
+    /// ECDSA with the NIST P-256 curve
+    Secp256r1,
+    /// ECDSA with the NIST P-384 curve
+    Secp384r1,
+    /// ECDSA with the NIST P-521 curve
+    Secp521r1,
+}
+
+impl KeyType {
+    fn to_mbed_tls(&self) -> mbedtls_ecp_group_id {
+        match self {
+            KeyType::Secp256r1 => mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+            KeyType::Secp384r1 => mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP384R1,
+            KeyType::Secp521r1 => mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP521R1,
+        }
+    }
+}
+
+/// Parameters of a certificate generated with [generate_self_signed]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CertParams<'a> {
+    /// Common Name of the subject, at most 64 bytes. It is also added as DNS name to the Subject
+    /// Alternative Names, which clients check the hostname against.
+    pub common_name: &'a str,
+    /// Start of the validity period, in seconds since the Unix epoch (UTC)
+    pub not_before: u64,
+    /// Length of the validity period in days
+    pub validity_days: u32,
+    /// Type of the generated key
+    pub key_type: KeyType,
+}
+
+/// Generate a key and a self-signed certificate for it, e.g. to provision a server on first boot
+/// instead of shipping the same key with every device.
+///
+/// The certificate and the key are written as DER to the end of `cert_buf` and `key_buf`, and
+/// returned as [X509] ready to be used as [Certificates::certificate] and
+/// [Certificates::private_key]. 1024 bytes for the certificate and 256 bytes for the key are
+/// enough for all key types. The key is generated from the hardware RNG.
+///
+/// Generating the key and signing the certificate takes a while, especially for the larger
+/// curves.
+///
+/// # Errors
+///
+/// Returns a [TlsError::MbedTlsError] if the certificate couldn't be generated, in particular
+/// if the `common_name` is too long or a buffer is too small.
+pub fn generate_self_signed<'c, 'k>(
+    params: &CertParams,
+    cert_buf: &'c mut [u8],
+    key_buf: &'k mut [u8],
+) -> Result<(X509<'c>, X509<'k>), TlsError> {
+    let mut name = [0u8; 3 + 2 * MAX_COMMON_NAME_LEN + 1];
+    distinguished_name(params.common_name, &mut name)?;
+    let not_after = params.not_before + params.validity_days as u64 * 86400;
+
+    unsafe {
+        let mut pk: mbedtls_pk_context = core::mem::zeroed();
+        mbedtls_pk_init(&mut pk);
+        let mut crt: mbedtls_x509write_cert = core::mem::zeroed();
+        mbedtls_x509write_crt_init(&mut crt);
+
+        let res = generate_key(params.key_type, &mut pk).and_then(|_| {
+            let key_len = write_key_der(&mut pk, key_buf)?;
+
+            let mut serial = [0u8; 16];
+            rng(core::ptr::null_mut(), serial.as_mut_ptr(), serial.len());
+            // Positive and without leading zero byte, as required for the DER integer
+            serial[0] = (serial[0] & 0x7f) | 0x40;
+
+            mbedtls_x509write_crt_set_version(&mut crt, MBEDTLS_X509_CRT_VERSION_3 as c_int);
+            mbedtls_x509write_crt_set_md_alg(&mut crt, mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            mbedtls_x509write_crt_set_subject_key(&mut crt, &mut pk);
+            mbedtls_x509write_crt_set_issuer_key(&mut crt, &mut pk);
+            error_checked!(mbedtls_x509write_crt_set_serial_raw(
+                &mut crt,
+                serial.as_mut_ptr(),
+                serial.len(),
+            ))?;
+            error_checked!(mbedtls_x509write_crt_set_validity(
+                &mut crt,
+                x509_time(params.not_before).as_ptr() as *const c_char,
+                x509_time(not_after).as_ptr() as *const c_char,
+            ))?;
+            error_checked!(mbedtls_x509write_crt_set_subject_name(
+                &mut crt,
+                name.as_ptr() as *const c_char,
+            ))?;
+            error_checked!(mbedtls_x509write_crt_set_issuer_name(
+                &mut crt,
+                name.as_ptr() as *const c_char,
+            ))?;
+            error_checked!(mbedtls_x509write_crt_set_basic_constraints(&mut crt, 0, -1))?;
+
+            // SEQUENCE { [2] IMPLICIT IA5String }, the lengths are below 128 bytes
+            let dns_name = params.common_name.as_bytes();
+            let mut san = [0u8; 4 + MAX_COMMON_NAME_LEN];
+            san[..4].copy_from_slice(&[0x30, dns_name.len() as u8 + 2, 0x82, dns_name.len() as u8]);
+            san[4..][..dns_name.len()].copy_from_slice(dns_name);
+            error_checked!(mbedtls_x509write_crt_set_extension(
+                &mut crt,
+                OID_SUBJECT_ALT_NAME.as_ptr() as *const c_char,
+                OID_SUBJECT_ALT_NAME.len(),
+                0,
+                san.as_ptr(),
+                dns_name.len() + 4,
+            ))?;
+
+            let res = mbedtls_x509write_crt_der(
+                &mut crt,
+                cert_buf.as_mut_ptr(),
+                cert_buf.len(),
+                Some(rng),
+                core::ptr::null_mut(),
+            );
+            if res < 0 {
+                return Err(TlsError::MbedTlsError(res));
+            }
+            Ok((res as usize, key_len))
+        });
+
+        mbedtls_x509write_crt_free(&mut crt);
+        mbedtls_pk_free(&mut pk);
+
+        let (cert_len, key_len) = res?;
+        let cert: &'c [u8] = cert_buf;
+        let key: &'k [u8] = key_buf;
+        Ok((
+            X509::der(&cert[cert.len() - cert_len..]),
+            X509::der(&key[key.len() - key_len..]),
+        ))
+    }
+}
+
+/// Key usage requested in a Certificate Signing Request, as a set of flags
+///
+/// The CA decides which usages end up in the issued certificate.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyUsage(u8);
+
+impl KeyUsage {
+    /// Signing, e.g. of the TLS 1.3 CertificateVerify or the ECDHE parameters of TLS 1.2
+    pub const DIGITAL_SIGNATURE: Self = Self(MBEDTLS_X509_KU_DIGITAL_SIGNATURE as u8);
+    /// Signatures that can't be repudiated later, also called content commitment
+    pub const NON_REPUDIATION: Self = Self(MBEDTLS_X509_KU_NON_REPUDIATION as u8);
+    /// Encrypting keys, e.g. the premaster secret of RSA key exchange
+    pub const KEY_ENCIPHERMENT: Self = Self(MBEDTLS_X509_KU_KEY_ENCIPHERMENT as u8);
+    /// Encrypting data other than keys
+    pub const DATA_ENCIPHERMENT: Self = Self(MBEDTLS_X509_KU_DATA_ENCIPHERMENT as u8);
+    /// Key agreement, e.g. static ECDH
+    pub const KEY_AGREEMENT: Self = Self(MBEDTLS_X509_KU_KEY_AGREEMENT as u8);
+    /// Signing certificates, only for CAs
+    pub const KEY_CERT_SIGN: Self = Self(MBEDTLS_X509_KU_KEY_CERT_SIGN as u8);
+    /// Signing revocation lists, only for CAs
+    pub const CRL_SIGN: Self = Self(MBEDTLS_X509_KU_CRL_SIGN as u8);
+
+    /// Returns no key usage
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns whether no key usage is set
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns whether all key usages of `other` are set
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for KeyUsage {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Parameters of a Certificate Signing Request generated with [generate_csr]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CsrParams<'a> {
+    /// Subject as comma-separated components, e.g. `C=DE,O=Example,CN=device-0001`. A comma
+    /// inside a value must be escaped as `\,`. At most 255 bytes.
+    pub subject: &'a str,
+    /// Requested key usage. Nothing is requested if empty.
+    pub key_usage: KeyUsage,
+}
+
+/// Generate a private key on the device, e.g. for a Certificate Signing Request created with
+/// [generate_csr]
+///
+/// The key is written as DER to the end of `key_buf` and returned as [X509] ready to be used as
+/// [Certificates::private_key]. 256 bytes are enough for all key types. The key is generated from
+/// the hardware RNG.
+///
+/// # Errors
+///
+/// Returns a [TlsError::MbedTlsError] if the key couldn't be generated or `key_buf` is too small.
+pub fn generate_private_key(key_type: KeyType, key_buf: &mut [u8]) -> Result<X509<'_>, TlsError> {
+    unsafe {
+        let mut pk: mbedtls_pk_context = core::mem::zeroed();
+        mbedtls_pk_init(&mut pk);
+
+        let res = generate_key(key_type, &mut pk).and_then(|_| write_key_der(&mut pk, key_buf));
+
+        mbedtls_pk_free(&mut pk);
+
+        let key_len = res?;
+        let key: &[u8] = key_buf;
+        Ok(X509::der(&key[key.len() - key_len..]))
+    }
+}
+
+/// Generate a Certificate Signing Request (PKCS#10) for `private_key`, to be sent to a CA for
+/// enrollment. Only the CSR leaves the device, the private key doesn't.
+///
+/// The request is signed with SHA-256 and written as DER to the end of `csr_buf`, which is
+/// returned. 512 bytes are enough for EC keys with a typical subject, RSA keys need more.
+///
+/// # Arguments
+///
+/// * `params` - Subject and key usage of the request
+/// * `private_key` - Unencrypted private key, e.g. from [generate_private_key]
+/// * `csr_buf` - Buffer to write the request to
+///
+/// # Errors
+///
+/// Returns [TlsError::PrivateKeyParse] if the key couldn't be parsed, and a
+/// [TlsError::MbedTlsError] if the request couldn't be generated, in particular if the `subject`
+/// is invalid or `csr_buf` is too small.
+pub fn generate_csr<'c>(
+    params: &CsrParams,
+    private_key: X509,
+    csr_buf: &'c mut [u8],
+) -> Result<&'c [u8], TlsError> {
+    let mut subject = [0u8; MAX_SUBJECT_LEN + 1];
+    if params.subject.len() > MAX_SUBJECT_LEN || params.subject.contains('\0') {
+        return Err(TlsError::MbedTlsError(MBEDTLS_ERR_X509_BAD_INPUT_DATA));
+    }
+    subject[..params.subject.len()].copy_from_slice(params.subject.as_bytes());
+
+    unsafe {
+        let mut pk: mbedtls_pk_context = core::mem::zeroed();
+        mbedtls_pk_init(&mut pk);
+        let mut csr: mbedtls_x509write_csr = core::mem::zeroed();
+        mbedtls_x509write_csr_init(&mut csr);
+
+        let res = error_checked!(mbedtls_pk_parse_key(
+            &mut pk,
+            private_key.as_ptr(),
+            private_key.len(),
+            core::ptr::null(),
+            0,
+            // Needed to compute the public key of EC keys that don't include it
+            Some(rng),
+            core::ptr::null_mut(),
+        ))
+        .map_err(|err| match err {
+            TlsError::MbedTlsError(code) => TlsError::PrivateKeyParse(code),
+            err => err,
+        })
+        .and_then(|_| {
+            mbedtls_x509write_csr_set_md_alg(&mut csr, mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+            mbedtls_x509write_csr_set_key(&mut csr, &mut pk);
+            error_checked!(mbedtls_x509write_csr_set_subject_name(
+                &mut csr,
+                subject.as_ptr() as *const c_char,
+            ))?;
+            if !params.key_usage.is_empty() {
+                error_checked!(mbedtls_x509write_csr_set_key_usage(
+                    &mut csr,
+                    params.key_usage.0,
+                ))?;
+            }
+
+            let res = mbedtls_x509write_csr_der(
+                &mut csr,
+                csr_buf.as_mut_ptr(),
+                csr_buf.len(),
+                Some(rng),
+                core::ptr::null_mut(),
+            );
+            if res < 0 {
+                return Err(TlsError::MbedTlsError(res));
+            }
+            Ok(res as usize)
+        });
+
+        mbedtls_x509write_csr_free(&mut csr);
+        mbedtls_pk_free(&mut pk);
+
+        let csr_len = res?;
+        let csr: &'c [u8] = csr_buf;
+        Ok(&csr[csr.len() - csr_len..])
+    }
+}
+
+/// Maximum length of a Common Name (`ub-common-name` of RFC 5280)
+const MAX_COMMON_NAME_LEN: usize = 64;
+
+/// Maximum length of the subject of a Certificate Signing Request
+const MAX_SUBJECT_LEN: usize = 255;
+
+/// DER encoded OID of the Subject Alternative Name extension, 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Writes the null-terminated name `CN=<common_name>` in the format of mbedtls to `buf`
+fn distinguished_name(common_name: &str, buf: &mut [u8]) -> Result<(), TlsError> {
+    if common_name.is_empty()
+        || common_name.len() > MAX_COMMON_NAME_LEN
+        || common_name.contains('\0')
+    {
+        return Err(TlsError::MbedTlsError(MBEDTLS_ERR_X509_BAD_INPUT_DATA));
+    }
+
+    let mut len = 0;
+    for &byte in b"CN=".iter().chain(common_name.as_bytes()) {
+        // A comma would start the next component
+        if byte == b',' {
+            buf[len] = b'\\';
+            len += 1;
+        }
+        buf[len] = byte;
+        len += 1;
+    }
+    buf[len] = 0;
+    Ok(())
+}
+
+/// Generates a key of `key_type` into the initialized `pk`
+pub(crate) unsafe fn generate_key(
+    key_type: KeyType,
+    pk: &mut mbedtls_pk_context,
+) -> Result<(), TlsError> {
+    error_checked!(mbedtls_pk_setup(
+        pk,
+        mbedtls_pk_info_from_type(mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+    ))?;
+    error_checked!(mbedtls_ecp_gen_key(
+        key_type.to_mbed_tls(),
+        pk.private_pk_ctx as *mut mbedtls_ecp_keypair,
+        Some(rng),
+        core::ptr::null_mut(),
+    ))
+}
+
+/// Writes `pk` as DER to the end of `buf`, returning the length
+pub(crate) unsafe fn write_key_der(
+    pk: &mut mbedtls_pk_context,
+    buf: &mut [u8],
+) -> Result<usize, TlsError> {
+    let res = mbedtls_pk_write_key_der(pk, buf.as_mut_ptr(), buf.len());
+    if res < 0 {
+        Err(TlsError::MbedTlsError(res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Formats seconds since the Unix epoch as the null-terminated `YYYYMMDDhhmmss` of mbedtls
+fn x509_time(time: u64) -> [u8; 15] {
+    // Civil date of the days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (time / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let seconds = time % 86400;
+    let fields = [
+        (year as u64, 4),
+        (month as u64, 2),
+        (day as u64, 2),
+        (seconds / 3600, 2),
+        (seconds / 60 % 60, 2),
+        (seconds % 60, 2),
+    ];
+
+    let mut buf = [0u8; 15];
+    let mut pos = 0;
+    for (mut value, digits) in fields {
+        for i in (0..digits).rev() {
+            buf[pos + i] = b'0' + (value % 10) as u8;
+            value /= 10;
+        }
+        pos += digits;
+    }
+    buf
+}
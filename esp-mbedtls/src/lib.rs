@@ -2,12 +2,38 @@
 #![feature(c_variadic)]
 #![allow(incomplete_features)]
 
+use embedded_io::BufRead;
+use embedded_io::Error as _;
 use embedded_io::ErrorType;
 #[doc(hidden)]
 pub use esp_hal as hal;
 use hal::{peripheral::Peripheral, peripherals::RSA, rsa::Rsa};
 
+macro_rules! error_checked {
+    ($block:expr) => {{
+        let res = $block;
+        if res != 0 {
+            Err(TlsError::MbedTlsError(res))
+        } else {
+            Ok(())
+        }
+    }};
+    ($block:expr, $variant:path) => {{
+        let res = $block;
+        if res != 0 {
+            Err($variant(res))
+        } else {
+            Ok(())
+        }
+    }};
+}
+
+mod cache;
+mod certificate;
 mod compat;
+mod generate;
+#[cfg(feature = "loopback")]
+pub mod loopback;
 
 #[cfg(any(feature = "esp32c3", feature = "esp32s2", feature = "esp32s3"))]
 mod bignum;
@@ -15,7 +41,9 @@ mod bignum;
 use core::ffi::CStr;
 use core::mem::size_of;
 
-use compat::StrBuf;
+pub use cache::SessionCache;
+use certificate::{parse_x509_chain, subject_alt_names};
+pub use certificate::{Certificate, NameInfo, SanEntry, TrustAnchor};
 use embedded_io::Read;
 use embedded_io::Write;
 use esp_mbedtls_sys::bindings::*;
@@ -27,6 +55,11 @@ pub use esp_mbedtls_sys::bindings::{
     mbedtls_rsa_self_test,
 };
 use esp_mbedtls_sys::c_types::*;
+pub use generate::{
+    generate_csr, generate_private_key, generate_self_signed, CertParams, CsrParams, KeyType,
+    KeyUsage,
+};
+use rand_core::RngCore;
 
 /// Hold the RSA peripheral for cryptographic operations.
 ///
@@ -37,6 +70,85 @@ use esp_mbedtls_sys::c_types::*;
 /// hardware accelerated RSA driver until the session called with this function is dropped.
 static mut RSA_REF: Option<Rsa<esp_hal::Blocking>> = None;
 
+/// Hardware accelerated RSA shared by all sessions.
+///
+/// While a [HardwareRsa] is alive, the RSA operations of every [Session] use the RSA peripheral,
+/// including sessions that run concurrently. This avoids having to pass the peripheral to each
+/// session with `with_hardware_rsa()`, which isn't possible when several sessions exist at the
+/// same time. The operations are blocking, so the sessions use the peripheral one after another.
+/// Once it is dropped, the sessions fall back to software RSA.
+///
+/// Don't combine it with `with_hardware_rsa()`: dropping such a session disables the hardware
+/// RSA for all sessions.
+///
+/// # Examples
+/// ```
+/// let _rsa = HardwareRsa::new(peripherals.RSA);
+/// // Both sessions use the RSA peripheral
+/// let first = Session::new(first_socket, /* ... */).unwrap();
+/// let second = Session::new(second_socket, /* ... */).unwrap();
+/// ```
+pub struct HardwareRsa<'d> {
+    _rsa: core::marker::PhantomData<&'d mut RSA>,
+}
+
+impl<'d> HardwareRsa<'d> {
+    /// Enable the use of the hardware accelerated RSA peripheral for all sessions.
+    ///
+    /// # Arguments
+    ///
+    /// * `rsa` - The RSA peripheral from the HAL
+    pub fn new(rsa: impl Peripheral<P = RSA> + 'd) -> Self {
+        unsafe { RSA_REF = core::mem::transmute(Some(Rsa::new(rsa, None))) }
+        Self {
+            _rsa: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<'d> Drop for HardwareRsa<'d> {
+    fn drop(&mut self) {
+        unsafe { RSA_REF = None }
+    }
+}
+
+/// Hardware accelerators used by mbedtls, as a set of flags
+///
+/// See `active_accelerators()` on [Session] and `asynch::Session`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Accelerators(u32);
+
+impl Accelerators {
+    /// The RSA peripheral, used for the modular exponentiation of RSA and DHE
+    pub const RSA: Self = Self(1 << 0);
+
+    /// Returns no accelerators, i.e. everything runs in software
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns whether no accelerator is used
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns whether all accelerators of `other` are used
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the accelerators that are currently used by all sessions
+    fn active() -> Self {
+        // The RSA peripheral is only hooked into mbedtls on these chips
+        #[cfg(any(feature = "esp32c3", feature = "esp32s2", feature = "esp32s3"))]
+        if unsafe { RSA_REF.is_some() } {
+            return Self::RSA;
+        }
+        Self::empty()
+    }
+}
+
 // these will come from esp-wifi (i.e. this can only be used together with esp-wifi)
 extern "C" {
     fn free(ptr: *const u8);
@@ -46,18 +158,8 @@ extern "C" {
     fn random() -> u32;
 }
 
-macro_rules! error_checked {
-    ($block:expr) => {{
-        let res = $block;
-        if res != 0 {
-            Err(TlsError::MbedTlsError(res))
-        } else {
-            Ok(())
-        }
-    }};
-}
-
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Mode {
     Client,
     Server,
@@ -73,8 +175,25 @@ impl Mode {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum TlsVersion {
+    /// TLS 1.2
+    ///
+    /// The Extended Master Secret extension (RFC 7627), which protects against the triple
+    /// handshake attack, is always offered and accepted, but mbedtls 3.4 can't require it: a peer
+    /// without it is still accepted. To rule out connections without it, only accept TLS 1.3,
+    /// e.g. with `with_version_range(TlsVersion::Tls1_3, TlsVersion::Tls1_3)`, as TLS 1.3 always
+    /// binds its secrets to the handshake.
+    ///
+    /// TLS False Start (RFC 7918), which lets a client send application data before it received
+    /// the server's Finished, isn't supported, as mbedtls 3 removed it. A TLS 1.3 handshake lets
+    /// the client send data after a single round trip, like TLS 1.2 with False Start, so prefer
+    /// TLS 1.3 to save the round trip on high-latency links.
     Tls1_2,
+    /// TLS 1.3
+    ///
+    /// Sending 0-RTT early data on resumption isn't supported: the bundled mbedtls 3.4 is built
+    /// without `MBEDTLS_SSL_EARLY_DATA` and has no API to write early data as a client.
     Tls1_3,
 }
 
@@ -87,413 +206,2453 @@ impl TlsVersion {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum TlsError {
-    Unknown,
-    OutOfMemory,
-    MbedTlsError(i32),
-    Eof,
-    X509MissingNullTerminator,
-    /// The client has given no certificates for the request
-    NoClientCertificate,
+/// How the certificate of the peer is verified, see [Session::with_verify_mode]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VerifyMode {
+    /// The certificate isn't verified. A server doesn't request a certificate from the client.
+    None,
+    /// The certificate is verified, but the handshake continues if the verification fails. The
+    /// result can be checked with `verify_result()` on the connected session.
+    Optional,
+    /// The handshake fails if the certificate can't be verified
+    Required,
 }
 
-impl embedded_io::Error for TlsError {
-    fn kind(&self) -> embedded_io::ErrorKind {
-        embedded_io::ErrorKind::Other
+impl VerifyMode {
+    fn to_mbed_tls(&self) -> i32 {
+        (match self {
+            VerifyMode::None => MBEDTLS_SSL_VERIFY_NONE,
+            VerifyMode::Optional => MBEDTLS_SSL_VERIFY_OPTIONAL,
+            VerifyMode::Required => MBEDTLS_SSL_VERIFY_REQUIRED,
+        }) as i32
     }
 }
 
-#[allow(unused)]
-pub fn set_debug(level: u32) {
-    #[cfg(not(target_arch = "xtensa"))]
-    unsafe {
-        mbedtls_debug_set_threshold(level as c_int);
-    }
-}
+/// Reasons why the verification of a certificate failed, as a set of flags.
+///
+/// The [core::fmt::Display] implementation lists the description of each flag, like
+/// `mbedtls_x509_crt_verify_info` does.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VerifyFlags(u32);
 
-/// Format type for [X509]
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-enum CertificateFormat {
-    PEM,
-    DER,
-}
+impl VerifyFlags {
+    pub const EXPIRED: Self = Self(MBEDTLS_X509_BADCERT_EXPIRED);
+    pub const REVOKED: Self = Self(MBEDTLS_X509_BADCERT_REVOKED);
+    pub const CN_MISMATCH: Self = Self(MBEDTLS_X509_BADCERT_CN_MISMATCH);
+    pub const NOT_TRUSTED: Self = Self(MBEDTLS_X509_BADCERT_NOT_TRUSTED);
+    pub const CRL_NOT_TRUSTED: Self = Self(MBEDTLS_X509_BADCRL_NOT_TRUSTED);
+    pub const CRL_EXPIRED: Self = Self(MBEDTLS_X509_BADCRL_EXPIRED);
+    pub const MISSING: Self = Self(MBEDTLS_X509_BADCERT_MISSING);
+    pub const SKIP_VERIFY: Self = Self(MBEDTLS_X509_BADCERT_SKIP_VERIFY);
+    pub const OTHER: Self = Self(MBEDTLS_X509_BADCERT_OTHER);
+    pub const FUTURE: Self = Self(MBEDTLS_X509_BADCERT_FUTURE);
+    pub const CRL_FUTURE: Self = Self(MBEDTLS_X509_BADCRL_FUTURE);
+    pub const KEY_USAGE: Self = Self(MBEDTLS_X509_BADCERT_KEY_USAGE);
+    pub const EXT_KEY_USAGE: Self = Self(MBEDTLS_X509_BADCERT_EXT_KEY_USAGE);
+    pub const NS_CERT_TYPE: Self = Self(MBEDTLS_X509_BADCERT_NS_CERT_TYPE);
+    pub const BAD_MD: Self = Self(MBEDTLS_X509_BADCERT_BAD_MD);
+    pub const BAD_PK: Self = Self(MBEDTLS_X509_BADCERT_BAD_PK);
+    pub const BAD_KEY: Self = Self(MBEDTLS_X509_BADCERT_BAD_KEY);
+    pub const CRL_BAD_MD: Self = Self(MBEDTLS_X509_BADCRL_BAD_MD);
+    pub const CRL_BAD_PK: Self = Self(MBEDTLS_X509_BADCRL_BAD_PK);
+    pub const CRL_BAD_KEY: Self = Self(MBEDTLS_X509_BADCRL_BAD_KEY);
 
-/// Holds a X509 certificate
-///
-/// # Examples
-/// Initialize with a PEM certificate
-/// ```
-/// const CERTIFICATE: &[u8] = include_bytes!("certificate.pem");
-/// let cert = X509::pem(CERTIFICATE).unwrap();
-/// ```
-///
-/// Initialize with a DER certificate
-/// ```
-/// const CERTIFICATE: &[u8] = include_bytes!("certificate.der");
-/// let cert = X509::der(CERTIFICATE);
-/// ```
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub struct X509<'a> {
-    bytes: &'a [u8],
-    format: CertificateFormat,
-}
+    /// Descriptions of the flags, as used by `mbedtls_x509_crt_verify_info`
+    const DESCRIPTIONS: [(Self, &'static str); 20] = [
+        (Self::EXPIRED, "The certificate validity has expired"),
+        (
+            Self::REVOKED,
+            "The certificate has been revoked (is on a CRL)",
+        ),
+        (
+            Self::CN_MISMATCH,
+            "The certificate Common Name (CN) does not match with the expected CN",
+        ),
+        (
+            Self::NOT_TRUSTED,
+            "The certificate is not correctly signed by the trusted CA",
+        ),
+        (
+            Self::CRL_NOT_TRUSTED,
+            "The CRL is not correctly signed by the trusted CA",
+        ),
+        (Self::CRL_EXPIRED, "The CRL is expired"),
+        (Self::MISSING, "Certificate was missing"),
+        (Self::SKIP_VERIFY, "Certificate verification was skipped"),
+        (Self::OTHER, "Other reason (can be used by verify callback)"),
+        (
+            Self::FUTURE,
+            "The certificate validity starts in the future",
+        ),
+        (Self::CRL_FUTURE, "The CRL is from the future"),
+        (
+            Self::KEY_USAGE,
+            "Usage does not match the keyUsage extension",
+        ),
+        (
+            Self::EXT_KEY_USAGE,
+            "Usage does not match the extendedKeyUsage extension",
+        ),
+        (
+            Self::NS_CERT_TYPE,
+            "Usage does not match the nsCertType extension",
+        ),
+        (
+            Self::BAD_MD,
+            "The certificate is signed with an unacceptable hash",
+        ),
+        (
+            Self::BAD_PK,
+            "The certificate is signed with an unacceptable PK alg (eg RSA vs ECDSA)",
+        ),
+        (
+            Self::BAD_KEY,
+            "The certificate is signed with an unacceptable key (eg bad curve, RSA too short)",
+        ),
+        (
+            Self::CRL_BAD_MD,
+            "The CRL is signed with an unacceptable hash",
+        ),
+        (
+            Self::CRL_BAD_PK,
+            "The CRL is signed with an unacceptable PK alg (eg RSA vs ECDSA)",
+        ),
+        (
+            Self::CRL_BAD_KEY,
+            "The CRL is signed with an unacceptable key (eg bad curve, RSA too short)",
+        ),
+    ];
 
-impl<'a> X509<'a> {
-    /// Reads certificate in pem format from bytes
-    ///
-    /// # Error
-    /// This function returns [TlsError::X509MissingNullTerminator] if the certificate
-    /// doesn't end with a null-byte.
-    pub fn pem(bytes: &'a [u8]) -> Result<Self, TlsError> {
-        if let Some(len) = X509::get_null(bytes) {
-            // Get a slice of only the certificate bytes including the \0
-            let bytes = unsafe { core::slice::from_raw_parts(bytes.as_ptr(), len + 1) };
-            Ok(Self {
-                bytes,
-                format: CertificateFormat::PEM,
-            })
-        } else {
-            Err(TlsError::X509MissingNullTerminator)
-        }
+    /// Returns no flags, i.e. a successful verification
+    pub const fn empty() -> Self {
+        Self(0)
     }
 
-    /// Reads certificate in der format from bytes
-    ///
-    /// *Note*: This function assumes that the size of the size is the exact
-    /// length of the certificate
-    pub fn der(bytes: &'a [u8]) -> Self {
-        Self {
-            bytes,
-            format: CertificateFormat::DER,
-        }
+    /// Creates the flags from the raw bitmask of mbedtls
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
     }
 
-    /// Returns the bytes of the certificate
-    pub fn data(&self) -> &'a [u8] {
-        self.bytes
+    /// Returns the raw bitmask of mbedtls
+    pub const fn bits(&self) -> u32 {
+        self.0
     }
 
-    /// Returns the length of the certificate
-    pub(crate) fn len(&self) -> usize {
-        self.data().len()
+    /// Returns whether no flag is set
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
     }
 
-    /// Returns a pointer to the data for parsing
-    pub(crate) fn as_ptr(&self) -> *const c_uchar {
-        self.data().as_ptr().cast()
+    /// Returns whether all flags of `other` are set
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
     }
 
-    /// Gets the first null byte in a slice
-    fn get_null(bytes: &[u8]) -> Option<usize> {
-        bytes.iter().position(|&byte| byte == 0)
+    /// Returns whether any flag of `other` is set
+    pub const fn intersects(&self, other: Self) -> bool {
+        self.0 & other.0 != 0
     }
 }
 
-/// Certificates used for a connection.
-///
-/// # Note:
-/// Both [certificate](Certificates::certificate) and [private_key](Certificates::private_key) must be set in pair.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Certificates<'a> {
-    /// Trusted CA (Certificate Authority) chain to be used for certificate
-    /// verification during the SSL/TLS handshake.
-    ///
-    /// Certificates can be chained. When dealing with intermediate CA certificates,
-    /// make sure to include the entire chain up to the root CA.
-    ///
-    /// # Client:
-    /// In Client mode, the CA chain should contain the trusted CA certificates
-    /// that will be used to verify the server's certificate during the handshake.
-    ///
-    /// # Server:
-    /// In server mode, the CA chain should contain the trusted CA certificates
-    /// that will be used to verify the client's certificate during the handshake.
-    /// When set to [None] the server will not request nor perform any verification
-    /// on the client certificates. Only set when you want to use client authentication.
-    pub ca_chain: Option<X509<'a>>,
+impl core::ops::BitOr for VerifyFlags {
+    type Output = Self;
 
-    /// Own certificate chain used for requests
-    /// It should contain in order from the bottom up your certificate chain.
-    /// The top certificate (self-signed) can be omitted.
-    ///
-    /// # Client:
-    /// In client mode, this certificate will be used for client authentication
-    /// when communicating wiht the server. Use [None] if you don't want to use
-    /// client authentication.
-    ///
-    /// # Server:
-    /// In server mode, this will be the certificate given to the client when
-    /// performing a handshake.
-    pub certificate: Option<X509<'a>>,
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
 
-    /// Private key paired with the certificate. Must be set when [Certificates::certificate]
-    /// is not [None]
-    pub private_key: Option<X509<'a>>,
+impl core::ops::BitAnd for VerifyFlags {
+    type Output = Self;
 
-    /// Password used for the private key.
-    /// Use [None] when the private key doesn't have a password.
-    pub password: Option<&'a str>,
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
 }
 
-impl<'a> Default for Certificates<'a> {
-    fn default() -> Self {
-        Self {
-            ca_chain: Default::default(),
-            certificate: Default::default(),
-            private_key: Default::default(),
-            password: Default::default(),
+impl core::fmt::Display for VerifyFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut first = true;
+        for (flag, description) in Self::DESCRIPTIONS {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                f.write_str(description)?;
+                first = false;
+            }
+        }
+        let unknown = self.0
+            & !Self::DESCRIPTIONS
+                .iter()
+                .fold(0, |all, (flag, _)| all | flag.0);
+        if unknown != 0 {
+            if !first {
+                f.write_str(", ")?;
+            }
+            write!(f, "Unknown reason ({unknown:#x})")?;
         }
+        Ok(())
     }
 }
 
-impl<'a> Certificates<'a> {
-    // Initialize the SSL using this set of certificates
-    fn init_ssl(
-        &self,
-        servername: &str,
-        mode: Mode,
-        min_version: TlsVersion,
-    ) -> Result<
-        (
-            *mut mbedtls_ctr_drbg_context,
-            *mut mbedtls_ssl_context,
-            *mut mbedtls_ssl_config,
-            *mut mbedtls_x509_crt,
-            *mut mbedtls_x509_crt,
-            *mut mbedtls_pk_context,
-        ),
-        TlsError,
-    > {
-        // Make sure that both certificate and private_key are either Some() or None
-        assert_eq!(
-            self.certificate.is_some(),
-            self.private_key.is_some(),
-            "Both certificate and private_key must be Some() or None"
-        );
+/// Named curve (TLS group) used for the ECDHE key exchange, see [Session::with_curves]
+///
+/// The curve negotiated for a connection can't be queried: mbedtls only keeps it in its private
+/// handshake parameters, which aren't exposed by the bindings and are freed once the handshake
+/// is over. Log it with [set_debug] if needed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NamedCurve {
+    X25519,
+    X448,
+    Secp256r1,
+    Secp384r1,
+    Secp521r1,
+    Secp256k1,
+    Bp256r1,
+    Bp384r1,
+    Bp512r1,
+}
 
-        unsafe {
-            error_checked!(psa_crypto_init())?;
+impl NamedCurve {
+    fn to_mbed_tls(&self) -> u16 {
+        (match self {
+            NamedCurve::X25519 => MBEDTLS_SSL_IANA_TLS_GROUP_X25519,
+            NamedCurve::X448 => MBEDTLS_SSL_IANA_TLS_GROUP_X448,
+            NamedCurve::Secp256r1 => MBEDTLS_SSL_IANA_TLS_GROUP_SECP256R1,
+            NamedCurve::Secp384r1 => MBEDTLS_SSL_IANA_TLS_GROUP_SECP384R1,
+            NamedCurve::Secp521r1 => MBEDTLS_SSL_IANA_TLS_GROUP_SECP521R1,
+            NamedCurve::Secp256k1 => MBEDTLS_SSL_IANA_TLS_GROUP_SECP256K1,
+            NamedCurve::Bp256r1 => MBEDTLS_SSL_IANA_TLS_GROUP_BP256R1,
+            NamedCurve::Bp384r1 => MBEDTLS_SSL_IANA_TLS_GROUP_BP384R1,
+            NamedCurve::Bp512r1 => MBEDTLS_SSL_IANA_TLS_GROUP_BP512R1,
+        }) as u16
+    }
+}
 
-            let drbg_context = calloc(1, size_of::<mbedtls_ctr_drbg_context>() as u32)
-                as *mut mbedtls_ctr_drbg_context;
-            if drbg_context.is_null() {
-                return Err(TlsError::OutOfMemory);
-            }
+/// Signature algorithm (TLS SignatureScheme) offered for the handshake, see [Session::with_sig_algs]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SigAlg {
+    EcdsaSecp256r1Sha256,
+    EcdsaSecp384r1Sha384,
+    EcdsaSecp521r1Sha512,
+    RsaPssRsaeSha256,
+    RsaPssRsaeSha384,
+    RsaPssRsaeSha512,
+    RsaPkcs1Sha256,
+    RsaPkcs1Sha384,
+    RsaPkcs1Sha512,
+}
 
-            let ssl_context =
-                calloc(1, size_of::<mbedtls_ssl_context>() as u32) as *mut mbedtls_ssl_context;
-            if ssl_context.is_null() {
-                free(drbg_context as *const _);
-                return Err(TlsError::OutOfMemory);
-            }
+impl SigAlg {
+    fn to_mbed_tls(&self) -> u16 {
+        (match self {
+            SigAlg::EcdsaSecp256r1Sha256 => MBEDTLS_TLS1_3_SIG_ECDSA_SECP256R1_SHA256,
+            SigAlg::EcdsaSecp384r1Sha384 => MBEDTLS_TLS1_3_SIG_ECDSA_SECP384R1_SHA384,
+            SigAlg::EcdsaSecp521r1Sha512 => MBEDTLS_TLS1_3_SIG_ECDSA_SECP521R1_SHA512,
+            SigAlg::RsaPssRsaeSha256 => MBEDTLS_TLS1_3_SIG_RSA_PSS_RSAE_SHA256,
+            SigAlg::RsaPssRsaeSha384 => MBEDTLS_TLS1_3_SIG_RSA_PSS_RSAE_SHA384,
+            SigAlg::RsaPssRsaeSha512 => MBEDTLS_TLS1_3_SIG_RSA_PSS_RSAE_SHA512,
+            SigAlg::RsaPkcs1Sha256 => MBEDTLS_TLS1_3_SIG_RSA_PKCS1_SHA256,
+            SigAlg::RsaPkcs1Sha384 => MBEDTLS_TLS1_3_SIG_RSA_PKCS1_SHA384,
+            SigAlg::RsaPkcs1Sha512 => MBEDTLS_TLS1_3_SIG_RSA_PKCS1_SHA512,
+        }) as u16
+    }
+}
 
-            let ssl_config =
-                calloc(1, size_of::<mbedtls_ssl_config>() as u32) as *mut mbedtls_ssl_config;
-            if ssl_config.is_null() {
-                free(ssl_context as *const _);
-                return Err(TlsError::OutOfMemory);
-            }
+/// Hash algorithm of a signature, see [SignatureAlgorithm]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+    Ripemd160,
+}
 
-            let crt = calloc(1, size_of::<mbedtls_x509_crt>() as u32) as *mut mbedtls_x509_crt;
-            if crt.is_null() {
-                free(drbg_context as *const _);
-                free(ssl_context as *const _);
-                free(ssl_config as *const _);
-                return Err(TlsError::OutOfMemory);
-            }
+impl HashAlgorithm {
+    #[allow(non_upper_case_globals)]
+    fn from_mbed_tls(md: mbedtls_md_type_t) -> Option<Self> {
+        Some(match md {
+            mbedtls_md_type_t_MBEDTLS_MD_MD5 => HashAlgorithm::Md5,
+            mbedtls_md_type_t_MBEDTLS_MD_SHA1 => HashAlgorithm::Sha1,
+            mbedtls_md_type_t_MBEDTLS_MD_SHA224 => HashAlgorithm::Sha224,
+            mbedtls_md_type_t_MBEDTLS_MD_SHA256 => HashAlgorithm::Sha256,
+            mbedtls_md_type_t_MBEDTLS_MD_SHA384 => HashAlgorithm::Sha384,
+            mbedtls_md_type_t_MBEDTLS_MD_SHA512 => HashAlgorithm::Sha512,
+            mbedtls_md_type_t_MBEDTLS_MD_RIPEMD160 => HashAlgorithm::Ripemd160,
+            _ => return None,
+        })
+    }
+}
 
-            let certificate =
-                calloc(1, size_of::<mbedtls_x509_crt>() as u32) as *mut mbedtls_x509_crt;
-            if certificate.is_null() {
-                free(drbg_context as *const _);
-                free(ssl_context as *const _);
-                free(ssl_config as *const _);
-                free(crt as *const _);
-                return Err(TlsError::OutOfMemory);
-            }
+/// Public key algorithm of a signature, see [SignatureAlgorithm]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SignatureKey {
+    /// RSA with PKCS#1 v1.5 padding
+    Rsa,
+    /// RSA with PSS padding
+    RsaPss,
+    Ecdsa,
+}
 
-            let private_key =
-                calloc(1, size_of::<mbedtls_pk_context>() as u32) as *mut mbedtls_pk_context;
-            if private_key.is_null() {
-                free(drbg_context as *const _);
-                free(ssl_context as *const _);
-                free(ssl_config as *const _);
-                free(crt as *const _);
-                free(certificate as *const _);
-                return Err(TlsError::OutOfMemory);
+impl SignatureKey {
+    #[allow(non_upper_case_globals)]
+    fn from_mbed_tls(pk: mbedtls_pk_type_t) -> Option<Self> {
+        Some(match pk {
+            mbedtls_pk_type_t_MBEDTLS_PK_RSA => SignatureKey::Rsa,
+            mbedtls_pk_type_t_MBEDTLS_PK_RSASSA_PSS => SignatureKey::RsaPss,
+            mbedtls_pk_type_t_MBEDTLS_PK_ECDSA | mbedtls_pk_type_t_MBEDTLS_PK_ECKEY => {
+                SignatureKey::Ecdsa
             }
+            _ => return None,
+        })
+    }
+}
 
-            mbedtls_ssl_init(ssl_context);
-            mbedtls_ssl_config_init(ssl_config);
-            // Initialize CA chain
-            mbedtls_x509_crt_init(crt);
-            // Initialize certificate
-            mbedtls_x509_crt_init(certificate);
-            // Initialize private key
-            mbedtls_pk_init(private_key);
-            (*ssl_config).private_f_dbg = Some(dbg_print);
-            // Init RNG
-            mbedtls_ctr_drbg_init(drbg_context);
-            mbedtls_ssl_conf_rng(ssl_config, Some(rng), drbg_context as *mut c_void);
+/// Algorithm a certificate was signed with, e.g. ECDSA with SHA-256
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SignatureAlgorithm {
+    pub key: SignatureKey,
+    pub hash: HashAlgorithm,
+}
 
-            error_checked!(mbedtls_ssl_config_defaults(
-                ssl_config,
-                mode.to_mbed_tls(),
-                MBEDTLS_SSL_TRANSPORT_STREAM as i32,
-                MBEDTLS_SSL_PRESET_DEFAULT as i32,
-            ))?;
+/// Key exchange of the negotiated ciphersuite
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyExchange {
+    /// RSA key transport, which isn't forward secret
+    Rsa,
+    DheRsa,
+    EcdheRsa,
+    EcdheEcdsa,
+    Psk,
+    DhePsk,
+    RsaPsk,
+    EcdhePsk,
+    /// Static ECDH, which isn't forward secret
+    EcdhRsa,
+    /// Static ECDH, which isn't forward secret
+    EcdhEcdsa,
+    EcJpake,
+    /// The ephemeral (EC)DHE key exchange of TLS 1.3. TLS 1.3 ciphersuites don't include the key
+    /// exchange, which is assumed to be this one, even for a session that was resumed with a
+    /// pre-shared key.
+    Tls13Ephemeral,
+}
 
-            mbedtls_ssl_conf_min_version(
-                ssl_config,
-                MBEDTLS_SSL_MAJOR_VERSION_3 as i32,
-                min_version.to_mbed_tls_minor(),
-            );
+impl KeyExchange {
+    #[allow(non_upper_case_globals)]
+    fn from_mbed_tls(key_exchange: mbedtls_key_exchange_type_t) -> Option<Self> {
+        Some(match key_exchange {
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_RSA => KeyExchange::Rsa,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_DHE_RSA => KeyExchange::DheRsa,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_ECDHE_RSA => KeyExchange::EcdheRsa,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_ECDHE_ECDSA => KeyExchange::EcdheEcdsa,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_PSK => KeyExchange::Psk,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_DHE_PSK => KeyExchange::DhePsk,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_RSA_PSK => KeyExchange::RsaPsk,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_ECDHE_PSK => KeyExchange::EcdhePsk,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_ECDH_RSA => KeyExchange::EcdhRsa,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_ECDH_ECDSA => KeyExchange::EcdhEcdsa,
+            mbedtls_key_exchange_type_t_MBEDTLS_KEY_EXCHANGE_ECJPAKE => KeyExchange::EcJpake,
+            _ => return None,
+        })
+    }
 
-            mbedtls_ssl_conf_authmode(
-                ssl_config,
-                if self.ca_chain.is_some() {
-                    MBEDTLS_SSL_VERIFY_REQUIRED as i32
-                } else {
-                    // Use this config when in server mode
-                    // Ref: https://os.mbed.com/users/markrad/code/mbedtls/docs/tip/ssl_8h.html#a5695285c9dbfefec295012b566290f37
-                    MBEDTLS_SSL_VERIFY_NONE as i32
-                },
-            );
+    /// Returns whether the key exchange is forward secret, i.e. whether recorded traffic stays
+    /// confidential if a long-term key is compromised later on.
+    pub fn is_forward_secret(&self) -> bool {
+        matches!(
+            self,
+            KeyExchange::DheRsa
+                | KeyExchange::EcdheRsa
+                | KeyExchange::EcdheEcdsa
+                | KeyExchange::DhePsk
+                | KeyExchange::EcdhePsk
+                | KeyExchange::EcJpake
+                | KeyExchange::Tls13Ephemeral
+        )
+    }
+}
 
-            if mode == Mode::Client {
-                let mut hostname = StrBuf::new();
-                hostname.append(servername);
-                hostname.append_char('\0');
-                error_checked!(mbedtls_ssl_set_hostname(
-                    ssl_context,
-                    hostname.as_str_ref().as_ptr() as *const c_char
-                ))?;
+/// Compression of the TLS records, see [ConnectedSession::compression]
+///
+/// mbedtls 3 removed the support for TLS compression (`MBEDTLS_ZLIB_SUPPORT`), which makes
+/// connections vulnerable to attacks such as CRIME. It only offers and accepts the null
+/// compression method, so records are never compressed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Compression {
+    /// The records aren't compressed
+    None,
+}
+
+/// TLS extensions in use on a connection, as a set of flags
+///
+/// See `negotiated_extensions()` on [ConnectedSession] and `asynch::AsyncConnectedSession`.
+///
+/// Whether the Extended Master Secret extension (RFC 7627) was negotiated isn't reported: mbedtls
+/// 3.4 only keeps it in the handshake state, which is freed once the handshake completed. TLS 1.3
+/// always binds the secrets to the handshake, like the extension does for TLS 1.2.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ExtensionFlags(u32);
+
+impl ExtensionFlags {
+    /// The client sent the ServerName (SNI) extension. A server doesn't track the name it
+    /// received.
+    pub const SERVER_NAME: Self = Self(1 << 0);
+    /// An application protocol was negotiated with ALPN
+    pub const ALPN: Self = Self(1 << 1);
+    /// A maximum fragment length was negotiated
+    pub const MAX_FRAGMENT_LENGTH: Self = Self(1 << 2);
+    /// Encrypt-then-MAC (RFC 7366) is used, which only applies to CBC ciphersuites
+    pub const ENCRYPT_THEN_MAC: Self = Self(1 << 3);
+    /// Secure renegotiation (renegotiation_info, RFC 5746) is supported by both sides. Only
+    /// applies to TLS 1.2.
+    pub const RENEGOTIATION_INFO: Self = Self(1 << 4);
+
+    /// Returns no extensions
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns whether no extension is in use
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns whether all extensions of `other` are in use
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the extensions in use on the connection of `ssl_context`
+    unsafe fn from_mbed_tls(ssl_context: *const mbedtls_ssl_context) -> Self {
+        let ssl = &*ssl_context;
+        let mut flags = 0;
+        // The hostname is only set for clients that send it
+        if !ssl.private_hostname.is_null() {
+            flags |= Self::SERVER_NAME.0;
+        }
+        if !mbedtls_ssl_get_alpn_protocol(ssl_context).is_null() {
+            flags |= Self::ALPN.0;
+        }
+        if ssl.private_secure_renegotiation == MBEDTLS_SSL_SECURE_RENEGOTIATION as c_int {
+            flags |= Self::RENEGOTIATION_INFO.0;
+        }
+        if let Some(session) = ssl.private_session.as_ref() {
+            if session.private_mfl_code != MBEDTLS_SSL_MAX_FRAG_LEN_NONE as c_uchar {
+                flags |= Self::MAX_FRAGMENT_LENGTH.0;
+            }
+            if session.private_encrypt_then_mac == MBEDTLS_SSL_ETM_ENABLED as c_int {
+                flags |= Self::ENCRYPT_THEN_MAC.0;
             }
+        }
+        Self(flags)
+    }
+}
 
-            if let Some(ca_chain) = self.ca_chain {
-                error_checked!(mbedtls_x509_crt_parse(
-                    crt,
-                    ca_chain.as_ptr(),
-                    ca_chain.len(),
-                ))?;
+/// State of the handshake, mirroring the `mbedtls_ssl_states` of mbedtls.
+///
+/// Each state names the message that is processed next, e.g. [HandshakeState::ServerHello] is
+/// reported once the ClientHello was sent. See [Session::with_handshake_progress].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HandshakeState {
+    HelloRequest,
+    ClientHello,
+    ServerHello,
+    ServerCertificate,
+    ServerKeyExchange,
+    CertificateRequest,
+    ServerHelloDone,
+    ClientCertificate,
+    ClientKeyExchange,
+    CertificateVerify,
+    ClientChangeCipherSpec,
+    ClientFinished,
+    ServerChangeCipherSpec,
+    ServerFinished,
+    FlushBuffers,
+    HandshakeWrapup,
+    NewSessionTicket,
+    ServerHelloVerifyRequestSent,
+    HelloRetryRequest,
+    EncryptedExtensions,
+    EndOfEarlyData,
+    ClientCertificateVerify,
+    ClientCcsAfterServerFinished,
+    ClientCcsBefore2ndClientHello,
+    ServerCcsAfterServerHello,
+    ClientCcsAfterClientHello,
+    ServerCcsAfterHelloRetryRequest,
+    HandshakeOver,
+    Tls13NewSessionTicket,
+    Tls13NewSessionTicketFlush,
+}
+
+impl HandshakeState {
+    #[allow(non_upper_case_globals)]
+    fn from_mbed_tls(state: c_int) -> Option<Self> {
+        Some(match state as mbedtls_ssl_states {
+            mbedtls_ssl_states_MBEDTLS_SSL_HELLO_REQUEST => HandshakeState::HelloRequest,
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_HELLO => HandshakeState::ClientHello,
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_HELLO => HandshakeState::ServerHello,
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_CERTIFICATE => HandshakeState::ServerCertificate,
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_KEY_EXCHANGE => HandshakeState::ServerKeyExchange,
+            mbedtls_ssl_states_MBEDTLS_SSL_CERTIFICATE_REQUEST => {
+                HandshakeState::CertificateRequest
             }
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_HELLO_DONE => HandshakeState::ServerHelloDone,
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_CERTIFICATE => HandshakeState::ClientCertificate,
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_KEY_EXCHANGE => HandshakeState::ClientKeyExchange,
+            mbedtls_ssl_states_MBEDTLS_SSL_CERTIFICATE_VERIFY => HandshakeState::CertificateVerify,
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_CHANGE_CIPHER_SPEC => {
+                HandshakeState::ClientChangeCipherSpec
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_FINISHED => HandshakeState::ClientFinished,
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_CHANGE_CIPHER_SPEC => {
+                HandshakeState::ServerChangeCipherSpec
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_FINISHED => HandshakeState::ServerFinished,
+            mbedtls_ssl_states_MBEDTLS_SSL_FLUSH_BUFFERS => HandshakeState::FlushBuffers,
+            mbedtls_ssl_states_MBEDTLS_SSL_HANDSHAKE_WRAPUP => HandshakeState::HandshakeWrapup,
+            mbedtls_ssl_states_MBEDTLS_SSL_NEW_SESSION_TICKET => HandshakeState::NewSessionTicket,
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_HELLO_VERIFY_REQUEST_SENT => {
+                HandshakeState::ServerHelloVerifyRequestSent
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_HELLO_RETRY_REQUEST => HandshakeState::HelloRetryRequest,
+            mbedtls_ssl_states_MBEDTLS_SSL_ENCRYPTED_EXTENSIONS => {
+                HandshakeState::EncryptedExtensions
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_END_OF_EARLY_DATA => HandshakeState::EndOfEarlyData,
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_CERTIFICATE_VERIFY => {
+                HandshakeState::ClientCertificateVerify
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_CCS_AFTER_SERVER_FINISHED => {
+                HandshakeState::ClientCcsAfterServerFinished
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_CCS_BEFORE_2ND_CLIENT_HELLO => {
+                HandshakeState::ClientCcsBefore2ndClientHello
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_CCS_AFTER_SERVER_HELLO => {
+                HandshakeState::ServerCcsAfterServerHello
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_CLIENT_CCS_AFTER_CLIENT_HELLO => {
+                HandshakeState::ClientCcsAfterClientHello
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_SERVER_CCS_AFTER_HELLO_RETRY_REQUEST => {
+                HandshakeState::ServerCcsAfterHelloRetryRequest
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_HANDSHAKE_OVER => HandshakeState::HandshakeOver,
+            mbedtls_ssl_states_MBEDTLS_SSL_TLS1_3_NEW_SESSION_TICKET => {
+                HandshakeState::Tls13NewSessionTicket
+            }
+            mbedtls_ssl_states_MBEDTLS_SSL_TLS1_3_NEW_SESSION_TICKET_FLUSH => {
+                HandshakeState::Tls13NewSessionTicketFlush
+            }
+            _ => return None,
+        })
+    }
+}
 
-            if let (Some(cert), Some(key)) = (self.certificate, self.private_key) {
-                // Certificate
-                match cert.format {
-                    CertificateFormat::PEM => {
-                        error_checked!(mbedtls_x509_crt_parse(
-                            certificate,
-                            cert.as_ptr(),
-                            cert.len(),
-                        ))?;
-                    }
-                    CertificateFormat::DER => {
-                        error_checked!(mbedtls_x509_crt_parse_der_nocopy(
-                            certificate,
-                            cert.as_ptr(),
-                            cert.len(),
-                        ))?;
-                    }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsError {
+    Unknown,
+    OutOfMemory,
+    MbedTlsError(i32),
+    Eof,
+    X509MissingNullTerminator,
+    /// The client has given no certificates for the request
+    NoClientCertificate,
+    /// The operation did not complete in the given time
+    Timeout,
+    /// The server certificate was not issued for the hostname given to [Session::new]
+    ///
+    /// This is reported whenever the name doesn't match, even if the certificate has other
+    /// problems, e.g. it isn't trusted either.
+    HostnameMismatch,
+    /// The private key is encrypted, but no [Certificates::password] was given
+    PasswordRequired,
+    /// The [Certificates::password] of the private key is wrong
+    PasswordMismatch,
+    /// The TCP connection to the server could not be established, with the kind of error the TCP
+    /// stack returned
+    TcpConnectFailed(embedded_io::ErrorKind),
+    /// The hostname of the server could not be resolved
+    DnsFailed,
+    /// The private key isn't in the format it was declared as, see [X509::pkcs8_key_pem],
+    /// [X509::sec1_key_pem], [X509::pkcs8_key_der] and [X509::sec1_key_der]
+    KeyFormatMismatch,
+    /// Only one of [Certificates::certificate] and [Certificates::private_key] was given, they
+    /// must be given together
+    IncompleteKeyPair,
+    /// The random number generator couldn't be seeded, holding the mbedtls error code
+    RngSeed(i32),
+    /// The configuration couldn't be applied (e.g. the hostname or the TLS defaults), holding the
+    /// mbedtls error code
+    Config(i32),
+    /// The [Certificates::ca_chain] couldn't be parsed, holding the mbedtls error code
+    CaChainParse(i32),
+    /// The [Certificates::certificate] couldn't be parsed, holding the mbedtls error code
+    CertificateParse(i32),
+    /// The [Certificates::private_key] couldn't be parsed, holding the mbedtls error code
+    PrivateKeyParse(i32),
+    /// The [Certificates::crl] couldn't be parsed, holding the mbedtls error code
+    CrlParse(i32),
+    /// Reading from or writing to the underlying stream failed.
+    ///
+    /// This holds the [kind](embedded_io::Error::kind) of the stream's error, to tell a failing
+    /// transport apart from a failure of the TLS protocol.
+    Io(embedded_io::ErrorKind),
+    /// The peer sent more handshake data than allowed with `with_max_handshake_size()` on the
+    /// session
+    HandshakeTooLarge,
+}
+
+impl TlsError {
+    /// Returns a human readable description of the error.
+    ///
+    /// For [TlsError::MbedTlsError] this is the description mbedtls has for the code. As mbedtls
+    /// error codes can combine a high-level (e.g. SSL) and a low-level (e.g. bignum) part, the
+    /// high-level description is preferred. Use the [core::fmt::Display] implementation to get
+    /// both.
+    pub fn description(&self) -> &'static str {
+        match self {
+            TlsError::Unknown => "Unknown error",
+            TlsError::OutOfMemory => "Out of memory",
+            TlsError::MbedTlsError(code) => high_level_strerr(*code)
+                .or_else(|| low_level_strerr(*code))
+                .unwrap_or("UNKNOWN ERROR CODE"),
+            TlsError::Eof => "End of stream",
+            TlsError::X509MissingNullTerminator => "PEM certificate is missing a null terminator",
+            TlsError::NoClientCertificate => "The client has given no certificates",
+            TlsError::Timeout => "The operation timed out",
+            TlsError::HostnameMismatch => "The certificate does not match the hostname",
+            TlsError::PasswordRequired => "The private key requires a password",
+            TlsError::PasswordMismatch => "The password of the private key is wrong",
+            TlsError::TcpConnectFailed(_) => "The TCP connection could not be established",
+            TlsError::DnsFailed => "The hostname could not be resolved",
+            TlsError::KeyFormatMismatch => "The private key is not in the expected format",
+            TlsError::IncompleteKeyPair => "The certificate and private key must be given together",
+            TlsError::RngSeed(_) => "The random number generator could not be seeded",
+            TlsError::Config(_) => "The configuration could not be applied",
+            TlsError::CaChainParse(_) => "The CA chain could not be parsed",
+            TlsError::CertificateParse(_) => "The certificate could not be parsed",
+            TlsError::PrivateKeyParse(_) => "The private key could not be parsed",
+            TlsError::CrlParse(_) => "The revocation list could not be parsed",
+            TlsError::Io(_) => "The underlying stream failed",
+            TlsError::HandshakeTooLarge => "The peer sent too much handshake data",
+        }
+    }
+}
+
+impl core::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TlsError::MbedTlsError(code) => {
+                let high = high_level_strerr(*code);
+                let low = low_level_strerr(*code);
+                write!(f, "-0x{:04X}", code.unsigned_abs())?;
+                match (high, low) {
+                    (Some(high), Some(low)) => write!(f, " - {high} : {low}"),
+                    (Some(desc), None) | (None, Some(desc)) => write!(f, " - {desc}"),
+                    (None, None) => write!(f, " - UNKNOWN ERROR CODE"),
                 }
+            }
+            TlsError::RngSeed(code)
+            | TlsError::Config(code)
+            | TlsError::CaChainParse(code)
+            | TlsError::CertificateParse(code)
+            | TlsError::PrivateKeyParse(code)
+            | TlsError::CrlParse(code) => {
+                write!(
+                    f,
+                    "{}: {}",
+                    self.description(),
+                    TlsError::MbedTlsError(*code)
+                )
+            }
+            _ => f.write_str(self.description()),
+        }
+    }
+}
 
-                // Private key
-                let (password_ptr, password_len) = if let Some(password) = self.password {
-                    (password.as_ptr(), password.len())
-                } else {
-                    (core::ptr::null(), 0)
-                };
-                error_checked!(mbedtls_pk_parse_key(
-                    private_key,
-                    key.as_ptr(),
-                    key.len(),
-                    password_ptr,
-                    password_len,
-                    None,
-                    core::ptr::null_mut(),
-                ))?;
+/// Description of the high-level part of an mbedtls error code
+fn high_level_strerr(code: i32) -> Option<&'static str> {
+    unsafe { static_c_str(mbedtls_high_level_strerr(code)) }
+}
 
-                mbedtls_ssl_conf_own_cert(ssl_config, certificate, private_key);
+/// Description of the low-level part of an mbedtls error code
+fn low_level_strerr(code: i32) -> Option<&'static str> {
+    unsafe { static_c_str(mbedtls_low_level_strerr(code)) }
+}
+
+/// Converts a pointer to a static null-terminated C string
+unsafe fn static_c_str(ptr: *const c_char) -> Option<&'static str> {
+    if ptr.is_null() {
+        None
+    } else {
+        CStr::from_ptr(ptr).to_str().ok()
+    }
+}
+
+/// Maps the result of a failed `mbedtls_ssl_handshake` to a [TlsError].
+///
+/// This must be called before the context is reset, as the reset clears the verification result.
+unsafe fn handshake_error(ssl_context: *const mbedtls_ssl_context, res: i32) -> TlsError {
+    match res {
+        MBEDTLS_ERR_SSL_NO_CLIENT_CERTIFICATE => TlsError::NoClientCertificate,
+        MBEDTLS_ERR_X509_CERT_VERIFY_FAILED
+            if mbedtls_ssl_get_verify_result(ssl_context) & MBEDTLS_X509_BADCERT_CN_MISMATCH
+                != 0 =>
+        {
+            TlsError::HostnameMismatch
+        }
+        _ => TlsError::MbedTlsError(res),
+    }
+}
+
+impl embedded_io::Error for TlsError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            TlsError::Io(kind) | TlsError::TcpConnectFailed(kind) => *kind,
+            _ => embedded_io::ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for TlsError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            TlsError::Unknown => defmt::write!(fmt, "Unknown"),
+            TlsError::OutOfMemory => defmt::write!(fmt, "OutOfMemory"),
+            TlsError::MbedTlsError(code) => match error_name(*code) {
+                Some(name) => defmt::write!(fmt, "MbedTlsError({=str})", name),
+                None => defmt::write!(fmt, "MbedTlsError(-{=u32:#06x})", code.unsigned_abs()),
+            },
+            TlsError::Eof => defmt::write!(fmt, "Eof"),
+            TlsError::X509MissingNullTerminator => defmt::write!(fmt, "X509MissingNullTerminator"),
+            TlsError::NoClientCertificate => defmt::write!(fmt, "NoClientCertificate"),
+            TlsError::Timeout => defmt::write!(fmt, "Timeout"),
+            TlsError::HostnameMismatch => defmt::write!(fmt, "HostnameMismatch"),
+            TlsError::PasswordRequired => defmt::write!(fmt, "PasswordRequired"),
+            TlsError::PasswordMismatch => defmt::write!(fmt, "PasswordMismatch"),
+            TlsError::TcpConnectFailed(kind) => {
+                defmt::write!(fmt, "TcpConnectFailed({})", defmt::Debug2Format(kind))
+            }
+            TlsError::DnsFailed => defmt::write!(fmt, "DnsFailed"),
+            TlsError::KeyFormatMismatch => defmt::write!(fmt, "KeyFormatMismatch"),
+            TlsError::IncompleteKeyPair => defmt::write!(fmt, "IncompleteKeyPair"),
+            TlsError::RngSeed(code) => {
+                defmt::write!(fmt, "RngSeed({})", TlsError::MbedTlsError(*code))
+            }
+            TlsError::Config(code) => {
+                defmt::write!(fmt, "Config({})", TlsError::MbedTlsError(*code))
+            }
+            TlsError::CaChainParse(code) => {
+                defmt::write!(fmt, "CaChainParse({})", TlsError::MbedTlsError(*code))
             }
+            TlsError::CertificateParse(code) => {
+                defmt::write!(fmt, "CertificateParse({})", TlsError::MbedTlsError(*code))
+            }
+            TlsError::PrivateKeyParse(code) => {
+                defmt::write!(fmt, "PrivateKeyParse({})", TlsError::MbedTlsError(*code))
+            }
+            TlsError::CrlParse(code) => {
+                defmt::write!(fmt, "CrlParse({})", TlsError::MbedTlsError(*code))
+            }
+            TlsError::Io(kind) => defmt::write!(fmt, "Io({})", defmt::Debug2Format(kind)),
+            TlsError::HandshakeTooLarge => defmt::write!(fmt, "HandshakeTooLarge"),
+        }
+    }
+}
 
-            mbedtls_ssl_conf_ca_chain(ssl_config, crt, core::ptr::null_mut());
-            error_checked!(mbedtls_ssl_setup(ssl_context, ssl_config))?;
-            Ok((
-                drbg_context,
-                ssl_context,
-                ssl_config,
-                crt,
-                certificate,
-                private_key,
-            ))
+/// Codes of common mbedtls errors, to match against [TlsError::MbedTlsError].
+///
+/// # Examples
+/// ```
+/// match session.connect() {
+///     Err(TlsError::MbedTlsError(error_codes::FATAL_ALERT_MESSAGE)) => log::warn!("Peer sent an alert"),
+///     _ => {}
+/// }
+/// ```
+pub mod error_codes {
+    use esp_mbedtls_sys::bindings::*;
+
+    /// The operation has to be retried once the stream can be read
+    pub const WANT_READ: i32 = MBEDTLS_ERR_SSL_WANT_READ;
+    /// The operation has to be retried once the stream can be written
+    pub const WANT_WRITE: i32 = MBEDTLS_ERR_SSL_WANT_WRITE;
+    /// The operation timed out
+    pub const TIMEOUT: i32 = MBEDTLS_ERR_SSL_TIMEOUT;
+    /// The connection was closed without a close notification
+    pub const CONN_EOF: i32 = MBEDTLS_ERR_SSL_CONN_EOF;
+    /// The peer closed the connection
+    pub const PEER_CLOSE_NOTIFY: i32 = MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY;
+    /// The peer sent a fatal alert, e.g. because it doesn't trust our certificate
+    pub const FATAL_ALERT_MESSAGE: i32 = MBEDTLS_ERR_SSL_FATAL_ALERT_MESSAGE;
+    /// No common parameters (version, ciphersuite, curve, ...) could be negotiated
+    pub const HANDSHAKE_FAILURE: i32 = MBEDTLS_ERR_SSL_HANDSHAKE_FAILURE;
+    /// The peer doesn't support the requested TLS versions
+    pub const BAD_PROTOCOL_VERSION: i32 = MBEDTLS_ERR_SSL_BAD_PROTOCOL_VERSION;
+    /// The certificate of the peer is malformed
+    pub const BAD_CERTIFICATE: i32 = MBEDTLS_ERR_SSL_BAD_CERTIFICATE;
+    /// The peer sent an unexpected message
+    pub const UNEXPECTED_MESSAGE: i32 = MBEDTLS_ERR_SSL_UNEXPECTED_MESSAGE;
+    /// A message of the peer couldn't be decoded
+    pub const DECODE_ERROR: i32 = MBEDTLS_ERR_SSL_DECODE_ERROR;
+    /// The input data is invalid
+    pub const BAD_INPUT_DATA: i32 = MBEDTLS_ERR_SSL_BAD_INPUT_DATA;
+    /// mbedtls ran out of memory
+    pub const ALLOC_FAILED: i32 = MBEDTLS_ERR_SSL_ALLOC_FAILED;
+    /// The output buffer is too small
+    pub const BUFFER_TOO_SMALL: i32 = MBEDTLS_ERR_SSL_BUFFER_TOO_SMALL;
+    /// Serialized data was saved by a different version or configuration of mbedtls
+    pub const VERSION_MISMATCH: i32 = MBEDTLS_ERR_SSL_VERSION_MISMATCH;
+    /// The certificate of the peer couldn't be verified
+    pub const CERT_VERIFY_FAILED: i32 = MBEDTLS_ERR_X509_CERT_VERIFY_FAILED;
+    /// A certificate isn't in a valid format
+    pub const X509_INVALID_FORMAT: i32 = MBEDTLS_ERR_X509_INVALID_FORMAT;
+    /// The private key isn't in a valid format
+    pub const PK_KEY_INVALID_FORMAT: i32 = MBEDTLS_ERR_PK_KEY_INVALID_FORMAT;
+}
+
+/// Returns the symbolic name of the most common mbedtls error codes
+#[cfg(feature = "defmt")]
+fn error_name(code: i32) -> Option<&'static str> {
+    Some(match code {
+        MBEDTLS_ERR_SSL_WANT_READ => "MBEDTLS_ERR_SSL_WANT_READ",
+        MBEDTLS_ERR_SSL_WANT_WRITE => "MBEDTLS_ERR_SSL_WANT_WRITE",
+        MBEDTLS_ERR_SSL_TIMEOUT => "MBEDTLS_ERR_SSL_TIMEOUT",
+        MBEDTLS_ERR_SSL_CONN_EOF => "MBEDTLS_ERR_SSL_CONN_EOF",
+        MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY => "MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY",
+        MBEDTLS_ERR_SSL_FATAL_ALERT_MESSAGE => "MBEDTLS_ERR_SSL_FATAL_ALERT_MESSAGE",
+        MBEDTLS_ERR_SSL_HANDSHAKE_FAILURE => "MBEDTLS_ERR_SSL_HANDSHAKE_FAILURE",
+        MBEDTLS_ERR_SSL_BAD_PROTOCOL_VERSION => "MBEDTLS_ERR_SSL_BAD_PROTOCOL_VERSION",
+        MBEDTLS_ERR_SSL_BAD_CERTIFICATE => "MBEDTLS_ERR_SSL_BAD_CERTIFICATE",
+        MBEDTLS_ERR_SSL_NO_CLIENT_CERTIFICATE => "MBEDTLS_ERR_SSL_NO_CLIENT_CERTIFICATE",
+        MBEDTLS_ERR_SSL_UNEXPECTED_MESSAGE => "MBEDTLS_ERR_SSL_UNEXPECTED_MESSAGE",
+        MBEDTLS_ERR_SSL_DECODE_ERROR => "MBEDTLS_ERR_SSL_DECODE_ERROR",
+        MBEDTLS_ERR_SSL_BAD_INPUT_DATA => "MBEDTLS_ERR_SSL_BAD_INPUT_DATA",
+        MBEDTLS_ERR_SSL_ALLOC_FAILED => "MBEDTLS_ERR_SSL_ALLOC_FAILED",
+        MBEDTLS_ERR_SSL_BUFFER_TOO_SMALL => "MBEDTLS_ERR_SSL_BUFFER_TOO_SMALL",
+        MBEDTLS_ERR_SSL_VERSION_MISMATCH => "MBEDTLS_ERR_SSL_VERSION_MISMATCH",
+        MBEDTLS_ERR_X509_CERT_VERIFY_FAILED => "MBEDTLS_ERR_X509_CERT_VERIFY_FAILED",
+        MBEDTLS_ERR_X509_INVALID_FORMAT => "MBEDTLS_ERR_X509_INVALID_FORMAT",
+        MBEDTLS_ERR_PK_KEY_INVALID_FORMAT => "MBEDTLS_ERR_PK_KEY_INVALID_FORMAT",
+        MBEDTLS_ERR_PK_PASSWORD_REQUIRED => "MBEDTLS_ERR_PK_PASSWORD_REQUIRED",
+        MBEDTLS_ERR_PK_PASSWORD_MISMATCH => "MBEDTLS_ERR_PK_PASSWORD_MISMATCH",
+        _ => return None,
+    })
+}
+
+/// The level set with [set_debug]
+static mut DEBUG_LEVEL: u32 = 0;
+
+/// The number of live sessions with each level set with `with_debug_level`, from 0 to 4
+static mut SESSION_DEBUG_LEVELS: [u32; 5] = [0; 5];
+
+/// Set the level of the mbedtls debug messages that are logged, from 0 (none) to 4 (verbose).
+///
+/// This applies to every session without its own level set with `with_debug_level`.
+pub fn set_debug(level: u32) {
+    unsafe {
+        DEBUG_LEVEL = level;
+        apply_debug_threshold();
+    }
+}
+
+/// Lets mbedtls generate the messages of the highest level that any session logs
+unsafe fn apply_debug_threshold() {
+    #[cfg(not(target_arch = "xtensa"))]
+    {
+        let session_level = SESSION_DEBUG_LEVELS
+            .iter()
+            .rposition(|&sessions| sessions > 0)
+            .unwrap_or(0) as u32;
+        mbedtls_debug_set_threshold(u32::max(DEBUG_LEVEL, session_level) as c_int);
+    }
+}
+
+/// Set the debug level of a single session, see [set_debug]
+///
+/// The level is passed to [dbg_print] as its context, offset by one so that a null context
+/// stands for the global level.
+unsafe fn conf_debug_level(ssl_config: *mut mbedtls_ssl_config, level: u32) {
+    // mbedtls has no level above 4, and the clamped level can't overflow when encoded
+    let level = usize::min(level as usize, 4);
+    release_debug_level(ssl_config);
+    mbedtls_ssl_conf_dbg(ssl_config, Some(dbg_print), (level + 1) as *mut c_void);
+    SESSION_DEBUG_LEVELS[level] += 1;
+    apply_debug_threshold();
+}
+
+/// Stops counting the level set with [conf_debug_level] on `ssl_config`, so that the threshold
+/// goes back down once no session needs it anymore
+unsafe fn release_debug_level(ssl_config: *mut mbedtls_ssl_config) {
+    let context = (*ssl_config).private_p_dbg as usize;
+    if context != 0 {
+        (*ssl_config).private_p_dbg = core::ptr::null_mut();
+        SESSION_DEBUG_LEVELS[usize::min(context - 1, 4)] -= 1;
+        apply_debug_threshold();
+    }
+}
+
+/// Returns the version of the mbedtls library that is linked in, e.g. `mbed TLS 3.4.0`.
+///
+/// This is read from the precompiled library at runtime, so it is useful for bug reports.
+pub fn mbedtls_version() -> &'static str {
+    unsafe {
+        if MBEDTLS_VERSION[0] == 0 {
+            mbedtls_version_get_string_full(MBEDTLS_VERSION.as_mut_ptr() as *mut c_char);
+        }
+        static_c_str(MBEDTLS_VERSION.as_ptr() as *const c_char)
+    }
+    .unwrap_or("unknown")
+}
+
+/// The full version string, filled on first use.
+/// mbedtls writes at most 18 bytes, including the null terminator.
+static mut MBEDTLS_VERSION: [u8; 18] = [0; 18];
+
+/// Compares two byte strings in constant time, e.g. a certificate fingerprint with a pinned one.
+///
+/// Unlike `==`, the time taken doesn't depend on where the inputs differ, so it doesn't leak
+/// how much of a secret matches. Only inputs of equal length are compared: if the lengths
+/// differ, this returns false right away, so the length itself isn't kept secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    // Keeps the compiler from stopping at the first difference
+    core::hint::black_box(diff) == 0
+}
+
+/// Returns the current time in seconds since the Unix epoch, see [set_time_source]
+static mut TIME_SOURCE: Option<fn() -> u64> = None;
+
+/// Set the clock used to check the validity period of certificates.
+///
+/// mbedtls is built without a clock, so by default the validity period (`notBefore`/`notAfter`)
+/// of certificates isn't checked at all. Once a time source is set, every certificate of the
+/// peer's chain is checked against it while verifying the peer: an expired certificate is
+/// reported as [VerifyFlags::EXPIRED] and one that isn't valid yet as [VerifyFlags::FUTURE], which
+/// fails the handshake when the certificate is verified with [VerifyMode::Required].
+///
+/// Only set it once the time is known, e.g. after it was synchronized with NTP. A wrong time
+/// rejects valid certificates, while no time source accepts expired (possibly compromised)
+/// certificates.
+///
+/// # Arguments
+///
+/// * `source` - Returns the current time in seconds since the Unix epoch (UTC). Use [None] to
+///   stop checking the validity period.
+pub fn set_time_source(source: Option<fn() -> u64>) {
+    unsafe { TIME_SOURCE = source }
+}
+
+/// Checks the validity period of each certificate of the chain against [TIME_SOURCE], and the
+/// last certificate of the chain against the trust anchors `ctx` points to, if any
+unsafe extern "C" fn verify(
+    ctx: *mut c_void,
+    crt: *mut mbedtls_x509_crt,
+    _depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    if let Some(source) = TIME_SOURCE {
+        let now = source() as i64;
+        if now > unix_time(&(*crt).valid_to) {
+            *flags |= MBEDTLS_X509_BADCERT_EXPIRED;
+        }
+        if now < unix_time(&(*crt).valid_from) {
+            *flags |= MBEDTLS_X509_BADCERT_FUTURE;
+        }
+    }
+
+    // The last certificate of the chain isn't trusted when it wasn't issued by the CA chain
+    if !ctx.is_null() && *flags & MBEDTLS_X509_BADCERT_NOT_TRUSTED != 0 {
+        let anchors = *(ctx as *const &[TrustAnchor]);
+        if anchors.iter().any(|anchor| anchor.is_issuer_of(&*crt)) {
+            *flags &= !MBEDTLS_X509_BADCERT_NOT_TRUSTED;
+        }
+    }
+    0
+}
+
+/// Verification callback of a session whose server name is an IP address, `ctx` is its SSL
+/// context
+///
+/// The vendored mbedtls only compares the name with the DNS names and the Common Name, so the
+/// name mismatch is cleared here if an IP address entry of the Subject Alternative Names matches.
+/// The callback of the configuration is called first, as mbedtls only calls this one.
+unsafe extern "C" fn verify_ip_address(
+    ctx: *mut c_void,
+    crt: *mut mbedtls_x509_crt,
+    depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    let ssl_context = ctx as *const mbedtls_ssl_context;
+    let ssl_config = (*ssl_context).private_conf;
+    if let Some(f_vrfy) = (*ssl_config).private_f_vrfy {
+        let res = f_vrfy((*ssl_config).private_p_vrfy, crt, depth, flags);
+        if res != 0 {
+            return res;
+        }
+    }
+
+    if depth == 0 && *flags & MBEDTLS_X509_BADCERT_CN_MISMATCH != 0 {
+        let address = static_c_str((*ssl_context).private_hostname).and_then(parse_ip_address);
+        if let Some((address, len)) = address {
+            if subject_alt_names(crt).any(|san| san == SanEntry::Ip(&address[..len])) {
+                *flags &= !MBEDTLS_X509_BADCERT_CN_MISMATCH;
+            }
         }
     }
+    0
 }
 
-pub struct Session<T> {
-    stream: T,
-    drbg_context: *mut mbedtls_ctr_drbg_context,
-    ssl_context: *mut mbedtls_ssl_context,
-    ssl_config: *mut mbedtls_ssl_config,
-    crt: *mut mbedtls_x509_crt,
-    client_crt: *mut mbedtls_x509_crt,
-    private_key: *mut mbedtls_pk_context,
-    // Indicate if this session is the one holding the RSA ref
-    owns_rsa: bool,
-}
+/// Parses an IPv4 address in dotted-decimal notation or an IPv6 address, returning its bytes and
+/// their number, 4 or 16
+///
+/// IPv6 addresses with an embedded IPv4 address (`::ffff:192.0.2.1`) aren't supported.
+fn parse_ip_address(name: &str) -> Option<([u8; 16], usize)> {
+    let mut address = [0u8; 16];
+    if name.contains('.') {
+        let mut len = 0;
+        for part in name.split('.') {
+            if len == 4 || part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            address[len] = part.parse().ok()?;
+            len += 1;
+        }
+        return (len == 4).then_some((address, 4));
+    }
+
+    // The groups before and after the `::` that stands for groups of zeros
+    let (head, tail) = match name.split_once("::") {
+        Some((head, tail)) => (head, Some(tail)),
+        None => (name, None),
+    };
+    let parse_groups = |part: &str, groups: &mut [u16; 8]| -> Option<usize> {
+        let mut len = 0;
+        if part.is_empty() {
+            return Some(len);
+        }
+        for group in part.split(':') {
+            if len == 8
+                || group.is_empty()
+                || group.len() > 4
+                || !group.bytes().all(|b| b.is_ascii_hexdigit())
+            {
+                return None;
+            }
+            groups[len] = u16::from_str_radix(group, 16).ok()?;
+            len += 1;
+        }
+        Some(len)
+    };
+    let mut groups = [0u16; 8];
+    let head_len = parse_groups(head, &mut groups)?;
+    match tail {
+        Some(tail) => {
+            let mut tail_groups = [0u16; 8];
+            let tail_len = parse_groups(tail, &mut tail_groups)?;
+            if head_len + tail_len > 7 {
+                return None;
+            }
+            groups[8 - tail_len..].copy_from_slice(&tail_groups[..tail_len]);
+        }
+        None if head_len == 8 => {}
+        None => return None,
+    }
+    for (bytes, group) in address.chunks_exact_mut(2).zip(groups) {
+        bytes.copy_from_slice(&group.to_be_bytes());
+    }
+    Some((address, 16))
+}
+
+/// Converts a time of a certificate to seconds since the Unix epoch
+fn unix_time(time: &mbedtls_x509_time) -> i64 {
+    // Days since the epoch of the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let year = time.year as i64 - if time.mon <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = (time.mon as i64 + 9) % 12;
+    let day_of_year = (153 * month + 2) / 5 + time.day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    days * 86400 + time.hour as i64 * 3600 + time.min as i64 * 60 + time.sec as i64
+}
+
+/// Format type for [X509]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum CertificateFormat {
+    PEM,
+    DER,
+}
+
+/// Holds a X509 certificate
+///
+/// # Examples
+/// Initialize with a PEM certificate
+/// ```
+/// const CERTIFICATE: &[u8] = include_bytes!("certificate.pem");
+/// let cert = X509::pem(CERTIFICATE).unwrap();
+/// ```
+///
+/// Initialize with a DER certificate
+/// ```
+/// const CERTIFICATE: &[u8] = include_bytes!("certificate.der");
+/// let cert = X509::der(CERTIFICATE);
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct X509<'a> {
+    bytes: &'a [u8],
+    format: CertificateFormat,
+}
+
+/// The tag of a DER sequence, which is always constructed
+const ASN1_SEQUENCE: u32 = MBEDTLS_ASN1_CONSTRUCTED | MBEDTLS_ASN1_SEQUENCE;
+
+impl<'a> X509<'a> {
+    /// Reads certificate in pem format from bytes
+    ///
+    /// The bytes can contain several concatenated certificates, e.g. a CA bundle. When used as a
+    /// [Certificates::ca_chain], all of them are loaded. Use [Certificate::parse] to check how
+    /// many certificates a bundle contains.
+    ///
+    /// The bytes are not copied by this function and can reside in memory-mapped flash. When the
+    /// certificates are parsed, mbedtls decodes the base64 of each certificate into a heap buffer,
+    /// so loading a PEM needs roughly 3/4 of its size in RAM. Use [X509::der] to avoid this.
+    ///
+    /// # Error
+    /// This function returns [TlsError::X509MissingNullTerminator] if the certificate
+    /// doesn't end with a null-byte.
+    pub fn pem(bytes: &'a [u8]) -> Result<Self, TlsError> {
+        if let Some(len) = X509::get_null(bytes) {
+            // Get a slice of only the certificate bytes including the \0
+            let bytes = unsafe { core::slice::from_raw_parts(bytes.as_ptr(), len + 1) };
+            Ok(Self {
+                bytes,
+                format: CertificateFormat::PEM,
+            })
+        } else {
+            Err(TlsError::X509MissingNullTerminator)
+        }
+    }
+
+    /// Reads certificate in der format from bytes
+    ///
+    /// *Note*: This function assumes that the size of the size is the exact
+    /// length of the certificate
+    ///
+    /// The bytes are not copied by this function and can reside in memory-mapped flash. Used as
+    /// [Certificates::certificate], the certificate is parsed in place without copying it to
+    /// RAM, so the bytes must stay valid for the lifetime of the [Session]. Used as
+    /// [Certificates::ca_chain] or with [Certificate::parse], mbedtls copies the certificate to
+    /// the heap.
+    pub fn der(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            format: CertificateFormat::DER,
+        }
+    }
+
+    /// Reads a PKCS#8 private key in pem format from bytes
+    ///
+    /// Unlike [X509::pem], which accepts any key that mbedtls can detect, this checks that the
+    /// key is a PKCS#8 key (`BEGIN PRIVATE KEY` or `BEGIN ENCRYPTED PRIVATE KEY`), for both RSA
+    /// and EC keys. Only the label of the first block is checked.
+    ///
+    /// # Error
+    /// This function returns [TlsError::X509MissingNullTerminator] if the key doesn't end with a
+    /// null-byte, and [TlsError::KeyFormatMismatch] if it isn't a PKCS#8 key.
+    pub fn pkcs8_key_pem(bytes: &'a [u8]) -> Result<Self, TlsError> {
+        let key = Self::pem(bytes)?;
+        if key.has_pem_label(b"PRIVATE KEY") || key.has_pem_label(b"ENCRYPTED PRIVATE KEY") {
+            Ok(key)
+        } else {
+            Err(TlsError::KeyFormatMismatch)
+        }
+    }
+
+    /// Reads a SEC1 EC private key in pem format from bytes
+    ///
+    /// Unlike [X509::pem], which accepts any key that mbedtls can detect, this checks that the
+    /// key is a SEC1 key (`BEGIN EC PRIVATE KEY`). Only the label of the first block is checked.
+    ///
+    /// # Error
+    /// This function returns [TlsError::X509MissingNullTerminator] if the key doesn't end with a
+    /// null-byte, and [TlsError::KeyFormatMismatch] if it isn't a SEC1 key.
+    pub fn sec1_key_pem(bytes: &'a [u8]) -> Result<Self, TlsError> {
+        let key = Self::pem(bytes)?;
+        if key.has_pem_label(b"EC PRIVATE KEY") {
+            Ok(key)
+        } else {
+            Err(TlsError::KeyFormatMismatch)
+        }
+    }
+
+    /// Reads a PKCS#8 private key in der format from bytes
+    ///
+    /// Unlike [X509::der], this checks that the key is a PKCS#8 key, i.e. a `PrivateKeyInfo` or
+    /// an `EncryptedPrivateKeyInfo`, for both RSA and EC keys. As for [X509::der], `bytes` must
+    /// be exactly the key.
+    ///
+    /// # Error
+    /// This function returns [TlsError::KeyFormatMismatch] if it isn't a PKCS#8 key.
+    pub fn pkcs8_key_der(bytes: &'a [u8]) -> Result<Self, TlsError> {
+        let key = Self::der(bytes);
+        match key.der_sequence_tags() {
+            // PrivateKeyInfo: version and algorithm, EncryptedPrivateKeyInfo: algorithm and data
+            Some([MBEDTLS_ASN1_INTEGER, ASN1_SEQUENCE])
+            | Some([ASN1_SEQUENCE, MBEDTLS_ASN1_OCTET_STRING]) => Ok(key),
+            _ => Err(TlsError::KeyFormatMismatch),
+        }
+    }
+
+    /// Reads a SEC1 EC private key in der format from bytes
+    ///
+    /// Unlike [X509::der], this checks that the key is a SEC1 key, i.e. an `ECPrivateKey`. As
+    /// for [X509::der], `bytes` must be exactly the key.
+    ///
+    /// # Error
+    /// This function returns [TlsError::KeyFormatMismatch] if it isn't a SEC1 key.
+    pub fn sec1_key_der(bytes: &'a [u8]) -> Result<Self, TlsError> {
+        let key = Self::der(bytes);
+        match key.der_sequence_tags() {
+            // ECPrivateKey: version and private key
+            Some([MBEDTLS_ASN1_INTEGER, MBEDTLS_ASN1_OCTET_STRING]) => Ok(key),
+            _ => Err(TlsError::KeyFormatMismatch),
+        }
+    }
+
+    /// Returns the bytes of the certificate
+    pub fn data(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Returns the length of the certificate
+    pub(crate) fn len(&self) -> usize {
+        self.data().len()
+    }
+
+    /// Returns a pointer to the data for parsing
+    pub(crate) fn as_ptr(&self) -> *const c_uchar {
+        self.data().as_ptr().cast()
+    }
+
+    /// Returns whether the first block of the pem data has the given label
+    fn has_pem_label(&self, label: &[u8]) -> bool {
+        const BEGIN: &[u8] = b"-----BEGIN ";
+        const END: &[u8] = b"-----";
+        let mut windows = self.bytes.windows(BEGIN.len());
+        let start = match windows.position(|window| window == BEGIN) {
+            Some(start) => start + BEGIN.len(),
+            None => return false,
+        };
+        let line = &self.bytes[start..];
+        line.starts_with(label) && line[label.len()..].starts_with(END)
+    }
+
+    /// Returns the tags of the first two elements of the der data, which has to be a single
+    /// sequence
+    fn der_sequence_tags(&self) -> Option<[u32; 2]> {
+        unsafe {
+            let mut p = self.bytes.as_ptr() as *mut c_uchar;
+            let end = p.add(self.bytes.len());
+            let mut len = 0;
+            if mbedtls_asn1_get_tag(&mut p, end, &mut len, ASN1_SEQUENCE as c_int) != 0
+                || p.add(len) != end
+            {
+                return None;
+            }
+
+            let mut tags = [0; 2];
+            for tag in tags.iter_mut() {
+                if p == end {
+                    return None;
+                }
+                *tag = *p as u32;
+                p = p.add(1);
+                if mbedtls_asn1_get_len(&mut p, end, &mut len) != 0 {
+                    return None;
+                }
+                p = p.add(len);
+            }
+            Some(tags)
+        }
+    }
+
+    /// Gets the first null byte in a slice
+    fn get_null(bytes: &[u8]) -> Option<usize> {
+        bytes.iter().position(|&byte| byte == 0)
+    }
+}
+
+/// Certificates used for a connection.
+///
+/// Both RSA and elliptic curve (e.g. P-256 ECDSA) certificates and keys are supported. In server
+/// mode, the ciphersuites are selected according to the type of the [Certificates::private_key].
+///
+/// # Note:
+/// Both [certificate](Certificates::certificate) and [private_key](Certificates::private_key) must be set in pair.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Certificates<'a> {
+    /// Trusted CA (Certificate Authority) chain to be used for certificate
+    /// verification during the SSL/TLS handshake.
+    ///
+    /// Certificates can be chained. When dealing with intermediate CA certificates,
+    /// make sure to include the entire chain up to the root CA.
+    ///
+    /// A PEM bundle with several concatenated certificates is supported. Certificates of the
+    /// bundle that can't be parsed are skipped with a warning.
+    ///
+    /// The whole chain is kept in RAM for the lifetime of the [Session]. Loading CA certificates
+    /// on demand during verification (`mbedtls_ssl_conf_ca_cb`) isn't available, as the bundled
+    /// mbedtls is built without `MBEDTLS_X509_TRUSTED_CERTIFICATE_CALLBACK`. To save memory, only
+    /// include the roots actually needed by the servers you connect to.
+    ///
+    /// The validity period of the certificates is only checked once a clock was set with
+    /// [set_time_source], as mbedtls is built without `MBEDTLS_HAVE_TIME_DATE`.
+    ///
+    /// # Client:
+    /// In Client mode, the CA chain should contain the trusted CA certificates
+    /// that will be used to verify the server's certificate during the handshake.
+    ///
+    /// # Server:
+    /// In server mode, the CA chain should contain the trusted CA certificates
+    /// that will be used to verify the client's certificate during the handshake.
+    /// When set to [None] the server will not request nor perform any verification
+    /// on the client certificates. Only set when you want to use client authentication.
+    ///
+    /// When set to [None] in client mode, the server's certificate is not verified at all. See
+    /// [Session::new_insecure_client].
+    pub ca_chain: Option<X509<'a>>,
+
+    /// Own certificate chain used for requests
+    /// It should contain in order from the bottom up your certificate chain.
+    /// The top certificate (self-signed) can be omitted.
+    ///
+    /// To send intermediate certificates, use a PEM [X509] with the certificates concatenated
+    /// leaf first, followed by each certificate's issuer. The whole chain is sent to the peer in
+    /// that order, so peers that only trust the root can build the path. A DER [X509] holds a
+    /// single certificate.
+    ///
+    /// # Client:
+    /// In client mode, this certificate will be used for client authentication
+    /// when communicating wiht the server. Use [None] if you don't want to use
+    /// client authentication.
+    ///
+    /// The certificate is sent whenever the server asks for one. mbedtls skips the list of
+    /// acceptable CAs of the server's CertificateRequest without storing it, so a client can't
+    /// pick among several certificates based on it: choose the certificate before creating the
+    /// [Session], e.g. by the server's hostname.
+    ///
+    /// # Server:
+    /// In server mode, this will be the certificate given to the client when
+    /// performing a handshake.
+    pub certificate: Option<X509<'a>>,
+
+    /// Private key paired with the certificate. Must be set when [Certificates::certificate]
+    /// is not [None]
+    ///
+    /// RSA keys (PKCS#1 or PKCS#8) and EC keys (SEC1 or PKCS#8) are accepted. The format is
+    /// detected automatically, use [X509::pkcs8_key_pem] or [X509::sec1_key_pem] to insist on a
+    /// format.
+    ///
+    /// For a key that can't be extracted, e.g. from a secure element, leave both this and the
+    /// [Certificates::certificate] [None] and use [Session::with_key_provider] instead.
+    pub private_key: Option<X509<'a>>,
+
+    /// Password used for the private key.
+    /// Use [None] when the private key doesn't have a password.
+    ///
+    /// Both encrypted PKCS#8 keys (`BEGIN ENCRYPTED PRIVATE KEY`) and legacy encrypted PEM keys
+    /// (`Proc-Type: 4,ENCRYPTED`) are supported. The key is decrypted while the [Session] is
+    /// created. The password is only borrowed and never copied by this crate, and mbedtls wipes
+    /// the key derived from it after use. Clearing the password itself is up to the caller.
+    pub password: Option<&'a str>,
+
+    /// Certificate Revocation List checked when verifying the peer's certificate.
+    ///
+    /// Use [X509::pem] (several concatenated CRLs are supported) or [X509::der]. A certificate
+    /// that was revoked by the CRL fails the verification with [VerifyFlags::REVOKED], which
+    /// makes the handshake fail when the certificate is verified with [VerifyMode::Required].
+    /// Only used together with a [Certificates::ca_chain].
+    ///
+    /// mbedtls doesn't support OCSP, neither stapled responses (the `status_request` extension)
+    /// nor OCSP requests, so a CRL is the only way to check for revoked certificates.
+    pub crl: Option<X509<'a>>,
+}
+
+impl<'a> core::fmt::Debug for Certificates<'a> {
+    /// The private key and its password are redacted, so the certificates can be logged safely.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Certificates")
+            .field("ca_chain", &self.ca_chain)
+            .field("certificate", &self.certificate)
+            .field("private_key", &self.private_key.map(|_| Redacted))
+            .field("password", &self.password.map(|_| Redacted))
+            .field("crl", &self.crl)
+            .finish()
+    }
+}
+
+/// Placeholder for secrets in [core::fmt::Debug] output
+struct Redacted;
+
+impl core::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<'a> Default for Certificates<'a> {
+    fn default() -> Self {
+        Self {
+            ca_chain: Default::default(),
+            certificate: Default::default(),
+            private_key: Default::default(),
+            password: Default::default(),
+            crl: Default::default(),
+        }
+    }
+}
+
+impl<'a> Certificates<'a> {
+    // Initialize the SSL using this set of certificates
+    fn init_ssl(
+        &self,
+        servername: &str,
+        mode: Mode,
+        min_version: TlsVersion,
+    ) -> Result<
+        (
+            *mut mbedtls_ctr_drbg_context,
+            *mut mbedtls_ssl_context,
+            *mut mbedtls_ssl_config,
+            *mut mbedtls_x509_crt,
+            *mut mbedtls_x509_crt,
+            *mut mbedtls_pk_context,
+            *mut mbedtls_x509_crl,
+        ),
+        TlsError,
+    > {
+        // Make sure that both certificate and private_key are either Some() or None
+        if self.certificate.is_some() != self.private_key.is_some() {
+            return Err(TlsError::IncompleteKeyPair);
+        }
+        // The name of a client has to fit the null-terminated copy passed to mbedtls
+        if mode == Mode::Client
+            && (servername.len() > MBEDTLS_SSL_MAX_HOST_NAME_LEN as usize
+                || servername.contains('\0'))
+        {
+            return Err(TlsError::Config(MBEDTLS_ERR_SSL_BAD_INPUT_DATA));
+        }
+        // Without a name, mbedtls would accept a certificate of any host issued by the CA
+        if mode == Mode::Client && servername.is_empty() && self.ca_chain.is_some() {
+            return Err(TlsError::Config(MBEDTLS_ERR_SSL_BAD_INPUT_DATA));
+        }
+
+        unsafe {
+            error_checked!(psa_crypto_init(), TlsError::Config)?;
+
+            let drbg_context = calloc(1, size_of::<mbedtls_ctr_drbg_context>() as u32)
+                as *mut mbedtls_ctr_drbg_context;
+            let ssl_context =
+                calloc(1, size_of::<mbedtls_ssl_context>() as u32) as *mut mbedtls_ssl_context;
+            let ssl_config =
+                calloc(1, size_of::<mbedtls_ssl_config>() as u32) as *mut mbedtls_ssl_config;
+            let crt = calloc(1, size_of::<mbedtls_x509_crt>() as u32) as *mut mbedtls_x509_crt;
+            let certificate =
+                calloc(1, size_of::<mbedtls_x509_crt>() as u32) as *mut mbedtls_x509_crt;
+            let private_key =
+                calloc(1, size_of::<mbedtls_pk_context>() as u32) as *mut mbedtls_pk_context;
+            let crl = calloc(1, size_of::<mbedtls_x509_crl>() as u32) as *mut mbedtls_x509_crl;
+
+            let allocations = [
+                drbg_context as *const u8,
+                ssl_context as *const u8,
+                ssl_config as *const u8,
+                crt as *const u8,
+                certificate as *const u8,
+                private_key as *const u8,
+                crl as *const u8,
+            ];
+            if allocations.iter().any(|ptr| ptr.is_null()) {
+                for ptr in allocations.into_iter().filter(|ptr| !ptr.is_null()) {
+                    free(ptr);
+                }
+                return Err(TlsError::OutOfMemory);
+            }
+
+            mbedtls_ssl_init(ssl_context);
+            mbedtls_ssl_config_init(ssl_config);
+            // Initialize CA chain
+            mbedtls_x509_crt_init(crt);
+            // Initialize certificate
+            mbedtls_x509_crt_init(certificate);
+            // Initialize private key
+            mbedtls_pk_init(private_key);
+            // Initialize revocation list
+            mbedtls_x509_crl_init(crl);
+            (*ssl_config).private_f_dbg = Some(dbg_print);
+            // Init RNG, the CTR_DRBG is seeded and reseeded from the hardware RNG
+            mbedtls_ctr_drbg_init(drbg_context);
+
+            // Frees everything if one of the steps below fails
+            let guard = InitGuard {
+                drbg_context,
+                ssl_context,
+                ssl_config,
+                crt,
+                certificate,
+                private_key,
+                crl,
+            };
+
+            error_checked!(
+                mbedtls_ctr_drbg_seed(
+                    drbg_context,
+                    Some(rng),
+                    core::ptr::null_mut(),
+                    core::ptr::null(),
+                    0,
+                ),
+                TlsError::RngSeed
+            )?;
+            mbedtls_ssl_conf_rng(
+                ssl_config,
+                Some(mbedtls_ctr_drbg_random),
+                drbg_context as *mut c_void,
+            );
+
+            error_checked!(
+                mbedtls_ssl_config_defaults(
+                    ssl_config,
+                    mode.to_mbed_tls(),
+                    MBEDTLS_SSL_TRANSPORT_STREAM as i32,
+                    MBEDTLS_SSL_PRESET_DEFAULT as i32,
+                ),
+                TlsError::Config
+            )?;
+
+            mbedtls_ssl_conf_min_version(
+                ssl_config,
+                MBEDTLS_SSL_MAJOR_VERSION_3 as i32,
+                min_version.to_mbed_tls_minor(),
+            );
+
+            // mbedtls still accepts 1024 bits by default
+            mbedtls_ssl_conf_dhm_min_bitlen(ssl_config, 2048);
+
+            mbedtls_ssl_conf_verify(ssl_config, Some(verify), core::ptr::null_mut());
+            if mode == Mode::Client && parse_ip_address(servername).is_some() {
+                mbedtls_ssl_set_verify(
+                    ssl_context,
+                    Some(verify_ip_address),
+                    ssl_context as *mut c_void,
+                );
+            }
+
+            mbedtls_ssl_conf_authmode(
+                ssl_config,
+                if self.ca_chain.is_some() {
+                    MBEDTLS_SSL_VERIFY_REQUIRED as i32
+                } else {
+                    // Use this config when in server mode
+                    // Ref: https://os.mbed.com/users/markrad/code/mbedtls/docs/tip/ssl_8h.html#a5695285c9dbfefec295012b566290f37
+                    MBEDTLS_SSL_VERIFY_NONE as i32
+                },
+            );
+
+            // An empty name would be sent as an invalid, empty ServerName extension. It is only
+            // allowed without verification, so there is no hostname to check.
+            if mode == Mode::Client && !servername.is_empty() {
+                // mbedtls copies the name, this buffer only adds the null terminator
+                let mut hostname = [0u8; MBEDTLS_SSL_MAX_HOST_NAME_LEN as usize + 1];
+                hostname[..servername.len()].copy_from_slice(servername.as_bytes());
+                error_checked!(
+                    mbedtls_ssl_set_hostname(ssl_context, hostname.as_ptr() as *const c_char),
+                    TlsError::Config
+                )?;
+            }
+
+            if let Some(ca_chain) = self.ca_chain {
+                // Unsupported certificates of a bundle are skipped rather than failing the session
+                let failed = parse_x509_chain(crt, &ca_chain).map_err(|err| match err {
+                    TlsError::MbedTlsError(code) => TlsError::CaChainParse(code),
+                    err => err,
+                })?;
+                if failed > 0 {
+                    log::warn!("{failed} certificates of the CA chain could not be parsed");
+                }
+            }
+
+            if let (Some(cert), Some(key)) = (self.certificate, self.private_key) {
+                // Certificate
+                parse_own_certificate(certificate, &cert)?;
+
+                // Private key
+                let (password_ptr, password_len) = if let Some(password) = self.password {
+                    (password.as_ptr(), password.len())
+                } else {
+                    (core::ptr::null(), 0)
+                };
+                error_checked!(mbedtls_pk_parse_key(
+                    private_key,
+                    key.as_ptr(),
+                    key.len(),
+                    password_ptr,
+                    password_len,
+                    // Needed to compute the public key of EC keys that don't include it
+                    Some(mbedtls_ctr_drbg_random),
+                    drbg_context as *mut c_void,
+                ))
+                .map_err(|err| match err {
+                    TlsError::MbedTlsError(MBEDTLS_ERR_PK_PASSWORD_REQUIRED) => {
+                        TlsError::PasswordRequired
+                    }
+                    TlsError::MbedTlsError(MBEDTLS_ERR_PK_PASSWORD_MISMATCH) => {
+                        TlsError::PasswordMismatch
+                    }
+                    TlsError::MbedTlsError(code) => TlsError::PrivateKeyParse(code),
+                    err => err,
+                })?;
+
+                mbedtls_ssl_conf_own_cert(ssl_config, certificate, private_key);
+            }
+
+            let ca_crl = if let Some(revocation_list) = self.crl {
+                error_checked!(
+                    mbedtls_x509_crl_parse(crl, revocation_list.as_ptr(), revocation_list.len()),
+                    TlsError::CrlParse
+                )?;
+                crl
+            } else {
+                core::ptr::null_mut()
+            };
+
+            mbedtls_ssl_conf_ca_chain(ssl_config, crt, ca_crl);
+            error_checked!(mbedtls_ssl_setup(ssl_context, ssl_config), TlsError::Config)?;
+            // The session owns the contexts from here on
+            core::mem::forget(guard);
+            Ok((
+                drbg_context,
+                ssl_context,
+                ssl_config,
+                crt,
+                certificate,
+                private_key,
+                crl,
+            ))
+        }
+    }
+}
+
+/// The contexts allocated by [Certificates::init_ssl], which are freed on drop if the
+/// initialization fails half-way
+struct InitGuard {
+    drbg_context: *mut mbedtls_ctr_drbg_context,
+    ssl_context: *mut mbedtls_ssl_context,
+    ssl_config: *mut mbedtls_ssl_config,
+    crt: *mut mbedtls_x509_crt,
+    certificate: *mut mbedtls_x509_crt,
+    private_key: *mut mbedtls_pk_context,
+    crl: *mut mbedtls_x509_crl,
+}
+
+impl Drop for InitGuard {
+    fn drop(&mut self) {
+        unsafe {
+            // The SSL context refers to its configuration, so it has to be freed first
+            mbedtls_ssl_free(self.ssl_context);
+            mbedtls_ssl_config_free(self.ssl_config);
+            mbedtls_ctr_drbg_free(self.drbg_context);
+            mbedtls_x509_crt_free(self.crt);
+            mbedtls_x509_crt_free(self.certificate);
+            mbedtls_pk_free(self.private_key);
+            mbedtls_x509_crl_free(self.crl);
+            free(self.drbg_context as *const _);
+            free(self.ssl_config as *const _);
+            free(self.ssl_context as *const _);
+            free(self.crt as *const _);
+            free(self.certificate as *const _);
+            free(self.private_key as *const _);
+            free(self.crl as *const _);
+        }
+    }
+}
+
+/// A private RSA key that stays outside of the session, e.g. in a secure element, see
+/// [Session::with_key_provider]
+///
+/// The handshake calls out to the provider for the operations of the private key, the key itself
+/// is never handed to mbedtls.
+///
+/// Only RSA keys with PKCS#1 v1.5 signatures can be delegated, through the RSA-alt interface of
+/// mbedtls (`mbedtls_pk_setup_rsa_alt`). As TLS 1.3 requires RSA-PSS signatures, such keys only
+/// work with TLS 1.2. Opaque keys (`mbedtls_pk_setup_opaque`), which would also cover EC keys,
+/// need `MBEDTLS_USE_PSA_CRYPTO`, which the precompiled mbedtls isn't built with.
+pub trait KeyProvider {
+    /// Returns the size of the RSA modulus in bytes, which is also the size of a signature
+    fn key_len(&self) -> usize;
+
+    /// Signs `hash` with PKCS#1 v1.5 padding, writing [KeyProvider::key_len] bytes to
+    /// `signature`.
+    ///
+    /// `hash_alg` is the algorithm `hash` was computed with, whose `DigestInfo` has to be
+    /// included in the padding. It's [None] if `hash` is to be signed as is.
+    fn sign(
+        &self,
+        hash_alg: Option<HashAlgorithm>,
+        hash: &[u8],
+        signature: &mut [u8],
+    ) -> Result<(), TlsError>;
+
+    /// Decrypts the PKCS#1 v1.5 encrypted `input` into `output`, returning the length of the
+    /// decrypted data.
+    ///
+    /// This is only used by the RSA key exchange (`TLS-RSA-WITH-*` ciphersuites) of a server. The
+    /// default implementation fails, which excludes these ciphersuites.
+    fn decrypt(&self, input: &[u8], output: &mut [u8]) -> Result<usize, TlsError> {
+        let _ = (input, output);
+        Err(TlsError::MbedTlsError(MBEDTLS_ERR_PK_FEATURE_UNAVAILABLE))
+    }
+}
+
+/// Statistics about the handshake of a connected session
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HandshakeStats {
+    /// How long the handshake took, in the unit of the clock set with `with_clock`.
+    /// [None] if no clock was set.
+    pub duration: Option<u64>,
+    /// Number of bytes written to the stream during the handshake
+    pub bytes_sent: usize,
+    /// Number of bytes read from the stream during the handshake
+    pub bytes_received: usize,
+    /// Whether a previous session was resumed instead of performing a full handshake
+    pub resumed: bool,
+}
+
+/// Traffic statistics of a connected session
+///
+/// Only the traffic after the handshake is counted in the application data fields, see
+/// [HandshakeStats] for the handshake itself.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IoStats {
+    /// Number of decrypted bytes returned by reads
+    pub app_bytes_read: usize,
+    /// Number of plaintext bytes accepted by writes
+    pub app_bytes_written: usize,
+    /// Number of application data records that were decrypted
+    pub records_read: usize,
+    /// Number of application data records that were sent
+    pub records_written: usize,
+    /// Number of encrypted bytes read from the stream, including the handshake
+    pub bytes_received: usize,
+    /// Number of encrypted bytes written to the stream, including the handshake
+    pub bytes_sent: usize,
+}
+
+impl IoStats {
+    fn add_read(&mut self, len: usize, new_record: bool) {
+        self.app_bytes_read += len;
+        if new_record {
+            self.records_read += 1;
+        }
+    }
+
+    fn add_written(&mut self, len: usize) {
+        if len > 0 {
+            self.app_bytes_written += len;
+            // mbedtls_ssl_write sends at most one record
+            self.records_written += 1;
+        }
+    }
+}
+
+/// A TLS session over a blocking stream, see [Session::new]
+///
+/// The session has no buffers of its own: mbedtls reads from and writes to the stream
+/// directly, within its record buffers. Unlike the async `asynch::Session`, it
+/// isn't generic over a buffer size, so only one instance of the code is generated per stream
+/// type.
+pub struct Session<T> {
+    stream: T,
+    drbg_context: *mut mbedtls_ctr_drbg_context,
+    ssl_context: *mut mbedtls_ssl_context,
+    ssl_config: *mut mbedtls_ssl_config,
+    crt: *mut mbedtls_x509_crt,
+    client_crt: *mut mbedtls_x509_crt,
+    private_key: *mut mbedtls_pk_context,
+    crl: *mut mbedtls_x509_crl,
+    // Indicate if this session is the one holding the RSA ref
+    owns_rsa: bool,
+    clock: Option<fn() -> u64>,
+    bytes_sent: usize,
+    bytes_received: usize,
+    handshake_stats: HandshakeStats,
+    // Start of the handshake and whether it resumed a session, kept across handshake steps
+    handshake_start: Option<u64>,
+    handshake_resumed: bool,
+    // The limit set with `with_max_handshake_size`, and whether the peer exceeded it
+    max_handshake_size: Option<usize>,
+    handshake_too_large: bool,
+    // Whether every write flushes the stream, see `with_immediate_flush`
+    immediate_flush: bool,
+    // Whether the first ClientHello is sent with the TLS 1.0 record version
+    legacy_record_version: bool,
+    io_stats: IoStats,
+    // Whether the peer sent a close_notify alert
+    peer_closed: bool,
+    // The error of the stream that made the last mbedtls call fail
+    io_error: Option<embedded_io::ErrorKind>,
+    // The callbacks of the stream, kept so that dropping the session can send a close_notify
+    // alert without requiring the stream to implement Read and Write
+    bio_send: mbedtls_ssl_send_t,
+    bio_recv: mbedtls_ssl_recv_t,
+    handshake_progress: Option<fn(HandshakeState)>,
+    // The secrets kept for `export_keying_material`, mbedtls keeps a pointer to it
+    key_export: *mut KeyExport,
+    // The group list set with `with_curves`, mbedtls keeps a pointer to it
+    groups: *mut u16,
+    // The list set with `with_sig_algs`, mbedtls keeps a pointer to it
+    sig_algs: *mut u16,
+    // The trust anchors set with `with_trust_anchors`, passed to the verify callback
+    trust_anchors: *mut &'static [TrustAnchor<'static>],
+    // The provider set with `with_key_provider`, mbedtls keeps a pointer to it
+    key_provider: *mut &'static dyn KeyProvider,
+    // The generator set with `with_rng`, mbedtls keeps a pointer to it
+    rng: *mut &'static mut dyn RngCore,
+    yield_hook: Option<fn()>,
+    // Bytes set with `with_initial_data`, returned by `receive` before reading from the stream
+    initial_data: *mut u8,
+    initial_data_len: usize,
+    initial_data_pos: usize,
+}
+
+impl<T> Session<T> {
+    /// Create a session for a TLS stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream for the connection.
+    /// * `servername` - The hostname to check against the received server certificate. It sets the ServerName TLS extension, too, if that extension is enabled. (client-side only)
+    ///   When a [Certificates::ca_chain] is set, the handshake fails with [TlsError::HostnameMismatch]
+    ///   unless the certificate's Subject Alternative Names (or Common Name if there are none)
+    ///   match this name. An IPv4 or IPv6 address also matches the IP address entries of the
+    ///   Subject Alternative Names.
+    ///   A server ignores it: there is no SNI callback, so the server always presents
+    ///   [Certificates::certificate], whether the client sent a ServerName or not.
+    ///   The name is copied, so it only has to live for this call. It must be at most 255 bytes
+    ///   long and must not contain a null byte, otherwise [TlsError::Config] is returned. A client
+    ///   can only use an empty name if it doesn't verify the server, i.e. without a
+    ///   [Certificates::ca_chain] or trust anchors, see [Session::new_insecure_client].
+    /// * `mode` - Use [Mode::Client] if you are running a client. [Mode::Server] if you are
+    /// running a server.
+    /// * `min_version` - The minimum TLS version for the connection, that will be accepted.
+    /// * `certificates` - Certificate chain for the connection. Will play a different role
+    /// depending on if running as client or server. See [Certificates] for more information.
+    ///
+    /// # Errors
+    ///
+    /// This will return a [TlsError] if there were an error during the initialization of the
+    /// session. This can happen if there is not enough memory of if the certificates are in an
+    /// invalid format.
+    ///
+    /// The error tells which step failed: [TlsError::CaChainParse],
+    /// [TlsError::CertificateParse], [TlsError::PrivateKeyParse] and [TlsError::CrlParse] for
+    /// the [Certificates], [TlsError::RngSeed] for the random number generator and
+    /// [TlsError::Config] for the remaining configuration, each holding the code mbedtls
+    /// returned. [TlsError::IncompleteKeyPair] is returned if only one of the certificate and the
+    /// private key was given.
+    pub fn new(
+        stream: T,
+        servername: &str,
+        mode: Mode,
+        min_version: TlsVersion,
+        certificates: Certificates,
+    ) -> Result<Self, TlsError> {
+        let (drbg_context, ssl_context, ssl_config, crt, client_crt, private_key, crl) =
+            certificates.init_ssl(servername, mode, min_version)?;
+        return Ok(Self {
+            stream,
+            drbg_context,
+            ssl_context,
+            ssl_config,
+            crt,
+            client_crt,
+            private_key,
+            crl,
+            owns_rsa: false,
+            clock: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            handshake_stats: HandshakeStats::default(),
+            handshake_start: None,
+            handshake_resumed: false,
+            max_handshake_size: None,
+            handshake_too_large: false,
+            immediate_flush: false,
+            legacy_record_version: false,
+            io_stats: IoStats::default(),
+            peer_closed: false,
+            io_error: None,
+            bio_send: None,
+            bio_recv: None,
+            handshake_progress: None,
+            key_export: core::ptr::null_mut(),
+            groups: core::ptr::null_mut(),
+            sig_algs: core::ptr::null_mut(),
+            trust_anchors: core::ptr::null_mut(),
+            key_provider: core::ptr::null_mut(),
+            rng: core::ptr::null_mut(),
+            yield_hook: None,
+            initial_data: core::ptr::null_mut(),
+            initial_data_len: 0,
+            initial_data_pos: 0,
+        });
+    }
+
+    /// Create a client session that doesn't verify the server, for testing purposes.
+    ///
+    /// **This is insecure**: any server, including an attacker in the middle, is accepted. Only
+    /// use this against local test servers.
+    ///
+    /// This is the same as calling [Session::new] in [Mode::Client] with the default
+    /// [Certificates]: the server's certificate isn't verified and no client certificate is
+    /// sent. The handshake fails if the server requires client authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream for the connection.
+    /// * `servername` - The name sent in the ServerName TLS extension. Use an empty string to
+    ///   not send the extension.
+    /// * `min_version` - The minimum TLS version for the connection, that will be accepted.
+    pub fn new_insecure_client(
+        stream: T,
+        servername: &str,
+        min_version: TlsVersion,
+    ) -> Result<Self, TlsError> {
+        Self::new(
+            stream,
+            servername,
+            Mode::Client,
+            min_version,
+            Certificates::default(),
+        )
+    }
+
+    /// Enable the use of the hardware accelerated RSA peripheral for the [Session].
+    ///
+    /// Note: Due to implementation constraints, this session and every other session will use the
+    /// hardware accelerated RSA driver until the sesssion called with this function is dropped.
+    ///
+    /// Only RSA operations are accelerated. Elliptic curve (ECDSA/ECDHE) operations always run in
+    /// software, as none of the supported chips has an ECC peripheral.
+    ///
+    /// To use the RSA peripheral with several sessions at the same time, use [HardwareRsa]
+    /// instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `rsa` - The RSA peripheral from the HAL
+    pub fn with_hardware_rsa(mut self, rsa: impl Peripheral<P = RSA>) -> Self {
+        unsafe { RSA_REF = core::mem::transmute(Some(Rsa::new(rsa, None))) }
+        self.owns_rsa = true;
+        self
+    }
+
+    /// Set after how many requests the random number generator of the [Session] is reseeded.
+    ///
+    /// The session uses a CTR_DRBG seeded from the hardware RNG, which by default is reseeded
+    /// every `MBEDTLS_CTR_DRBG_RESEED_INTERVAL` (10000) requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - The number of requests between two reseeds
+    pub fn with_drbg_reseed_interval(self, interval: u32) -> Self {
+        unsafe { mbedtls_ctr_drbg_set_reseed_interval(self.drbg_context, interval as c_int) };
+        self
+    }
+
+    /// Enable or disable prediction resistance of the random number generator of the [Session].
+    ///
+    /// With prediction resistance, the CTR_DRBG is reseeded from the hardware RNG before every
+    /// request, which is more expensive. It is disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether prediction resistance should be used
+    pub fn with_prediction_resistance(self, enabled: bool) -> Self {
+        let resistance = if enabled {
+            MBEDTLS_CTR_DRBG_PR_ON
+        } else {
+            MBEDTLS_CTR_DRBG_PR_OFF
+        };
+        unsafe {
+            mbedtls_ctr_drbg_set_prediction_resistance(self.drbg_context, resistance as c_int)
+        };
+        self
+    }
+
+    /// Allow renegotiating the session once it is connected. Disabled by default.
+    ///
+    /// Renegotiation is only supported by TLS 1.2. It can be started by either side with
+    /// `renegotiate()` on the connected session, or by the peer while reading.
+    ///
+    /// # Security
+    ///
+    /// Keep renegotiation disabled unless you really need it: it increases the attack surface
+    /// and lets the peer trigger expensive handshakes at will. Only secure renegotiation
+    /// (RFC 5746) is allowed, peers that don't support it can't renegotiate.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether renegotiation is allowed
+    pub fn with_renegotiation(self, enabled: bool) -> Self {
+        let renegotiation = if enabled {
+            MBEDTLS_SSL_RENEGOTIATION_ENABLED
+        } else {
+            MBEDTLS_SSL_RENEGOTIATION_DISABLED
+        };
+        unsafe { mbedtls_ssl_conf_renegotiation(self.ssl_config, renegotiation as c_int) };
+        self
+    }
+
+    /// Returns the hardware accelerators that mbedtls currently uses.
+    ///
+    /// This tells whether [Session::with_hardware_rsa] is effective: it has no effect on the
+    /// ESP32, and the accelerator is shared by all sessions, see [HardwareRsa]. SHA and AES
+    /// always run in software.
+    pub fn active_accelerators(&self) -> Accelerators {
+        Accelerators::active()
+    }
+
+    /// Set the minimum size of the Diffie-Hellman prime offered by a server for a DHE
+    /// ciphersuite. Defaults to 2048 bits.
+    ///
+    /// The handshake fails if the server offers a smaller group. This only applies to clients,
+    /// and has no effect on ECDHE ciphersuites.
+    ///
+    /// # Arguments
+    ///
+    /// * `bits` - The minimum number of bits of the prime
+    pub fn with_min_dhm_bitlen(self, bits: u32) -> Self {
+        unsafe { mbedtls_ssl_conf_dhm_min_bitlen(self.ssl_config, bits as c_uint) };
+        self
+    }
+
+    /// Set how the certificate of the peer is verified.
+    ///
+    /// By default, the certificate is verified ([VerifyMode::Required]) when a
+    /// [Certificates::ca_chain] is given, and not verified at all ([VerifyMode::None])
+    /// otherwise. With [VerifyMode::Optional], the handshake completes even if the verification
+    /// fails, so the reasons can be inspected with `verify_result()` on the connected session
+    /// before deciding whether to trust the peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - How the certificate is verified
+    pub fn with_verify_mode(self, mode: VerifyMode) -> Self {
+        unsafe { mbedtls_ssl_conf_authmode(self.ssl_config, mode.to_mbed_tls()) };
+        self
+    }
+
+    /// Set the level of the mbedtls debug messages that are logged for this session, from 0
+    /// (none) to 4 (verbose).
+    ///
+    /// This takes precedence over the global level set with [set_debug], in both directions:
+    /// a session can be debugged while the others stay quiet, or be silenced while the others
+    /// log. Debug messages aren't available on Xtensa chips.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - The highest level of the messages that are logged
+    pub fn with_debug_level(self, level: u32) -> Self {
+        unsafe { conf_debug_level(self.ssl_config, level) };
+        self
+    }
+
+    /// Set a clock used to measure the duration of the handshake.
+    ///
+    /// The duration is reported in [HandshakeStats::duration], in the unit of the values
+    /// returned by `clock` (e.g. milliseconds since boot).
+    ///
+    /// # Arguments
+    ///
+    /// * `clock` - Returns the current time. It must never go backwards.
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Set a callback that is called whenever the handshake advances to a new state.
+    ///
+    /// This can be used to report the progress of the handshake, or to find out where a stuck
+    /// handshake stalls.
+    ///
+    /// # Arguments
+    ///
+    /// * `progress` - Called with the new state during [Session::connect]
+    pub fn with_handshake_progress(mut self, progress: fn(HandshakeState)) -> Self {
+        self.handshake_progress = Some(progress);
+        self
+    }
+
+    /// Keep the secrets needed to export keying material from the connection, see
+    /// `export_keying_material()` on the connected session.
+    ///
+    /// The master secret of TLS 1.2 connections is kept in the session for this, in addition to
+    /// the keys mbedtls derived from it. It is erased when the session is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::OutOfMemory] if the secrets couldn't be allocated.
+    pub fn with_keying_material_export(mut self) -> Result<Self, TlsError> {
+        unsafe { conf_key_export(self.ssl_context, &mut self.key_export)? };
+        Ok(self)
+    }
+
+    /// Limit the number of bytes the peer may send during the handshake.
+    ///
+    /// mbedtls copies the certificate chain of the peer to the heap, so a peer sending a huge chain
+    /// can exhaust the RAM. With a limit, the handshake fails with [TlsError::HandshakeTooLarge] as
+    /// soon as the bytes read from the stream exceed it, before mbedtls processes them. The limit
+    /// includes the record headers and every other handshake message of the peer, so allow a few KB
+    /// more than the largest expected certificate chain. Only the initial handshake is limited, not
+    /// a renegotiation.
+    ///
+    /// Without a limit, a single handshake message is only bounded by the input buffer of mbedtls,
+    /// 16 KB for TLS 1.2 and TLS 1.3.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Maximum number of bytes received until the handshake completed
+    pub fn with_max_handshake_size(mut self, size: usize) -> Self {
+        self.max_handshake_size = Some(size);
+        self
+    }
+
+    /// Flush the stream after every write, for latency-sensitive request/response protocols.
+    ///
+    /// Each write already passes the record to the stream. With this, the stream is flushed as
+    /// well, so that buffering in the stream doesn't delay small records. Without it, call
+    /// `flush()` after the last write of a message.
+    ///
+    /// Nagle's algorithm of the TCP socket can still delay small segments. It is a setting of the
+    /// socket, so disable it there if the network stack allows it.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether every write flushes the stream
+    pub fn with_immediate_flush(mut self, enabled: bool) -> Self {
+        self.immediate_flush = enabled;
+        self
+    }
+
+    /// Send the ClientHello in a record with the TLS 1.0 version, for old middleboxes (e.g.
+    /// firewalls) that drop connections otherwise. (client-side only)
+    ///
+    /// This is a compatibility workaround, only use it if a middlebox requires it. The version of
+    /// the record layer is independent of the negotiated version, which this doesn't change: the
+    /// ClientHello still offers the versions given to [Session::new], and every other record has
+    /// the usual version. Both TLS 1.2 and TLS 1.3 allow TLS 1.0 as the version of the record of
+    /// the first ClientHello.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether the first ClientHello is sent with the TLS 1.0 record version
+    pub fn with_legacy_record_version(mut self, enabled: bool) -> Self {
+        self.legacy_record_version = enabled;
+        self
+    }
+
+    /// Let returning clients resume their session from `cache`, instead of performing a full
+    /// handshake. (server-side only)
+    ///
+    /// See [SessionCache].
+    pub fn with_session_cache<const N: usize, const ENTRY_SIZE: usize>(
+        self,
+        cache: &'static SessionCache<N, ENTRY_SIZE>,
+    ) -> Self {
+        unsafe { cache.configure(self.ssl_config) };
+        self
+    }
+
+    /// Set a hook that is called whenever the session waits for the underlying stream.
+    ///
+    /// [Session::connect], `renegotiate()` and `read()` retry immediately when the stream has no
+    /// data or can't take more yet, which keeps the CPU busy. The hook is called before each
+    /// retry and can e.g. feed a watchdog or sleep briefly.
+    ///
+    /// # Arguments
+    ///
+    /// * `hook` - Called each time an operation would block
+    pub fn with_yield(mut self, hook: fn()) -> Self {
+        self.yield_hook = Some(hook);
+        self
+    }
+
+    /// Set bytes that were already read from the stream, to be processed before reading from
+    /// the stream.
+    ///
+    /// This allows peeking at the start of a connection, e.g. to tell TLS and plaintext apart on
+    /// the same port, and to hand the connection over to TLS afterwards. The bytes are copied.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The bytes read from the stream so far
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::OutOfMemory] if the bytes couldn't be copied.
+    pub fn with_initial_data(mut self, data: &[u8]) -> Result<Self, TlsError> {
+        if data.is_empty() {
+            return Ok(self);
+        }
+        unsafe {
+            let initial_data = calloc(1, data.len() as u32) as *mut u8;
+            if initial_data.is_null() {
+                return Err(TlsError::OutOfMemory);
+            }
+            initial_data.copy_from_nonoverlapping(data.as_ptr(), data.len());
+            self.free_initial_data();
+            self.initial_data = initial_data;
+        }
+        self.initial_data_len = data.len();
+        Ok(self)
+    }
+
+    /// Restrict the named curves offered and accepted for the ECDHE key exchange.
+    ///
+    /// The curves are given in order of preference. By default, mbedtls allows every curve it
+    /// was built with, preferring X25519, then secp256r1 and secp384r1. If the peer supports
+    /// none of the given curves, the handshake fails with a [TlsError::MbedTlsError] (usually
+    /// `MBEDTLS_ERR_SSL_HANDSHAKE_FAILURE`) instead of falling back to another curve.
+    ///
+    /// # Arguments
+    ///
+    /// * `curves` - The allowed curves, most preferred first
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::OutOfMemory] if the list couldn't be allocated.
+    pub fn with_curves(mut self, curves: &[NamedCurve]) -> Result<Self, TlsError> {
+        unsafe {
+            let groups =
+                replace_list(&mut self.groups, curves.iter().map(NamedCurve::to_mbed_tls))?;
+            mbedtls_ssl_conf_groups(self.ssl_config, groups);
+        }
+        Ok(self)
+    }
+
+    /// Restrict the signature algorithms offered and accepted for the handshake.
+    ///
+    /// The algorithms are given in order of preference. They apply to the signatures of the
+    /// handshake messages (CertificateVerify and ServerKeyExchange). By default, mbedtls offers
+    /// ECDSA with secp256r1/SHA-256, secp384r1/SHA-384 and secp521r1/SHA-512, then RSA-PSS and
+    /// RSA PKCS#1 v1.5, each with SHA-256, SHA-384 and SHA-512. TLS 1.3 never uses RSA PKCS#1
+    /// v1.5 for handshake messages.
+    ///
+    /// If the peer supports none of the given algorithms, the handshake fails with a
+    /// [TlsError::MbedTlsError].
+    ///
+    /// # Arguments
+    ///
+    /// * `sig_algs` - The allowed signature algorithms, most preferred first
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::OutOfMemory] if the list couldn't be allocated.
+    pub fn with_sig_algs(mut self, sig_algs: &[SigAlg]) -> Result<Self, TlsError> {
+        unsafe {
+            let sig_algs =
+                replace_list(&mut self.sig_algs, sig_algs.iter().map(SigAlg::to_mbed_tls))?;
+            mbedtls_ssl_conf_sig_algs(self.ssl_config, sig_algs);
+        }
+        Ok(self)
+    }
+
+    /// Set the Diffie-Hellman parameters of a server, to offer DHE ciphersuites.
+    ///
+    /// Without parameters, a server only offers ECDHE (and static RSA) ciphersuites. The
+    /// parameters are copied into the configuration, so `params` doesn't have to outlive the
+    /// session.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The DH parameters (`BEGIN DH PARAMETERS`), as generated by e.g.
+    ///   `openssl dhparam 2048`. Use [X509::pem] or [X509::der].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [TlsError::MbedTlsError] if the parameters couldn't be parsed.
+    pub fn with_dhm_params(self, params: X509) -> Result<Self, TlsError> {
+        unsafe { conf_dhm_params(self.ssl_config, &params)? };
+        Ok(self)
+    }
 
-impl<T> Session<T> {
-    /// Create a session for a TLS stream.
+    /// Trust the peers whose chain was issued by one of `anchors`, in addition to the
+    /// [Certificates::ca_chain].
+    ///
+    /// This needs much less flash than storing the whole root certificates, see [TrustAnchor].
+    /// The certificate of the peer is required to be verified afterwards, as with
+    /// [VerifyMode::Required], so the anchors also work without a [Certificates::ca_chain].
     ///
     /// # Arguments
     ///
-    /// * `stream` - The stream for the connection.
-    /// * `servername` - The hostname to check against the received server certificate. It sets the ServerName TLS extension, too, if that extension is enabled. (client-side only)
-    /// * `mode` - Use [Mode::Client] if you are running a client. [Mode::Server] if you are
-    /// running a server.
-    /// * `min_version` - The minimum TLS version for the connection, that will be accepted.
-    /// * `certificates` - Certificate chain for the connection. Will play a different role
-    /// depending on if running as client or server. See [Certificates] for more information.
+    /// * `anchors` - The trusted roots, usually constants in flash
     ///
     /// # Errors
     ///
-    /// This will return a [TlsError] if there were an error during the initialization of the
-    /// session. This can happen if there is not enough memory of if the certificates are in an
-    /// invalid format.
-    pub fn new(
-        stream: T,
-        servername: &str,
-        mode: Mode,
-        min_version: TlsVersion,
-        certificates: Certificates,
+    /// Returns [TlsError::OutOfMemory] if the anchors couldn't be registered, or
+    /// [TlsError::Config] for a client created with an empty servername, whose certificate
+    /// couldn't be matched to a host.
+    pub fn with_trust_anchors(
+        mut self,
+        anchors: &'static [TrustAnchor<'static>],
     ) -> Result<Self, TlsError> {
-        let (drbg_context, ssl_context, ssl_config, crt, client_crt, private_key) =
-            certificates.init_ssl(servername, mode, min_version)?;
-        return Ok(Self {
-            stream,
-            drbg_context,
-            ssl_context,
-            ssl_config,
-            crt,
-            client_crt,
-            private_key,
-            owns_rsa: false,
-        });
+        unsafe {
+            conf_trust_anchors(
+                self.ssl_context,
+                self.ssl_config,
+                &mut self.trust_anchors,
+                anchors,
+            )?;
+        }
+        Ok(self)
     }
 
-    /// Enable the use of the hardware accelerated RSA peripheral for the [Session].
+    /// Use `certificate` as own certificate, with its private key held by `provider`.
     ///
-    /// Note: Due to implementation constraints, this session and every other session will use the
-    /// hardware accelerated RSA driver until the sesssion called with this function is dropped.
+    /// This replaces [Certificates::certificate] and [Certificates::private_key] for keys that
+    /// can't be extracted, e.g. from a secure element: the handshake calls `provider` for the
+    /// signature (and for a server, the decryption) instead. See [KeyProvider] for the supported
+    /// keys, which limit the session to TLS 1.2.
     ///
     /// # Arguments
     ///
-    /// * `rsa` - The RSA peripheral from the HAL
-    pub fn with_hardware_rsa(mut self, rsa: impl Peripheral<P = RSA>) -> Self {
-        unsafe { RSA_REF = core::mem::transmute(Some(Rsa::new(rsa, None))) }
-        self.owns_rsa = true;
-        self
+    /// * `certificate` - The certificate chain of the key, as for [Certificates::certificate]
+    /// * `provider` - The operations of the private key
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::IncompleteKeyPair] if the [Certificates] already had a certificate,
+    /// [TlsError::CertificateParse] if `certificate` couldn't be parsed, or
+    /// [TlsError::OutOfMemory] if the provider couldn't be registered.
+    pub fn with_key_provider(
+        mut self,
+        certificate: X509,
+        provider: &'static dyn KeyProvider,
+    ) -> Result<Self, TlsError> {
+        unsafe {
+            conf_key_provider(
+                self.ssl_config,
+                self.client_crt,
+                self.private_key,
+                &mut self.key_provider,
+                &certificate,
+                provider,
+            )?;
+        }
+        Ok(self)
+    }
+
+    /// Use `rng` instead of the default random number generator for this session.
+    ///
+    /// By default, the random numbers of a session come from a CTR_DRBG seeded from the hardware
+    /// RNG. With this, the handshake (e.g. the random values and the ephemeral keys) draws
+    /// directly from `rng` instead, which has to be cryptographically secure. Other sessions
+    /// aren't affected.
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::OutOfMemory] if the generator couldn't be registered.
+    pub fn with_rng(mut self, rng: &'static mut dyn RngCore) -> Result<Self, TlsError> {
+        unsafe { conf_rng(self.ssl_config, &mut self.rng, rng)? };
+        Ok(self)
+    }
+
+    /// Resume the session serialized with `serialize_session` of a previous connection to the
+    /// same server, e.g. after a reboot. (client-side only)
+    ///
+    /// If the server accepts it, the handshake is abbreviated, which saves the expensive public
+    /// key operations. Otherwise, a full handshake is performed. Check
+    /// [ConnectedSession::is_resumed] to find out whether the session was resumed.
+    ///
+    /// The data starts with the version of mbedtls and the options it was built with, so data
+    /// saved by a firmware with a different mbedtls is rejected. The data holds the secrets of the
+    /// session, so it has to be stored as securely as a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [TlsError::MbedTlsError] if the data is invalid, in particular
+    /// [error_codes::VERSION_MISMATCH] if it was saved by an incompatible mbedtls.
+    pub fn with_serialized_session(self, data: &[u8]) -> Result<Self, TlsError> {
+        unsafe { load_session(self.ssl_context, data)? };
+        Ok(self)
+    }
+
+    /// Accept the TLS versions from `min` to `max`, replacing the minimum version given to
+    /// [Session::new].
+    ///
+    /// By default, all versions from the minimum version up to TLS 1.3 are accepted. Passing the
+    /// same version for `min` and `max` only accepts that version.
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::Config] if `min` is newer than `max`.
+    pub fn with_version_range(self, min: TlsVersion, max: TlsVersion) -> Result<Self, TlsError> {
+        unsafe { conf_version_range(self.ssl_config, min, max)? };
+        Ok(self)
+    }
+
+    /// Returns the mbedtls configuration of the session, to set options that aren't wrapped
+    /// by this crate with the `mbedtls_ssl_conf_*` functions of `esp-mbedtls-sys`.
+    ///
+    /// Call this before connecting, so the options apply to the handshake. Options that are only
+    /// used by `mbedtls_ssl_setup`, such as the transport, have no effect anymore, as the context
+    /// was already set up.
+    ///
+    /// # Safety
+    ///
+    /// The configuration is freed together with the session, so the pointer must not be used
+    /// afterwards. Anything given to mbedtls by pointer has to outlive the session. Changing
+    /// options this crate relies on (the RNG, the debug and verify callbacks, the own certificate,
+    /// the CA chain, the curves and signature algorithms) can break the safe API.
+    pub unsafe fn ssl_config(&mut self) -> *mut mbedtls_ssl_config {
+        self.ssl_config
+    }
+
+    /// Returns whether the handshake has completed, without touching the stream
+    ///
+    /// Use this between [Session::connect_step] calls, e.g. to decide whether to wait for the
+    /// socket to become readable or to call [Session::connect].
+    pub fn handshake_done(&self) -> bool {
+        unsafe { handshake_over(self.ssl_context) }
     }
 }
 
@@ -501,49 +2660,100 @@ impl<T> Session<T>
 where
     T: Read + Write,
 {
-    pub fn connect<'b>(self) -> Result<ConnectedSession<T>, TlsError> {
+    pub fn connect<'b>(mut self) -> Result<ConnectedSession<T>, TlsError> {
+        while !self.handshake_done() {
+            let res = self.internal_handshake_step()?;
+            if res == MBEDTLS_ERR_SSL_WANT_READ || res == MBEDTLS_ERR_SSL_WANT_WRITE {
+                self.yield_now();
+            }
+
+            // try again
+        }
+
+        Ok(ConnectedSession { session: self })
+    }
+
+    /// Perform one step of the handshake, for a stream that doesn't block, e.g. in an event loop
+    ///
+    /// Returns `true` once the handshake has completed, after which [Session::connect] returns
+    /// the connected session without touching the stream. `false` means that the handshake needs
+    /// to be continued by calling this again, e.g. once the stream is readable.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [TlsError] if the handshake failed. The session is reset then, so the next call
+    /// starts a new handshake.
+    pub fn connect_step(&mut self) -> Result<bool, TlsError> {
+        if !self.handshake_done() {
+            self.internal_handshake_step()?;
+        }
+        Ok(self.handshake_done())
+    }
+
+    /// Runs the next step of the handshake, recording the statistics once it completed
+    fn internal_handshake_step(&mut self) -> Result<i32, TlsError> {
         unsafe {
-            mbedtls_ssl_set_bio(
+            self.set_bio();
+
+            if self.handshake_start.is_none() {
+                self.handshake_start = self.clock.map(|clock| clock());
+            }
+            let res = handshake_step(
                 self.ssl_context,
-                core::ptr::addr_of!(self) as *mut c_void,
-                Some(Self::send),
-                Some(Self::receive),
-                None,
+                &mut self.handshake_resumed,
+                self.handshake_progress,
             );
+            if res < 0 && res != MBEDTLS_ERR_SSL_WANT_READ && res != MBEDTLS_ERR_SSL_WANT_WRITE {
+                // real error
+                // Reference: https://os.mbed.com/teams/sandbox/code/mbedtls/docs/tip/ssl_8h.html#a4a37e497cd08c896870a42b1b618186e
+                let error = match self.io_error.take() {
+                    _ if core::mem::take(&mut self.handshake_too_large) => {
+                        TlsError::HandshakeTooLarge
+                    }
+                    Some(kind) => TlsError::Io(kind),
+                    None => handshake_error(self.ssl_context, res),
+                };
+                mbedtls_ssl_session_reset(self.ssl_context);
+                self.handshake_start = None;
+                self.handshake_resumed = false;
+                // A new handshake starts with the ClientHello again
+                self.bytes_sent = 0;
+                self.bytes_received = 0;
+                return Err(error);
+            }
 
-            loop {
-                let res = mbedtls_ssl_handshake(self.ssl_context);
-                if res == 0 {
-                    // success
-                    break;
-                }
-                if res < 0 && res != MBEDTLS_ERR_SSL_WANT_READ && res != MBEDTLS_ERR_SSL_WANT_WRITE
-                {
-                    // real error
-                    // Reference: https://os.mbed.com/teams/sandbox/code/mbedtls/docs/tip/ssl_8h.html#a4a37e497cd08c896870a42b1b618186e
-                    mbedtls_ssl_session_reset(self.ssl_context);
-                    return Err(match res {
-                        MBEDTLS_ERR_SSL_NO_CLIENT_CERTIFICATE => TlsError::NoClientCertificate,
-                        _ => TlsError::MbedTlsError(res),
-                    });
-                }
-
-                // try again immediately
+            if handshake_over(self.ssl_context) {
+                self.handshake_stats = HandshakeStats {
+                    duration: self
+                        .clock
+                        .zip(self.handshake_start)
+                        .map(|(clock, start)| clock().saturating_sub(start)),
+                    bytes_sent: self.bytes_sent,
+                    bytes_received: self.bytes_received,
+                    resumed: self.handshake_resumed,
+                };
             }
 
-            Ok(ConnectedSession { session: self })
+            Ok(res)
         }
     }
 
+    /// Point the callbacks of mbedtls to this session
+    unsafe fn set_bio(&mut self) {
+        self.bio_send = Some(Self::send);
+        self.bio_recv = Some(Self::receive);
+        mbedtls_ssl_set_bio(
+            self.ssl_context,
+            self as *mut _ as *mut c_void,
+            self.bio_send,
+            self.bio_recv,
+            None,
+        );
+    }
+
     fn internal_write(&mut self, buf: &[u8]) -> i32 {
         unsafe {
-            mbedtls_ssl_set_bio(
-                self.ssl_context,
-                self as *mut _ as *mut c_void,
-                Some(Self::send),
-                Some(Self::receive),
-                None,
-            );
+            self.set_bio();
 
             mbedtls_ssl_write(self.ssl_context, buf.as_ptr(), buf.len())
         }
@@ -551,13 +2761,7 @@ where
 
     fn internal_read(&mut self, buf: &mut [u8]) -> i32 {
         unsafe {
-            mbedtls_ssl_set_bio(
-                self.ssl_context,
-                self as *mut _ as *mut c_void,
-                Some(Self::send),
-                Some(Self::receive),
-                None,
-            );
+            self.set_bio();
 
             mbedtls_ssl_read(self.ssl_context, buf.as_mut_ptr(), buf.len())
         }
@@ -565,6 +2769,10 @@ where
 
     unsafe extern "C" fn send(ctx: *mut c_void, buf: *const c_uchar, len: usize) -> c_int {
         let session = ctx as *mut Session<T>;
+        if (*session).legacy_record_version && (*session).bytes_sent == 0 {
+            legacy_client_hello_version((*session).ssl_context, buf, len);
+        }
+
         let stream = &mut (*session).stream;
         let slice = core::ptr::slice_from_raw_parts(buf as *const u8, len as usize);
         let res = stream.write(&*slice);
@@ -572,30 +2780,81 @@ where
         match res {
             Ok(written) => {
                 if written > 0 {
+                    (*session).bytes_sent += written;
                     written as i32
                 } else {
                     MBEDTLS_ERR_SSL_WANT_WRITE
                 }
             }
-            Err(_) => 0,
+            Err(err) => {
+                (*session).io_error = Some(err.kind());
+                MBEDTLS_ERR_SSL_INTERNAL_ERROR
+            }
         }
     }
 
     unsafe extern "C" fn receive(ctx: *mut c_void, buf: *mut c_uchar, len: usize) -> c_int {
         let session = ctx as *mut Session<T>;
-        let stream = &mut (*session).stream;
-        let mut buffer = core::slice::from_raw_parts_mut(buf as *mut u8, len as usize);
-        let res = stream.read(&mut buffer);
+        let received = if (*session).initial_data_pos < (*session).initial_data_len {
+            let len = usize::min(
+                len,
+                (*session).initial_data_len - (*session).initial_data_pos,
+            );
+            buf.copy_from_nonoverlapping(
+                (*session).initial_data.add((*session).initial_data_pos),
+                len,
+            );
+            (*session).initial_data_pos += len;
+            len
+        } else {
+            let stream = &mut (*session).stream;
+            let mut buffer = core::slice::from_raw_parts_mut(buf as *mut u8, len as usize);
+            let res = stream.read(&mut buffer);
 
-        match res {
-            Ok(len) => {
-                if len == 0 {
-                    MBEDTLS_ERR_SSL_WANT_READ
-                } else {
-                    len as c_int
+            match res {
+                Ok(0) => return MBEDTLS_ERR_SSL_WANT_READ,
+                Ok(len) => len,
+                Err(err) => {
+                    (*session).io_error = Some(err.kind());
+                    return MBEDTLS_ERR_SSL_INTERNAL_ERROR;
                 }
             }
-            Err(_) => 0,
+        };
+
+        (*session).bytes_received += received;
+        if exceeds_handshake_limit(
+            (*session).ssl_context,
+            (*session).max_handshake_size,
+            (*session).bytes_received,
+        ) {
+            (*session).handshake_too_large = true;
+            return MBEDTLS_ERR_SSL_INTERNAL_ERROR;
+        }
+        received as c_int
+    }
+
+    /// Frees the bytes set with [Session::with_initial_data]
+    unsafe fn free_initial_data(&mut self) {
+        if !self.initial_data.is_null() {
+            free(self.initial_data as *const _);
+            self.initial_data = core::ptr::null_mut();
+        }
+        self.initial_data_len = 0;
+        self.initial_data_pos = 0;
+    }
+
+    /// Calls the hook set with [Session::with_yield]
+    fn yield_now(&self) {
+        if let Some(hook) = self.yield_hook {
+            hook();
+        }
+    }
+
+    /// Converts an error returned by mbedtls, preferring the error of the stream that caused it
+    fn error(&mut self, res: i32) -> TlsError {
+        match self.io_error.take() {
+            Some(kind) => TlsError::Io(kind),
+            None => TlsError::MbedTlsError(res),
         }
     }
 }
@@ -609,23 +2868,64 @@ impl<T> Drop for Session<T> {
             if self.owns_rsa {
                 RSA_REF = core::mem::transmute(None::<RSA>);
             }
-            mbedtls_ssl_close_notify(self.ssl_context);
-            mbedtls_ctr_drbg_free(self.drbg_context);
-            mbedtls_ssl_config_free(self.ssl_config);
+            // The session may have moved since the callbacks were set, so they are pointed to its
+            // current location. A session that never used its stream has nothing to close.
+            if self.bio_send.is_some() {
+                mbedtls_ssl_set_bio(
+                    self.ssl_context,
+                    self as *mut _ as *mut c_void,
+                    self.bio_send,
+                    self.bio_recv,
+                    None,
+                );
+                mbedtls_ssl_close_notify(self.ssl_context);
+            }
+            // The *_free functions wipe the key material, session secrets and record buffers.
+            // The SSL context still refers to its configuration while being freed, so it has to be
+            // freed first.
             mbedtls_ssl_free(self.ssl_context);
+            release_debug_level(self.ssl_config);
+            mbedtls_ssl_config_free(self.ssl_config);
+            mbedtls_ctr_drbg_free(self.drbg_context);
             mbedtls_x509_crt_free(self.crt);
             mbedtls_x509_crt_free(self.client_crt);
             mbedtls_pk_free(self.private_key);
+            mbedtls_x509_crl_free(self.crl);
             free(self.drbg_context as *const _);
             free(self.ssl_config as *const _);
             free(self.ssl_context as *const _);
             free(self.crt as *const _);
             free(self.client_crt as *const _);
             free(self.private_key as *const _);
+            free(self.crl as *const _);
+            if !self.groups.is_null() {
+                free(self.groups as *const _);
+            }
+            if !self.sig_algs.is_null() {
+                free(self.sig_algs as *const _);
+            }
+            free_key_export(self.key_export);
+            if !self.trust_anchors.is_null() {
+                free(self.trust_anchors as *const _);
+            }
+            // Freed after the key, whose RSA-alt context points to it
+            if !self.key_provider.is_null() {
+                free(self.key_provider as *const _);
+            }
+            if !self.rng.is_null() {
+                free(self.rng as *const _);
+            }
+            self.free_initial_data();
         }
     }
 }
 
+/// A [Session] that completed the handshake, used to exchange data.
+///
+/// Besides the session itself, `&mut ConnectedSession` implements [Read], [Write] and [BufRead]
+/// too (through the blanket implementations of `embedded-io`). This allows passing a temporary
+/// borrow, e.g. `&mut *cell.borrow_mut()` for a session in a `RefCell`, to code that takes its
+/// reader or writer by value.
 pub struct ConnectedSession<T>
 where
     T: Read + Write,
@@ -633,6 +2933,264 @@ where
     session: Session<T>,
 }
 
+impl<T> ConnectedSession<T>
+where
+    T: Read + Write,
+{
+    /// Returns the number of bytes that are already decrypted and can be read without reading
+    /// from the underlying stream.
+    pub fn bytes_available(&self) -> usize {
+        unsafe { mbedtls_ssl_get_bytes_avail(self.session.ssl_context) }
+    }
+
+    /// Returns the duration, traffic and type of the handshake that established this session.
+    pub fn handshake_stats(&self) -> HandshakeStats {
+        self.session.handshake_stats
+    }
+
+    /// Returns the traffic of this session since it was created.
+    pub fn stats(&self) -> IoStats {
+        IoStats {
+            bytes_received: self.session.bytes_received,
+            bytes_sent: self.session.bytes_sent,
+            ..self.session.io_stats
+        }
+    }
+
+    /// Returns whether a previous session was resumed, instead of performing a full handshake.
+    ///
+    /// A TLS 1.2 session was resumed if the server echoed the session ID offered by the client, a
+    /// TLS 1.3 session if a PSK key exchange mode was negotiated.
+    ///
+    /// A client only resumes a session that was given to [Session::with_serialized_session],
+    /// otherwise every new session performs a full handshake with fresh key material.
+    pub fn is_resumed(&self) -> bool {
+        self.session.handshake_stats.resumed
+    }
+
+    /// Export keying material from the connection (RFC 5705), e.g. to bind a token of the
+    /// application protocol to this connection.
+    ///
+    /// Both peers derive the same `out` from the master secret, the `label` and the optional
+    /// `context`. Note that RFC 5705 tells an absent context apart from an empty one.
+    ///
+    /// Only TLS 1.2 is supported, and only if the session was created with
+    /// `with_keying_material_export()`. mbedtls 3.4 doesn't expose the exporter secret of TLS 1.3.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [TlsError::MbedTlsError] with [error_codes::BAD_INPUT_DATA] if keying material
+    /// can't be exported from this connection, if `label` contains a null byte or if `context` is
+    /// longer than 65535 bytes.
+    pub fn export_keying_material(
+        &self,
+        label: &str,
+        context: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> Result<(), TlsError> {
+        unsafe {
+            export_keying_material(
+                self.session.ssl_context,
+                self.session.key_export,
+                label,
+                context,
+                out,
+            )
+        }
+    }
+
+    /// Returns the session ID, e.g. to correlate connections in the logs of a server.
+    ///
+    /// With TLS 1.2, a resumed session keeps the ID of the session it resumes, unless it was
+    /// resumed with a ticket. A server that issues tickets may send an empty ID, and a client
+    /// resuming with a ticket sends a random one. With TLS 1.3, session IDs are only kept for
+    /// compatibility: this is the legacy_session_id the client picked for the ClientHello, which
+    /// isn't reused when a session is resumed.
+    ///
+    /// Returns an empty slice if there is no ID.
+    pub fn session_id(&self) -> &[u8] {
+        unsafe { session_id(self.session.ssl_context) }
+    }
+
+    /// Serializes the session into `buf` to resume it later with
+    /// [Session::with_serialized_session], returning the length of the data. (client-side only)
+    ///
+    /// The data can be kept across reboots, e.g. in flash, as long as the server accepts to
+    /// resume the session. With TLS 1.3, a session can only be resumed with a ticket the server
+    /// sends after the handshake, so call this after data was read from the server.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [TlsError::MbedTlsError] if the session couldn't be exported, in particular
+    /// [error_codes::BUFFER_TOO_SMALL] if `buf` is too small.
+    pub fn serialize_session(&self, buf: &mut [u8]) -> Result<usize, TlsError> {
+        unsafe { save_session(self.session.ssl_context, buf) }
+    }
+
+    /// Returns whether the peer closed the connection cleanly, by sending a close_notify alert.
+    ///
+    /// Once a read reported the end of the connection, this tells a graceful shutdown apart from
+    /// a stream that ended abruptly, which could also be a truncation attack.
+    pub fn peer_closed_cleanly(&self) -> bool {
+        self.session.peer_closed
+    }
+
+    /// Read and discard the application data until the stream has no more, returning the number
+    /// of discarded bytes.
+    ///
+    /// Use this before closing the connection, by dropping the session or with
+    /// [ConnectedSession::into_session]. Closing a TCP socket while received data wasn't read
+    /// makes many TCP stacks answer with a reset instead of a regular close, which can race with
+    /// the close_notify alert sent on close and makes some peers report an error.
+    ///
+    /// The data that mbedtls holds is read first, then the stream is read until a read returns
+    /// no data, its read times out or it ends. With a non-blocking stream, this discards what was
+    /// received so far. A blocking stream without a read timeout is read until the peer closes
+    /// the connection. If the peer's close_notify alert is among the data, draining stops there
+    /// and [ConnectedSession::peer_closed_cleanly] returns true.
+    pub fn drain(&mut self) -> Result<usize, TlsError> {
+        let mut discarded = 0;
+        let mut buf = [0u8; 64];
+        loop {
+            let new_record = self.bytes_available() == 0;
+            match self.session.internal_read(&mut buf) {
+                MBEDTLS_ERR_SSL_WANT_READ => break, // no data left in the stream
+                MBEDTLS_ERR_SSL_RECEIVED_NEW_SESSION_TICKET => continue, // no data
+                MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY => {
+                    self.session.peer_closed = true;
+                    break;
+                } // eof
+                0 => break,                         // eof
+                res @ 1..=i32::MAX => {
+                    self.session.io_stats.add_read(res as usize, new_record);
+                    discarded += res as usize;
+                } // data
+                res => match self.session.error(res) {
+                    TlsError::Io(embedded_io::ErrorKind::TimedOut) => break, // no data in time
+                    err => return Err(err),
+                }, // error
+            }
+        }
+        Ok(discarded)
+    }
+
+    /// Returns the algorithm the peer's certificate was signed with by its issuer.
+    ///
+    /// This helps to diagnose peers that reject a chain, e.g. because it is signed with SHA-1.
+    /// The signature algorithm negotiated for the handshake messages itself isn't exposed by
+    /// mbedtls.
+    ///
+    /// Returns [None] if the peer didn't send a certificate (e.g. a client without client
+    /// authentication) or if the algorithm isn't known.
+    pub fn peer_sig_algorithm(&self) -> Option<SignatureAlgorithm> {
+        unsafe { peer_sig_algorithm(self.session.ssl_context) }
+    }
+
+    /// Returns the TLS extensions in use on this connection, to debug interoperability issues.
+    /// See [ExtensionFlags].
+    pub fn negotiated_extensions(&self) -> ExtensionFlags {
+        unsafe { ExtensionFlags::from_mbed_tls(self.session.ssl_context) }
+    }
+
+    /// Returns the compression of the records, which is always [Compression::None].
+    ///
+    /// This allows asserting that compression is off, e.g. for an audit. See [Compression].
+    pub fn compression(&self) -> Compression {
+        Compression::None
+    }
+
+    /// Returns the key exchange of the negotiated ciphersuite, e.g. to reject connections that
+    /// aren't [forward secret](KeyExchange::is_forward_secret).
+    ///
+    /// Returns [None] if the key exchange isn't known.
+    pub fn key_exchange(&self) -> Option<KeyExchange> {
+        unsafe { key_exchange(self.session.ssl_context) }
+    }
+
+    /// Returns the result of the verification of the peer's certificate.
+    ///
+    /// The flags are empty if the certificate was verified successfully. With
+    /// [VerifyMode::Optional], they tell why the verification failed. Returns [None] if no
+    /// verification result is available.
+    pub fn verify_result(&self) -> Option<VerifyFlags> {
+        match unsafe { mbedtls_ssl_get_verify_result(self.session.ssl_context) } {
+            u32::MAX => None,
+            flags => Some(VerifyFlags::from_bits(flags)),
+        }
+    }
+
+    /// Returns the largest amount of data a single `write` can send in one record.
+    ///
+    /// This takes the negotiated maximum fragment length and the overhead of the ciphersuite
+    /// into account. Larger writes are split into several records.
+    ///
+    /// The record_size_limit extension (RFC 8449) isn't supported, as it is still experimental in
+    /// mbedtls and disabled in the precompiled libraries, so a TLS 1.3 peer can't be asked to send
+    /// smaller records.
+    pub fn max_out_record_payload(&self) -> Result<usize, TlsError> {
+        let res = unsafe { mbedtls_ssl_get_max_out_record_payload(self.session.ssl_context) };
+        if res < 0 {
+            Err(TlsError::MbedTlsError(res))
+        } else {
+            Ok(res as usize)
+        }
+    }
+
+    /// Renegotiate the session, performing a new handshake over the established connection.
+    ///
+    /// Renegotiation must have been enabled with [Session::with_renegotiation] on both sides,
+    /// and is only supported by TLS 1.2.
+    pub fn renegotiate(&mut self) -> Result<(), TlsError> {
+        unsafe {
+            self.session.set_bio();
+
+            loop {
+                match mbedtls_ssl_renegotiate(self.session.ssl_context) {
+                    0 => return Ok(()),
+                    MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_WANT_WRITE => {
+                        self.session.yield_now()
+                    }
+                    res => return Err(self.session.error(res)),
+                }
+            }
+        }
+    }
+
+    /// Close the connection and prepare the session for a new connection over `stream`.
+    ///
+    /// This notifies the peer that the connection is closed and resets the session with
+    /// `mbedtls_ssl_session_reset`. The configuration, certificates and keys are kept, so they
+    /// don't have to be parsed again for every connection, e.g. in the accept loop of a server.
+    /// The previous stream is dropped. Use [Session::connect] to perform the handshake over the
+    /// new stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [TlsError] if the session couldn't be reset, in which case it is freed.
+    pub fn into_session(mut self, stream: T) -> Result<Session<T>, TlsError> {
+        let session = &mut self.session;
+        unsafe {
+            session.set_bio();
+            // Failing to notify the peer doesn't prevent reusing the session
+            mbedtls_ssl_close_notify(session.ssl_context);
+            error_checked!(mbedtls_ssl_session_reset(session.ssl_context))?;
+        }
+
+        session.stream = stream;
+        unsafe { session.free_initial_data() };
+        session.bytes_sent = 0;
+        session.bytes_received = 0;
+        session.handshake_stats = HandshakeStats::default();
+        session.handshake_start = None;
+        session.handshake_resumed = false;
+        session.handshake_too_large = false;
+        session.io_stats = IoStats::default();
+        session.peer_closed = false;
+        session.io_error = None;
+        Ok(self.session)
+    }
+}
+
 impl<T> ErrorType for ConnectedSession<T>
 where
     T: Read + Write,
@@ -644,15 +3202,71 @@ impl<T> Read for ConnectedSession<T>
 where
     T: Read + Write,
 {
+    /// Read decrypted data from the session.
+    ///
+    /// When the peer closed the connection with a close_notify alert, this returns
+    /// [TlsError::MbedTlsError] with [error_codes::PEER_CLOSE_NOTIFY], like
+    /// [fill_buf](BufRead::fill_buf), and [ConnectedSession::peer_closed_cleanly] returns true.
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         loop {
+            let new_record = self.bytes_available() == 0;
             let res = self.session.internal_read(buf);
             match res {
-                MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_RECEIVED_NEW_SESSION_TICKET => continue, // no data
-                0_i32..=i32::MAX => return Ok(res as usize), // data
-                i32::MIN..=-1_i32 => return Err(TlsError::MbedTlsError(res)), // error
+                MBEDTLS_ERR_SSL_WANT_READ => self.session.yield_now(), // no data
+                MBEDTLS_ERR_SSL_RECEIVED_NEW_SESSION_TICKET => continue, // no data
+                MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY => {
+                    self.session.peer_closed = true;
+                    return Err(self.session.error(res));
+                } // eof
+                0_i32..=i32::MAX => {
+                    self.session
+                        .io_stats
+                        .add_read(res as usize, new_record && res > 0);
+                    return Ok(res as usize);
+                } // data
+                i32::MIN..=-1_i32 => return Err(self.session.error(res)), // error
+            }
+        }
+    }
+}
+
+impl<T> BufRead for ConnectedSession<T>
+where
+    T: Read + Write,
+{
+    /// Returns the decrypted data of the current TLS record without copying it.
+    ///
+    /// The returned slice never spans more than one record. Once it has been
+    /// [consumed](BufRead::consume), the next call processes the following record.
+    ///
+    /// When the peer closed the connection with a close_notify alert, this returns
+    /// [TlsError::MbedTlsError] with [error_codes::PEER_CLOSE_NOTIFY], like [read](Read::read),
+    /// and [ConnectedSession::peer_closed_cleanly] returns true.
+    fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        let new_record = self.bytes_available() == 0;
+        while unsafe { mbedtls_ssl_get_bytes_avail(self.session.ssl_context) } == 0 {
+            // A zero-length read makes mbedtls process the next record without copying it
+            let res = self.session.internal_read(&mut []);
+            match res {
+                MBEDTLS_ERR_SSL_WANT_READ => self.session.yield_now(), // no data
+                MBEDTLS_ERR_SSL_RECEIVED_NEW_SESSION_TICKET => continue, // no data
+                MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY => {
+                    self.session.peer_closed = true;
+                    return Err(self.session.error(res));
+                } // eof
+                0_i32..=i32::MAX => continue,                          // check for data
+                i32::MIN..=-1_i32 => return Err(self.session.error(res)), // error
             }
         }
+
+        self.session.io_stats.add_read(0, new_record);
+        Ok(unsafe { pending_plaintext(self.session.ssl_context) })
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = usize::min(amt, self.bytes_available());
+        self.session.io_stats.add_read(amt, false);
+        unsafe { consume_plaintext(self.session.ssl_context, amt) }
     }
 }
 
@@ -660,19 +3274,41 @@ impl<T> Write for ConnectedSession<T>
 where
     T: Read + Write,
 {
+    /// Encrypt and send data.
+    ///
+    /// At most one record is sent, so less than `buf.len()` bytes may be written. Use
+    /// [write_all](Write::write_all) to send the whole buffer.
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let res = self.session.internal_write(buf);
-        Ok(res as usize)
+        loop {
+            let res = self.session.internal_write(buf);
+            match res {
+                // mbedtls may already hold part of the record, so it has to be called again with
+                // the same data
+                MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_WANT_WRITE => self.session.yield_now(),
+                0_i32..=i32::MAX => {
+                    self.session.io_stats.add_written(res as usize);
+                    if self.session.immediate_flush {
+                        self.flush()?;
+                    }
+                    return Ok(res as usize);
+                } // written
+                i32::MIN..=-1_i32 => return Err(self.session.error(res)), // error
+            }
+        }
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        self.session.stream.flush().map_err(|_| TlsError::Unknown)
+        self.session
+            .stream
+            .flush()
+            .map_err(|err| TlsError::Io(err.kind()))
     }
 }
 
 #[cfg(feature = "async")]
 pub mod asynch {
     use super::*;
+    use embedded_nal_async::{AddrType, Dns, SocketAddr, TcpConnect};
 
     pub struct Session<T, const BUFFER_SIZE: usize = 4096> {
         stream: T,
@@ -682,10 +3318,36 @@ pub mod asynch {
         crt: *mut mbedtls_x509_crt,
         client_crt: *mut mbedtls_x509_crt,
         private_key: *mut mbedtls_pk_context,
+        crl: *mut mbedtls_x509_crl,
         eof: bool,
         tx_buffer: BufferedBytes<BUFFER_SIZE>,
         rx_buffer: BufferedBytes<BUFFER_SIZE>,
         owns_rsa: bool,
+        clock: Option<fn() -> u64>,
+        bytes_sent: usize,
+        bytes_received: usize,
+        handshake_stats: HandshakeStats,
+        // Start of the handshake and whether it resumed a session, kept across handshake steps
+        handshake_start: Option<u64>,
+        handshake_resumed: bool,
+        // The limit set with `with_max_handshake_size`, and whether the peer exceeded it
+        max_handshake_size: Option<usize>,
+        handshake_too_large: bool,
+        // Whether every write flushes the stream, see `with_immediate_flush`
+        immediate_flush: bool,
+        // Whether the first ClientHello is sent with the TLS 1.0 record version
+        legacy_record_version: bool,
+        io_stats: IoStats,
+        peer_closed: bool,
+        timer: Timer,
+        read_timeout: Option<embassy_time::Duration>,
+        handshake_progress: Option<fn(HandshakeState)>,
+        key_export: *mut KeyExport,
+        groups: *mut u16,
+        sig_algs: *mut u16,
+        trust_anchors: *mut &'static [TrustAnchor<'static>],
+        key_provider: *mut &'static dyn KeyProvider,
+        rng: *mut &'static mut dyn RngCore,
     }
 
     impl<T, const BUFFER_SIZE: usize> Session<T, BUFFER_SIZE> {
@@ -695,6 +3357,16 @@ pub mod asynch {
         ///
         /// * `stream` - The stream for the connection.
         /// * `servername` - The hostname to check against the received server certificate. It sets the ServerName TLS extension, too, if that extension is enabled. (client-side only)
+        ///   When a [Certificates::ca_chain] is set, the handshake fails with [TlsError::HostnameMismatch]
+        ///   unless the certificate's Subject Alternative Names (or Common Name if there are none)
+        ///   match this name. An IPv4 or IPv6 address also matches the IP address entries of the
+        ///   Subject Alternative Names.
+        ///   A server ignores it: there is no SNI callback, so the server always presents
+        ///   [Certificates::certificate], whether the client sent a ServerName or not.
+        ///   The name is copied, so it only has to live for this call. It must be at most 255 bytes
+        ///   long and must not contain a null byte, otherwise [TlsError::Config] is returned.
+        ///   A client can only use an empty name if it doesn't verify the server, i.e. without a
+        ///   [Certificates::ca_chain] or trust anchors, see [Session::new_insecure_client].
         /// * `mode` - Use [Mode::Client] if you are running a client. [Mode::Server] if you are
         /// running a server.
         /// * `min_version` - The minimum TLS version for the connection, that will be accepted.
@@ -706,6 +3378,8 @@ pub mod asynch {
         /// This will return a [TlsError] if there were an error during the initialization of the
         /// session. This can happen if there is not enough memory of if the certificates are in an
         /// invalid format.
+        ///
+        /// The error tells which step failed, see [crate::Session::new].
         pub fn new(
             stream: T,
             servername: &str,
@@ -713,35 +3387,604 @@ pub mod asynch {
             min_version: TlsVersion,
             certificates: Certificates,
         ) -> Result<Self, TlsError> {
-            let (drbg_context, ssl_context, ssl_config, crt, client_crt, private_key) =
-                certificates.init_ssl(servername, mode, min_version)?;
-            return Ok(Self {
-                stream,
-                drbg_context,
-                ssl_context,
-                ssl_config,
-                crt,
-                client_crt,
-                private_key,
-                eof: false,
-                tx_buffer: Default::default(),
-                rx_buffer: Default::default(),
-                owns_rsa: false,
-            });
+            let (drbg_context, ssl_context, ssl_config, crt, client_crt, private_key, crl) =
+                certificates.init_ssl(servername, mode, min_version)?;
+            return Ok(Self {
+                stream,
+                drbg_context,
+                ssl_context,
+                ssl_config,
+                crt,
+                client_crt,
+                private_key,
+                crl,
+                eof: false,
+                tx_buffer: Default::default(),
+                rx_buffer: Default::default(),
+                owns_rsa: false,
+                clock: None,
+                bytes_sent: 0,
+                bytes_received: 0,
+                handshake_stats: HandshakeStats::default(),
+                handshake_start: None,
+                handshake_resumed: false,
+                max_handshake_size: None,
+                handshake_too_large: false,
+                immediate_flush: false,
+                legacy_record_version: false,
+                io_stats: IoStats::default(),
+                peer_closed: false,
+                timer: Timer::default(),
+                read_timeout: None,
+                handshake_progress: None,
+                key_export: core::ptr::null_mut(),
+                groups: core::ptr::null_mut(),
+                sig_algs: core::ptr::null_mut(),
+                trust_anchors: core::ptr::null_mut(),
+                key_provider: core::ptr::null_mut(),
+                rng: core::ptr::null_mut(),
+            });
+        }
+
+        /// Create a client session that doesn't verify the server, for testing purposes.
+        ///
+        /// **This is insecure**: any server, including an attacker in the middle, is accepted. Only
+        /// use this against local test servers.
+        ///
+        /// This is the same as calling [Session::new] in [Mode::Client] with the default
+        /// [Certificates]: the server's certificate isn't verified and no client certificate is
+        /// sent. The handshake fails if the server requires client authentication.
+        ///
+        /// # Arguments
+        ///
+        /// * `stream` - The stream for the connection.
+        /// * `servername` - The name sent in the ServerName TLS extension. Use an empty string to
+        ///   not send the extension.
+        /// * `min_version` - The minimum TLS version for the connection, that will be accepted.
+        pub fn new_insecure_client(
+            stream: T,
+            servername: &str,
+            min_version: TlsVersion,
+        ) -> Result<Self, TlsError> {
+            Self::new(
+                stream,
+                servername,
+                Mode::Client,
+                min_version,
+                Certificates::default(),
+            )
+        }
+
+        /// Replace the stream of the session before connecting.
+        ///
+        /// This allows creating and configuring the session before the connection exists, with a
+        /// placeholder stream such as `()`, and attaching the stream once it is connected. See
+        /// also [Session::connect_with]. The previous stream is dropped.
+        ///
+        /// # Examples
+        /// ```
+        /// let session = Session::<_, 4096>::new((), "example.com", Mode::Client, TlsVersion::Tls1_3, certificates)?
+        ///     .with_curves(&[NamedCurve::X25519])?;
+        /// socket.connect(address).await?;
+        /// let connected = session.connect_with(&mut socket).await?;
+        /// ```
+        pub fn with_stream<U>(self, stream: U) -> Session<U, BUFFER_SIZE> {
+            // Session implements Drop, so the fields are moved out of a session that isn't dropped
+            let this = core::mem::ManuallyDrop::new(self);
+            unsafe {
+                drop(core::ptr::read(&this.stream));
+                Session {
+                    stream,
+                    drbg_context: this.drbg_context,
+                    ssl_context: this.ssl_context,
+                    ssl_config: this.ssl_config,
+                    crt: this.crt,
+                    client_crt: this.client_crt,
+                    private_key: this.private_key,
+                    crl: this.crl,
+                    eof: this.eof,
+                    tx_buffer: core::ptr::read(&this.tx_buffer),
+                    rx_buffer: core::ptr::read(&this.rx_buffer),
+                    owns_rsa: this.owns_rsa,
+                    clock: this.clock,
+                    bytes_sent: this.bytes_sent,
+                    bytes_received: this.bytes_received,
+                    handshake_stats: this.handshake_stats,
+                    handshake_start: this.handshake_start,
+                    handshake_resumed: this.handshake_resumed,
+                    max_handshake_size: this.max_handshake_size,
+                    handshake_too_large: this.handshake_too_large,
+                    immediate_flush: this.immediate_flush,
+                    legacy_record_version: this.legacy_record_version,
+                    io_stats: this.io_stats,
+                    peer_closed: this.peer_closed,
+                    timer: core::ptr::read(&this.timer),
+                    read_timeout: this.read_timeout,
+                    handshake_progress: this.handshake_progress,
+                    key_export: this.key_export,
+                    groups: this.groups,
+                    sig_algs: this.sig_algs,
+                    trust_anchors: this.trust_anchors,
+                    key_provider: this.key_provider,
+                    rng: this.rng,
+                }
+            }
+        }
+
+        /// Perform the handshake over `stream`, which replaces the stream the session was created
+        /// with, see [Session::with_stream] and [Session::connect].
+        pub async fn connect_with<U>(
+            self,
+            stream: U,
+        ) -> Result<AsyncConnectedSession<U, BUFFER_SIZE>, TlsError>
+        where
+            U: embedded_io_async::Read + embedded_io_async::Write,
+        {
+            self.with_stream(stream).connect().await
+        }
+
+        /// Enable the use of the hardware accelerated RSA peripheral for the [Session].
+        ///
+        /// Note: Due to implementation constraints, this session and every other session will use the
+        /// hardware accelerated RSA driver until the sesssion called with this function is dropped.
+        ///
+        /// Only RSA operations are accelerated. Elliptic curve (ECDSA/ECDHE) operations always run in
+        /// software, as none of the supported chips has an ECC peripheral.
+        ///
+        /// To use the RSA peripheral with several sessions at the same time, use [HardwareRsa]
+        /// instead.
+        ///
+        /// # Arguments
+        ///
+        /// * `rsa` - The RSA peripheral from the HAL
+        pub fn with_hardware_rsa(mut self, rsa: impl Peripheral<P = RSA>) -> Self {
+            unsafe { RSA_REF = core::mem::transmute(Some(Rsa::new(rsa, None))) }
+            self.owns_rsa = true;
+            self
+        }
+
+        /// Set after how many requests the random number generator of the [Session] is reseeded.
+        ///
+        /// The session uses a CTR_DRBG seeded from the hardware RNG, which by default is reseeded
+        /// every `MBEDTLS_CTR_DRBG_RESEED_INTERVAL` (10000) requests.
+        ///
+        /// # Arguments
+        ///
+        /// * `interval` - The number of requests between two reseeds
+        pub fn with_drbg_reseed_interval(self, interval: u32) -> Self {
+            unsafe { mbedtls_ctr_drbg_set_reseed_interval(self.drbg_context, interval as c_int) };
+            self
+        }
+
+        /// Enable or disable prediction resistance of the random number generator of the [Session].
+        ///
+        /// With prediction resistance, the CTR_DRBG is reseeded from the hardware RNG before every
+        /// request, which is more expensive. It is disabled by default.
+        ///
+        /// # Arguments
+        ///
+        /// * `enabled` - Whether prediction resistance should be used
+        pub fn with_prediction_resistance(self, enabled: bool) -> Self {
+            let resistance = if enabled {
+                MBEDTLS_CTR_DRBG_PR_ON
+            } else {
+                MBEDTLS_CTR_DRBG_PR_OFF
+            };
+            unsafe {
+                mbedtls_ctr_drbg_set_prediction_resistance(self.drbg_context, resistance as c_int)
+            };
+            self
+        }
+
+        /// Allow renegotiating the session once it is connected. Disabled by default.
+        ///
+        /// Renegotiation is only supported by TLS 1.2. It can be started by either side with
+        /// `renegotiate()` on the connected session, or by the peer while reading.
+        ///
+        /// # Security
+        ///
+        /// Keep renegotiation disabled unless you really need it: it increases the attack surface
+        /// and lets the peer trigger expensive handshakes at will. Only secure renegotiation
+        /// (RFC 5746) is allowed, peers that don't support it can't renegotiate.
+        ///
+        /// # Arguments
+        ///
+        /// * `enabled` - Whether renegotiation is allowed
+        pub fn with_renegotiation(self, enabled: bool) -> Self {
+            let renegotiation = if enabled {
+                MBEDTLS_SSL_RENEGOTIATION_ENABLED
+            } else {
+                MBEDTLS_SSL_RENEGOTIATION_DISABLED
+            };
+            unsafe { mbedtls_ssl_conf_renegotiation(self.ssl_config, renegotiation as c_int) };
+            self
+        }
+
+        /// Returns the hardware accelerators that mbedtls currently uses.
+        ///
+        /// This tells whether [Session::with_hardware_rsa] is effective: it has no effect on the
+        /// ESP32, and the accelerator is shared by all sessions, see [HardwareRsa]. SHA and AES
+        /// always run in software.
+        pub fn active_accelerators(&self) -> Accelerators {
+            Accelerators::active()
+        }
+
+        /// Set the minimum size of the Diffie-Hellman prime offered by a server for a DHE
+        /// ciphersuite. Defaults to 2048 bits.
+        ///
+        /// The handshake fails if the server offers a smaller group. This only applies to clients,
+        /// and has no effect on ECDHE ciphersuites.
+        ///
+        /// # Arguments
+        ///
+        /// * `bits` - The minimum number of bits of the prime
+        pub fn with_min_dhm_bitlen(self, bits: u32) -> Self {
+            unsafe { mbedtls_ssl_conf_dhm_min_bitlen(self.ssl_config, bits as c_uint) };
+            self
+        }
+
+        /// Set how the certificate of the peer is verified.
+        ///
+        /// By default, the certificate is verified ([VerifyMode::Required]) when a
+        /// [Certificates::ca_chain] is given, and not verified at all ([VerifyMode::None])
+        /// otherwise. With [VerifyMode::Optional], the handshake completes even if the verification
+        /// fails, so the reasons can be inspected with `verify_result()` on the connected session
+        /// before deciding whether to trust the peer.
+        ///
+        /// # Arguments
+        ///
+        /// * `mode` - How the certificate is verified
+        pub fn with_verify_mode(self, mode: VerifyMode) -> Self {
+            unsafe { mbedtls_ssl_conf_authmode(self.ssl_config, mode.to_mbed_tls()) };
+            self
+        }
+
+        /// Set the level of the mbedtls debug messages that are logged for this session, from 0
+        /// (none) to 4 (verbose).
+        ///
+        /// This takes precedence over the global level set with [set_debug], in both directions:
+        /// a session can be debugged while the others stay quiet, or be silenced while the others
+        /// log. Debug messages aren't available on Xtensa chips.
+        ///
+        /// # Arguments
+        ///
+        /// * `level` - The highest level of the messages that are logged
+        pub fn with_debug_level(self, level: u32) -> Self {
+            unsafe { conf_debug_level(self.ssl_config, level) };
+            self
+        }
+
+        /// Set a clock used to measure the duration of the handshake.
+        ///
+        /// The duration is reported in [HandshakeStats::duration], in the unit of the values
+        /// returned by `clock` (e.g. milliseconds since boot).
+        ///
+        /// # Arguments
+        ///
+        /// * `clock` - Returns the current time. It must never go backwards.
+        pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+            self.clock = Some(clock);
+            self
+        }
+
+        /// Set a callback that is called whenever the handshake advances to a new state.
+        ///
+        /// This can be used to report the progress of the handshake, or to find out where a stuck
+        /// handshake stalls.
+        ///
+        /// # Arguments
+        ///
+        /// * `progress` - Called with the new state during [Session::connect]
+        pub fn with_handshake_progress(mut self, progress: fn(HandshakeState)) -> Self {
+            self.handshake_progress = Some(progress);
+            self
+        }
+
+        /// Keep the secrets needed to export keying material from the connection, see
+        /// `export_keying_material()` on the connected session.
+        ///
+        /// The master secret of TLS 1.2 connections is kept in the session for this, in addition to
+        /// the keys mbedtls derived from it. It is erased when the session is dropped.
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::OutOfMemory] if the secrets couldn't be allocated.
+        pub fn with_keying_material_export(mut self) -> Result<Self, TlsError> {
+            unsafe { conf_key_export(self.ssl_context, &mut self.key_export)? };
+            Ok(self)
+        }
+
+        /// Limit the number of bytes the peer may send during the handshake.
+        ///
+        /// mbedtls copies the certificate chain of the peer to the heap, so a peer sending a huge
+        /// chain can exhaust the RAM. With a limit, the handshake fails with
+        /// [TlsError::HandshakeTooLarge] as soon as the bytes read from the stream exceed it,
+        /// before mbedtls processes them. The limit includes the record headers and every other
+        /// handshake message of the peer, so allow a few KB more than the largest expected
+        /// certificate chain. Only the initial handshake is limited, not a renegotiation.
+        ///
+        /// Without a limit, a single handshake message is only bounded by the input buffer of
+        /// mbedtls, 16 KB for TLS 1.2 and TLS 1.3.
+        ///
+        /// # Arguments
+        ///
+        /// * `size` - Maximum number of bytes received until the handshake completed
+        pub fn with_max_handshake_size(mut self, size: usize) -> Self {
+            self.max_handshake_size = Some(size);
+            self
+        }
+
+        /// Flush the stream after every write, for latency-sensitive request/response protocols.
+        ///
+        /// Each write already passes the record to the stream. With this, the stream is flushed as
+        /// well, so that buffering in the stream doesn't delay small records. Without it, call
+        /// `flush()` after the last write of a message.
+        ///
+        /// Nagle's algorithm of the TCP socket can still delay small segments. It is a setting of
+        /// the socket, so disable it there if the network stack allows it.
+        ///
+        /// # Arguments
+        ///
+        /// * `enabled` - Whether every write flushes the stream
+        pub fn with_immediate_flush(mut self, enabled: bool) -> Self {
+            self.immediate_flush = enabled;
+            self
+        }
+
+        /// Send the ClientHello in a record with the TLS 1.0 version, for old middleboxes (e.g.
+        /// firewalls) that drop connections otherwise. (client-side only)
+        ///
+        /// This is a compatibility workaround, only use it if a middlebox requires it. The version
+        /// of the record layer is independent of the negotiated version, which this doesn't change:
+        /// the ClientHello still offers the versions given to [Session::new], and every other
+        /// record has the usual version. Both TLS 1.2 and TLS 1.3 allow TLS 1.0 as the version of
+        /// the record of the first ClientHello.
+        ///
+        /// # Arguments
+        ///
+        /// * `enabled` - Whether the first ClientHello is sent with the TLS 1.0 record version
+        pub fn with_legacy_record_version(mut self, enabled: bool) -> Self {
+            self.legacy_record_version = enabled;
+            self
+        }
+
+        /// Let returning clients resume their session from `cache`, instead of performing a full
+        /// handshake. (server-side only)
+        ///
+        /// See [SessionCache].
+        pub fn with_session_cache<const N: usize, const ENTRY_SIZE: usize>(
+            self,
+            cache: &'static SessionCache<N, ENTRY_SIZE>,
+        ) -> Self {
+            unsafe { cache.configure(self.ssl_config) };
+            self
+        }
+
+        /// Restrict the named curves offered and accepted for the ECDHE key exchange.
+        ///
+        /// The curves are given in order of preference. By default, mbedtls allows every curve it
+        /// was built with, preferring X25519, then secp256r1 and secp384r1. If the peer supports
+        /// none of the given curves, the handshake fails with a [TlsError::MbedTlsError] (usually
+        /// `MBEDTLS_ERR_SSL_HANDSHAKE_FAILURE`) instead of falling back to another curve.
+        ///
+        /// # Arguments
+        ///
+        /// * `curves` - The allowed curves, most preferred first
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::OutOfMemory] if the list couldn't be allocated.
+        pub fn with_curves(mut self, curves: &[NamedCurve]) -> Result<Self, TlsError> {
+            unsafe {
+                let groups =
+                    replace_list(&mut self.groups, curves.iter().map(NamedCurve::to_mbed_tls))?;
+                mbedtls_ssl_conf_groups(self.ssl_config, groups);
+            }
+            Ok(self)
+        }
+
+        /// Restrict the signature algorithms offered and accepted for the handshake.
+        ///
+        /// The algorithms are given in order of preference. They apply to the signatures of the
+        /// handshake messages (CertificateVerify and ServerKeyExchange). By default, mbedtls offers
+        /// ECDSA with secp256r1/SHA-256, secp384r1/SHA-384 and secp521r1/SHA-512, then RSA-PSS and
+        /// RSA PKCS#1 v1.5, each with SHA-256, SHA-384 and SHA-512. TLS 1.3 never uses RSA PKCS#1
+        /// v1.5 for handshake messages.
+        ///
+        /// If the peer supports none of the given algorithms, the handshake fails with a
+        /// [TlsError::MbedTlsError].
+        ///
+        /// # Arguments
+        ///
+        /// * `sig_algs` - The allowed signature algorithms, most preferred first
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::OutOfMemory] if the list couldn't be allocated.
+        pub fn with_sig_algs(mut self, sig_algs: &[SigAlg]) -> Result<Self, TlsError> {
+            unsafe {
+                let sig_algs =
+                    replace_list(&mut self.sig_algs, sig_algs.iter().map(SigAlg::to_mbed_tls))?;
+                mbedtls_ssl_conf_sig_algs(self.ssl_config, sig_algs);
+            }
+            Ok(self)
+        }
+
+        /// Set bytes that were already read from the stream, to be processed before reading from
+        /// the stream.
+        ///
+        /// This allows peeking at the start of a connection, e.g. to tell TLS and plaintext apart
+        /// on the same port, and to hand the connection over to TLS afterwards. The bytes are
+        /// copied to the receive buffer.
+        ///
+        /// # Arguments
+        ///
+        /// * `data` - The bytes read from the stream so far
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::OutOfMemory] if the bytes don't fit into the receive buffer of
+        /// `BUFFER_SIZE` bytes.
+        pub fn with_initial_data(mut self, data: &[u8]) -> Result<Self, TlsError> {
+            self.rx_buffer
+                .push(data)
+                .map_err(|_| TlsError::OutOfMemory)?;
+            Ok(self)
+        }
+
+        /// Set the Diffie-Hellman parameters of a server, to offer DHE ciphersuites.
+        ///
+        /// Without parameters, a server only offers ECDHE (and static RSA) ciphersuites. The
+        /// parameters are copied into the configuration, so `params` doesn't have to outlive the
+        /// session.
+        ///
+        /// # Arguments
+        ///
+        /// * `params` - The DH parameters (`BEGIN DH PARAMETERS`), as generated by e.g.
+        ///   `openssl dhparam 2048`. Use [X509::pem] or [X509::der].
+        ///
+        /// # Errors
+        ///
+        /// Returns a [TlsError::MbedTlsError] if the parameters couldn't be parsed.
+        pub fn with_dhm_params(self, params: X509) -> Result<Self, TlsError> {
+            unsafe { conf_dhm_params(self.ssl_config, &params)? };
+            Ok(self)
+        }
+
+        /// Trust the peers whose chain was issued by one of `anchors`, in addition to the
+        /// [Certificates::ca_chain].
+        ///
+        /// This needs much less flash than storing the whole root certificates, see [TrustAnchor].
+        /// The certificate of the peer is required to be verified afterwards, as with
+        /// [VerifyMode::Required], so the anchors also work without a [Certificates::ca_chain].
+        ///
+        /// # Arguments
+        ///
+        /// * `anchors` - The trusted roots, usually constants in flash
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::OutOfMemory] if the anchors couldn't be registered, or
+        /// [TlsError::Config] for a client created with an empty servername, whose certificate
+        /// couldn't be matched to a host.
+        pub fn with_trust_anchors(
+            mut self,
+            anchors: &'static [TrustAnchor<'static>],
+        ) -> Result<Self, TlsError> {
+            unsafe {
+                conf_trust_anchors(
+                    self.ssl_context,
+                    self.ssl_config,
+                    &mut self.trust_anchors,
+                    anchors,
+                )?;
+            }
+            Ok(self)
+        }
+
+        /// Use `certificate` as own certificate, with its private key held by `provider`.
+        ///
+        /// This replaces [Certificates::certificate] and [Certificates::private_key] for keys
+        /// that can't be extracted, e.g. from a secure element: the handshake calls `provider` for
+        /// the signature (and for a server, the decryption) instead. See [KeyProvider] for the
+        /// supported keys, which limit the session to TLS 1.2.
+        ///
+        /// # Arguments
+        ///
+        /// * `certificate` - The certificate chain of the key, as for [Certificates::certificate]
+        /// * `provider` - The operations of the private key
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::IncompleteKeyPair] if the [Certificates] already had a certificate,
+        /// [TlsError::CertificateParse] if `certificate` couldn't be parsed, or
+        /// [TlsError::OutOfMemory] if the provider couldn't be registered.
+        pub fn with_key_provider(
+            mut self,
+            certificate: X509,
+            provider: &'static dyn KeyProvider,
+        ) -> Result<Self, TlsError> {
+            unsafe {
+                conf_key_provider(
+                    self.ssl_config,
+                    self.client_crt,
+                    self.private_key,
+                    &mut self.key_provider,
+                    &certificate,
+                    provider,
+                )?;
+            }
+            Ok(self)
+        }
+
+        /// Use `rng` instead of the default random number generator for this session.
+        ///
+        /// By default, the random numbers of a session come from a CTR_DRBG seeded from the
+        /// hardware RNG. With this, the handshake (e.g. the random values and the ephemeral keys)
+        /// draws directly from `rng` instead, which has to be cryptographically secure. Other
+        /// sessions aren't affected.
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::OutOfMemory] if the generator couldn't be registered.
+        pub fn with_rng(mut self, rng: &'static mut dyn RngCore) -> Result<Self, TlsError> {
+            unsafe { conf_rng(self.ssl_config, &mut self.rng, rng)? };
+            Ok(self)
+        }
+
+        /// Resume the session serialized with `serialize_session` of a previous connection to the
+        /// same server, e.g. after a reboot. (client-side only)
+        ///
+        /// If the server accepts it, the handshake is abbreviated, which saves the expensive public
+        /// key operations. Otherwise, a full handshake is performed. Check
+        /// [ConnectedSession::is_resumed] to find out whether the session was resumed.
+        ///
+        /// The data starts with the version of mbedtls and the options it was built with, so
+        /// data saved by a firmware with a different mbedtls is rejected. The data holds the
+        /// secrets of the session, so it has to be stored as securely as a key.
+        ///
+        /// # Errors
+        ///
+        /// Returns a [TlsError::MbedTlsError] if the data is invalid, in particular
+        /// [error_codes::VERSION_MISMATCH] if it was saved by an incompatible mbedtls.
+        pub fn with_serialized_session(self, data: &[u8]) -> Result<Self, TlsError> {
+            unsafe { load_session(self.ssl_context, data)? };
+            Ok(self)
+        }
+
+        /// Accept the TLS versions from `min` to `max`, replacing the minimum version given to
+        /// [Session::new].
+        ///
+        /// By default, all versions from the minimum version up to TLS 1.3 are accepted. Passing
+        /// the same version for `min` and `max` only accepts that version.
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::Config] if `min` is newer than `max`.
+        pub fn with_version_range(
+            self,
+            min: TlsVersion,
+            max: TlsVersion,
+        ) -> Result<Self, TlsError> {
+            unsafe { conf_version_range(self.ssl_config, min, max)? };
+            Ok(self)
         }
 
-        /// Enable the use of the hardware accelerated RSA peripheral for the [Session].
+        /// Returns the mbedtls configuration of the session, to set options that aren't wrapped
+        /// by this crate with the `mbedtls_ssl_conf_*` functions of `esp-mbedtls-sys`.
         ///
-        /// Note: Due to implementation constraints, this session and every other session will use the
-        /// hardware accelerated RSA driver until the sesssion called with this function is dropped.
+        /// Call this before connecting, so the options apply to the handshake. Options that are only
+        /// used by `mbedtls_ssl_setup`, such as the transport, have no effect anymore, as the context
+        /// was already set up.
         ///
-        /// # Arguments
+        /// # Safety
         ///
-        /// * `rsa` - The RSA peripheral from the HAL
-        pub fn with_hardware_rsa(mut self, rsa: impl Peripheral<P = RSA>) -> Self {
-            unsafe { RSA_REF = core::mem::transmute(Some(Rsa::new(rsa, None))) }
-            self.owns_rsa = true;
-            self
+        /// The configuration is freed together with the session, so the pointer must not be used
+        /// afterwards. Anything given to mbedtls by pointer has to outlive the session. Changing
+        /// options this crate relies on (the RNG, the debug and verify callbacks, the own
+        /// certificate, the CA chain, the curves and signature algorithms) can break the safe API.
+        pub unsafe fn ssl_config(&mut self) -> *mut mbedtls_ssl_config {
+            self.ssl_config
         }
     }
 
@@ -754,19 +3997,46 @@ pub mod asynch {
                 if self.owns_rsa {
                     RSA_REF = core::mem::transmute(None::<RSA>);
                 }
-                mbedtls_ssl_close_notify(self.ssl_context);
-                mbedtls_ctr_drbg_free(self.drbg_context);
-                mbedtls_ssl_config_free(self.ssl_config);
+                // The session may have moved since the callbacks were set, and the tx buffer is
+                // dropped anyway, so make sure mbedtls doesn't write to the old location. This also
+                // covers a handshake that was aborted by dropping its future. Sending needs to
+                // await the stream, so no close_notify alert is sent on drop, use
+                // AsyncConnectedSession::into_session to notify the peer.
+                mbedtls_ssl_set_bio(self.ssl_context, core::ptr::null_mut(), None, None, None);
+                // The *_free functions wipe the key material, session secrets and record buffers.
+                // The SSL context still refers to its configuration while being freed, so it has to be
+                // freed first.
                 mbedtls_ssl_free(self.ssl_context);
+                release_debug_level(self.ssl_config);
+                mbedtls_ssl_config_free(self.ssl_config);
+                mbedtls_ctr_drbg_free(self.drbg_context);
                 mbedtls_x509_crt_free(self.crt);
                 mbedtls_x509_crt_free(self.client_crt);
                 mbedtls_pk_free(self.private_key);
+                mbedtls_x509_crl_free(self.crl);
                 free(self.drbg_context as *const _);
                 free(self.ssl_config as *const _);
                 free(self.ssl_context as *const _);
                 free(self.crt as *const _);
                 free(self.client_crt as *const _);
                 free(self.private_key as *const _);
+                free(self.crl as *const _);
+                if !self.groups.is_null() {
+                    free(self.groups as *const _);
+                }
+                if !self.sig_algs.is_null() {
+                    free(self.sig_algs as *const _);
+                }
+                free_key_export(self.key_export);
+                if !self.trust_anchors.is_null() {
+                    free(self.trust_anchors as *const _);
+                }
+                if !self.key_provider.is_null() {
+                    free(self.key_provider as *const _);
+                }
+                if !self.rng.is_null() {
+                    free(self.rng as *const _);
+                }
             }
         }
     }
@@ -775,94 +4045,169 @@ pub mod asynch {
     where
         T: embedded_io_async::Read + embedded_io_async::Write,
     {
+        /// Perform the handshake, returning the connected session.
+        ///
+        /// # Cancellation
+        ///
+        /// The session is owned by the returned future, so dropping the future aborts the
+        /// handshake and frees the session. The stream is dropped with it without being closed,
+        /// so when passing a borrowed socket, close or abort it afterwards. A dropped handshake
+        /// can't be continued, use [Session::handshake] for that.
         pub async fn connect<'b>(
             mut self,
         ) -> Result<AsyncConnectedSession<T, BUFFER_SIZE>, TlsError> {
+            self.handshake().await?;
+            Ok(AsyncConnectedSession { session: self })
+        }
+
+        /// Perform the handshake without consuming the session, then turn it into the connected
+        /// session with [Session::connect], which returns immediately.
+        ///
+        /// # Cancellation
+        ///
+        /// This is cancellation safe: the handshake advances in steps, and the bytes that were
+        /// already exchanged with the stream are kept in the session. When the future is dropped,
+        /// e.g. by `select!`, calling this again continues the handshake where it stopped. This
+        /// requires the stream to be cancellation safe as well, which e.g. the sockets of
+        /// `embassy-net` are.
+        ///
+        /// # Errors
+        ///
+        /// Returns a [TlsError] if the handshake or the stream failed. The session is reset then,
+        /// discarding the records that were buffered, so the next call starts a new handshake,
+        /// which requires a new stream, see [Session::with_stream].
+        pub async fn handshake(&mut self) -> Result<(), TlsError> {
+            let res = self.handshake_steps().await;
+            if res.is_err() {
+                // The error of the handshake is more useful than a failure to reset
+                unsafe { self.reset().ok() };
+            }
+            res
+        }
+
+        async fn handshake_steps(&mut self) -> Result<(), TlsError> {
             unsafe {
-                mbedtls_ssl_set_bio(
-                    self.ssl_context,
-                    core::ptr::addr_of!(self) as *mut c_void,
-                    Some(Self::sync_send),
-                    Some(Self::sync_receive),
-                    None,
-                );
+                self.set_bio();
 
-                loop {
-                    let res = mbedtls_ssl_handshake(self.ssl_context);
-                    log::debug!("mbedtls_ssl_handshake: {res}");
-                    if res == 0 {
-                        // success
-                        break;
-                    }
+                if self.handshake_start.is_none() {
+                    self.handshake_start = self.clock.map(|clock| clock());
+                }
+                while !handshake_over(self.ssl_context) {
+                    let res = handshake_step(
+                        self.ssl_context,
+                        &mut self.handshake_resumed,
+                        self.handshake_progress,
+                    );
+                    log::debug!("mbedtls_ssl_handshake_step: {res}");
                     if res < 0
                         && res != MBEDTLS_ERR_SSL_WANT_READ
                         && res != MBEDTLS_ERR_SSL_WANT_WRITE
                     {
                         // real error
                         // Reference: https://os.mbed.com/teams/sandbox/code/mbedtls/docs/tip/ssl_8h.html#a4a37e497cd08c896870a42b1b618186e
-                        mbedtls_ssl_session_reset(self.ssl_context);
-                        return Err(match res {
-                            MBEDTLS_ERR_SSL_NO_CLIENT_CERTIFICATE => TlsError::NoClientCertificate,
-                            _ => TlsError::MbedTlsError(res),
-                        });
-                    } else {
-                        if !self.tx_buffer.empty() {
-                            log::debug!("Having data to send to stream");
-                            let data = self.tx_buffer.pull(BUFFER_SIZE);
-                            log::debug!(
-                                "pulled {} bytes from tx_buffer ... send to stream",
-                                data.len()
-                            );
-                            self.stream
-                                .write(data)
-                                .await
-                                .map_err(|_| TlsError::Unknown)?;
-                        }
+                        let error = if core::mem::take(&mut self.handshake_too_large) {
+                            TlsError::HandshakeTooLarge
+                        } else {
+                            handshake_error(self.ssl_context, res)
+                        };
+                        return Err(error);
+                    }
 
-                        if res == MBEDTLS_ERR_SSL_WANT_READ {
-                            let mut buf = [0u8; BUFFER_SIZE];
-                            let res = self
-                                .stream
-                                .read(&mut buf[..self.rx_buffer.remaining()])
-                                .await
-                                .map_err(|_| TlsError::Unknown)?;
-                            if res > 0 {
-                                log::debug!("push {} bytes to rx-buffer", res);
-                                self.rx_buffer.push(&buf[..res]).ok();
-                            }
-                        }
+                    if handshake_over(self.ssl_context) {
+                        self.handshake_stats = HandshakeStats {
+                            duration: self
+                                .clock
+                                .zip(self.handshake_start)
+                                .map(|(clock, start)| clock().saturating_sub(start)),
+                            bytes_sent: self.bytes_sent,
+                            bytes_received: self.bytes_received,
+                            resumed: self.handshake_resumed,
+                        };
+                    }
+
+                    self.drain_tx_buffer().await?;
+
+                    if res == MBEDTLS_ERR_SSL_WANT_READ {
+                        self.fill_rx_buffer().await?;
                     }
                 }
-                self.drain_tx_buffer().await?;
+                self.drain_tx_buffer().await
+            }
+        }
+
+        /// Point the callbacks of mbedtls to this session
+        unsafe fn set_bio(&mut self) {
+            mbedtls_ssl_set_bio(
+                self.ssl_context,
+                self as *mut _ as *mut c_void,
+                Some(Self::sync_send),
+                Some(Self::sync_receive),
+                None,
+            );
+            mbedtls_ssl_set_timer_cb(
+                self.ssl_context,
+                &mut self.timer as *mut _ as *mut c_void,
+                Some(Timer::set),
+                Some(Timer::get),
+            );
+        }
 
-                Ok(AsyncConnectedSession { session: self })
+        /// Reset the session for a new connection, dropping the state of the previous one
+        unsafe fn reset(&mut self) -> Result<(), TlsError> {
+            error_checked!(mbedtls_ssl_session_reset(self.ssl_context))?;
+            self.eof = false;
+            self.tx_buffer = BufferedBytes::default();
+            self.rx_buffer = BufferedBytes::default();
+            self.bytes_sent = 0;
+            self.bytes_received = 0;
+            self.handshake_stats = HandshakeStats::default();
+            self.handshake_start = None;
+            self.handshake_resumed = false;
+            self.handshake_too_large = false;
+            self.io_stats = IoStats::default();
+            self.peer_closed = false;
+            Ok(())
+        }
+
+        /// Read from the stream into the rx buffer, returning the number of bytes read
+        async fn fill_rx_buffer(&mut self) -> Result<usize, TlsError> {
+            let mut buf = [0u8; BUFFER_SIZE];
+            let res = self
+                .stream
+                .read(&mut buf[..self.rx_buffer.remaining()])
+                .await
+                .map_err(|err| TlsError::Io(err.kind()))?;
+            if res > 0 {
+                log::debug!("push {} bytes to rx-buffer", res);
+                self.rx_buffer.push(&buf[..res]).ok();
             }
+            Ok(res)
         }
 
         async fn drain_tx_buffer(&mut self) -> Result<(), TlsError> {
             unsafe {
-                mbedtls_ssl_set_bio(
-                    self.ssl_context,
-                    self as *mut _ as *mut c_void,
-                    Some(Self::sync_send),
-                    Some(Self::sync_receive),
-                    None,
-                );
+                self.set_bio();
                 if !self.tx_buffer.empty() {
                     log::debug!("Drain tx buffer");
-                    let data = self.tx_buffer.pull(BUFFER_SIZE);
-                    log::debug!(
-                        "pulled {} bytes from tx_buffer ... send to stream",
-                        data.len()
-                    );
-                    log::debug!("{:02x?}", &data);
-                    let res = self
-                        .stream
-                        .write(data)
+                    while self.tx_buffer.can_read() {
+                        let data = self.tx_buffer.peek();
+                        log::debug!("{:02x?}", &data);
+                        let res = self
+                            .stream
+                            .write(data)
+                            .await
+                            .map_err(|err| TlsError::Io(err.kind()))?;
+                        log::debug!("wrote {res} bytes to stream");
+                        if res == 0 {
+                            return Err(TlsError::Io(embedded_io::ErrorKind::WriteZero));
+                        }
+                        // Only dropped once written, so the bytes are kept if this is cancelled
+                        self.tx_buffer.advance(res);
+                    }
+                    self.stream
+                        .flush()
                         .await
-                        .map_err(|_| TlsError::Unknown)?;
-                    log::debug!("wrote {res} bytes to stream");
-                    self.stream.flush().await.map_err(|_| TlsError::Unknown)?;
+                        .map_err(|err| TlsError::Io(err.kind()))?;
                 }
             }
 
@@ -871,13 +4216,7 @@ pub mod asynch {
 
         async fn async_internal_write(&mut self, buf: &[u8]) -> Result<i32, TlsError> {
             unsafe {
-                mbedtls_ssl_set_bio(
-                    self.ssl_context,
-                    self as *mut _ as *mut c_void,
-                    Some(Self::sync_send),
-                    Some(Self::sync_receive),
-                    None,
-                );
+                self.set_bio();
                 self.drain_tx_buffer().await?;
 
                 let len = mbedtls_ssl_write(self.ssl_context, buf.as_ptr(), buf.len());
@@ -889,13 +4228,7 @@ pub mod asynch {
 
         async fn async_internal_read(&mut self, buf: &mut [u8]) -> Result<i32, TlsError> {
             unsafe {
-                mbedtls_ssl_set_bio(
-                    self.ssl_context,
-                    self as *mut _ as *mut c_void,
-                    Some(Self::sync_send),
-                    Some(Self::sync_receive),
-                    None,
-                );
+                self.set_bio();
                 self.drain_tx_buffer().await?;
 
                 if !self.rx_buffer.can_read() && mbedtls_ssl_check_pending(self.ssl_context) == 0 {
@@ -904,7 +4237,7 @@ pub mod asynch {
                         .stream
                         .read(&mut buffer[..self.rx_buffer.remaining()])
                         .await
-                        .map_err(|_| TlsError::Unknown)?;
+                        .map_err(|err| TlsError::Io(err.kind()))?;
                     if from_socket > 0 {
                         log::debug!("<<< got {} bytes from socket", from_socket);
                         self.rx_buffer.push(&buffer[..from_socket]).ok();
@@ -914,13 +4247,16 @@ pub mod asynch {
                     }
                 }
 
-                if !self.rx_buffer.empty() || mbedtls_ssl_get_bytes_avail(self.ssl_context) > 0 {
+                // mbedtls can hold the rest of a record, or more records, that it already took from
+                // the receive buffer
+                if !self.rx_buffer.empty() || mbedtls_ssl_check_pending(self.ssl_context) != 0 {
                     log::debug!("<<< read data from mbedtls");
                     let res = mbedtls_ssl_read(self.ssl_context, buf.as_mut_ptr(), buf.len());
                     log::debug!("<<< mbedtls returned {res}");
 
                     if res == MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY {
                         self.eof = true;
+                        self.peer_closed = true;
                         return Ok(0);
                     }
                     Ok(res)
@@ -933,6 +4269,10 @@ pub mod asynch {
         unsafe extern "C" fn sync_send(ctx: *mut c_void, buf: *const c_uchar, len: usize) -> c_int {
             log::debug!("*** sync send called, bytes={len}");
             let session = ctx as *mut Session<T, BUFFER_SIZE>;
+            if (*session).legacy_record_version && (*session).bytes_sent == 0 {
+                legacy_client_hello_version((*session).ssl_context, buf, len);
+            }
+
             let slice = core::ptr::slice_from_raw_parts(
                 buf as *const u8,
                 usize::min(len as usize, (*session).tx_buffer.remaining()),
@@ -940,8 +4280,10 @@ pub mod asynch {
             (*session).tx_buffer.push(&*slice).ok();
             let written = (&*slice).len();
             log::debug!("*** put {} bytes into tx_buffer", written);
+            (*session).bytes_sent += written;
 
             if written == 0 {
+                // The tx buffer is full, the caller has to drain it by awaiting the stream
                 MBEDTLS_ERR_SSL_WANT_WRITE
             } else {
                 written as c_int
@@ -961,26 +4303,558 @@ pub mod asynch {
                 return MBEDTLS_ERR_SSL_WANT_READ;
             }
 
-            let buffer = core::slice::from_raw_parts_mut(buf as *mut u8, len as usize);
-            let max_len = usize::min(len as usize, (*session).rx_buffer.remaining());
-            let data = (*session).rx_buffer.pull(max_len);
-            buffer[0..data.len()].copy_from_slice(data);
+            let buffer = core::slice::from_raw_parts_mut(buf as *mut u8, len as usize);
+            let mut pulled = 0;
+            // Twice at most, if the buffered bytes wrap around
+            while pulled < buffer.len() && (*session).rx_buffer.can_read() {
+                let data = (*session).rx_buffer.pull(buffer.len() - pulled);
+                buffer[pulled..][..data.len()].copy_from_slice(data);
+                pulled += data.len();
+            }
+
+            log::debug!("*** pulled {} bytes from rx-buffer", pulled);
+            (*session).bytes_received += pulled;
+            if exceeds_handshake_limit(
+                (*session).ssl_context,
+                (*session).max_handshake_size,
+                (*session).bytes_received,
+            ) {
+                (*session).handshake_too_large = true;
+                return MBEDTLS_ERR_SSL_INTERNAL_ERROR;
+            }
+
+            if pulled == 0 {
+                MBEDTLS_ERR_SSL_WANT_READ
+            } else {
+                pulled as c_int
+            }
+        }
+    }
+
+    /// Connect to a TLS server: open a TCP connection to `remote` and perform the handshake.
+    ///
+    /// This is a shortcut for connecting a socket with `tcp` and creating a client [Session] on
+    /// it. `tcp` can be any [TcpConnect] implementation, such as `embassy_net::tcp::client::TcpClient`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tcp` - The TCP stack used to connect to the server
+    /// * `remote` - The address of the server, e.g. as resolved by DNS
+    /// * `servername` - The hostname to check against the received server certificate. See
+    ///   [Session::new].
+    /// * `min_version` - The minimum TLS version for the connection, that will be accepted.
+    /// * `certificates` - Certificate chain for the connection. See [Certificates].
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::TcpConnectFailed] with the kind of the TCP error if the server couldn't
+    /// be reached, or any error of [Session::new] and [Session::connect].
+    pub async fn connect_tls<'a, C, const BUFFER_SIZE: usize>(
+        tcp: &'a C,
+        remote: SocketAddr,
+        servername: &str,
+        min_version: TlsVersion,
+        certificates: Certificates<'_>,
+    ) -> Result<AsyncConnectedSession<C::Connection<'a>, BUFFER_SIZE>, TlsError>
+    where
+        C: TcpConnect,
+    {
+        let socket = tcp
+            .connect(remote)
+            .await
+            .map_err(|err| TlsError::TcpConnectFailed(err.kind()))?;
+        Session::new(socket, servername, Mode::Client, min_version, certificates)?
+            .connect()
+            .await
+    }
+
+    /// Connect to a TLS server by its hostname: resolve it, open a TCP connection and perform
+    /// the handshake.
+    ///
+    /// This is a shortcut for resolving `hostname` with `dns` and calling [connect_tls]. The
+    /// hostname is sent in the ServerName extension and checked against the server certificate.
+    ///
+    /// # Arguments
+    ///
+    /// * `dns` - The resolver used to look up the hostname, such as `embassy_net::dns::DnsSocket`
+    /// * `tcp` - The TCP stack used to connect to the server
+    /// * `hostname` - The hostname of the server
+    /// * `port` - The port of the server, usually 443
+    /// * `min_version` - The minimum TLS version for the connection, that will be accepted.
+    /// * `certificates` - Certificate chain for the connection. See [Certificates].
+    ///
+    /// # Errors
+    ///
+    /// Returns [TlsError::DnsFailed] if the hostname couldn't be resolved, or any error of
+    /// [connect_tls].
+    pub async fn connect_tls_to_host<'a, D, C, const BUFFER_SIZE: usize>(
+        dns: &D,
+        tcp: &'a C,
+        hostname: &str,
+        port: u16,
+        min_version: TlsVersion,
+        certificates: Certificates<'_>,
+    ) -> Result<AsyncConnectedSession<C::Connection<'a>, BUFFER_SIZE>, TlsError>
+    where
+        D: Dns,
+        C: TcpConnect,
+    {
+        let address = dns
+            .get_host_by_name(hostname, AddrType::Either)
+            .await
+            .map_err(|_| TlsError::DnsFailed)?;
+        connect_tls(
+            tcp,
+            SocketAddr::new(address, port),
+            hostname,
+            min_version,
+            certificates,
+        )
+        .await
+    }
+
+    /// Accepts TLS connections of clients, performing the server side of the handshake.
+    ///
+    /// This holds the configuration shared by all the connections of a server, so each accepted
+    /// stream only has to be passed to [TlsAcceptor::accept].
+    ///
+    /// # Examples
+    /// ```
+    /// let acceptor = TlsAcceptor::new(TlsVersion::Tls1_2, certificates);
+    /// loop {
+    ///     socket.accept(443).await.unwrap();
+    ///     match acceptor.accept::<_, 4096>(&mut socket).await {
+    ///         Ok(session) => handle(session).await,
+    ///         Err(error) => log::warn!("Handshake failed: {error}"),
+    ///     }
+    /// }
+    /// ```
+    #[derive(Debug, Clone, Copy)]
+    pub struct TlsAcceptor<'a> {
+        min_version: TlsVersion,
+        certificates: Certificates<'a>,
+    }
+
+    impl<'a> TlsAcceptor<'a> {
+        /// Create an acceptor for a server.
+        ///
+        /// # Arguments
+        ///
+        /// * `min_version` - The minimum TLS version for the connections, that will be accepted.
+        /// * `certificates` - The certificate and private key of the server, and the CA chain to
+        ///   verify clients with when using client authentication. See [Certificates].
+        pub fn new(min_version: TlsVersion, certificates: Certificates<'a>) -> Self {
+            Self {
+                min_version,
+                certificates,
+            }
+        }
+
+        /// Perform the server side of the handshake on a connection accepted from a client.
+        ///
+        /// # Arguments
+        ///
+        /// * `stream` - The stream of the accepted connection
+        ///
+        /// # Errors
+        ///
+        /// Returns any error of [Session::new] and [Session::connect]. A client that doesn't
+        /// trust the certificate of the server usually aborts with a
+        /// [TlsError::MbedTlsError] of [error_codes::FATAL_ALERT_MESSAGE].
+        pub async fn accept<T, const BUFFER_SIZE: usize>(
+            &self,
+            stream: T,
+        ) -> Result<AsyncConnectedSession<T, BUFFER_SIZE>, TlsError>
+        where
+            T: embedded_io_async::Read + embedded_io_async::Write,
+        {
+            Session::new(
+                stream,
+                "",
+                Mode::Server,
+                self.min_version,
+                self.certificates,
+            )?
+            .connect()
+            .await
+        }
+    }
+
+    /// A [Session] that completed the handshake, used to exchange data.
+    ///
+    /// Besides the session itself, `&mut AsyncConnectedSession` implements the `embedded-io-async`
+    /// `Read`, `BufRead` and `Write` traits too (through their blanket implementations), so a
+    /// temporary borrow can be passed to code that takes its reader or writer by value.
+    pub struct AsyncConnectedSession<T, const BUFFER_SIZE: usize>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+    {
+        pub(crate) session: Session<T, BUFFER_SIZE>,
+    }
+
+    impl<T, const BUFFER_SIZE: usize> AsyncConnectedSession<T, BUFFER_SIZE>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+    {
+        /// Returns the number of bytes that are already decrypted and can be read without reading
+        /// from the underlying stream.
+        pub fn bytes_available(&self) -> usize {
+            unsafe { mbedtls_ssl_get_bytes_avail(self.session.ssl_context) }
+        }
+
+        /// Returns the duration, traffic and type of the handshake that established this session.
+        pub fn handshake_stats(&self) -> HandshakeStats {
+            self.session.handshake_stats
+        }
+
+        /// Returns the traffic of this session since it was created.
+        pub fn stats(&self) -> IoStats {
+            IoStats {
+                bytes_received: self.session.bytes_received,
+                bytes_sent: self.session.bytes_sent,
+                ..self.session.io_stats
+            }
+        }
+
+        /// Returns whether a previous session was resumed, instead of performing a full handshake.
+        ///
+        /// A TLS 1.2 session was resumed if the server echoed the session ID offered by the client,
+        /// a TLS 1.3 session if a PSK key exchange mode was negotiated.
+        ///
+        /// A client only resumes a session that was given to [Session::with_serialized_session],
+        /// otherwise every new session performs a full handshake with fresh key material.
+        pub fn is_resumed(&self) -> bool {
+            self.session.handshake_stats.resumed
+        }
+
+        /// Export keying material from the connection (RFC 5705), e.g. to bind a token of the
+        /// application protocol to this connection.
+        ///
+        /// Both peers derive the same `out` from the master secret, the `label` and the optional
+        /// `context`. Note that RFC 5705 tells an absent context apart from an empty one.
+        ///
+        /// Only TLS 1.2 is supported, and only if the session was created with
+        /// `with_keying_material_export()`. mbedtls 3.4 doesn't expose the exporter secret of TLS
+        /// 1.3.
+        ///
+        /// # Errors
+        ///
+        /// Returns a [TlsError::MbedTlsError] with [error_codes::BAD_INPUT_DATA] if keying material
+        /// can't be exported from this connection, if `label` contains a null byte or if `context`
+        /// is longer than 65535 bytes.
+        pub fn export_keying_material(
+            &self,
+            label: &str,
+            context: Option<&[u8]>,
+            out: &mut [u8],
+        ) -> Result<(), TlsError> {
+            unsafe {
+                export_keying_material(
+                    self.session.ssl_context,
+                    self.session.key_export,
+                    label,
+                    context,
+                    out,
+                )
+            }
+        }
+
+        /// Returns the session ID, e.g. to correlate connections in the logs of a server.
+        ///
+        /// With TLS 1.2, a resumed session keeps the ID of the session it resumes, unless it was
+        /// resumed with a ticket. A server that issues tickets may send an empty ID, and a client
+        /// resuming with a ticket sends a random one. With TLS 1.3, session IDs are only kept for
+        /// compatibility: this is the legacy_session_id the client picked for the ClientHello,
+        /// which isn't reused when a session is resumed.
+        ///
+        /// Returns an empty slice if there is no ID.
+        pub fn session_id(&self) -> &[u8] {
+            unsafe { session_id(self.session.ssl_context) }
+        }
+
+        /// Serializes the session into `buf` to resume it later with
+        /// [Session::with_serialized_session], returning the length of the data. (client-side only)
+        ///
+        /// The data can be kept across reboots, e.g. in flash, as long as the server accepts to
+        /// resume the session. With TLS 1.3, a session can only be resumed with a ticket the server
+        /// sends after the handshake, so call this after data was read from the server.
+        ///
+        /// # Errors
+        ///
+        /// Returns a [TlsError::MbedTlsError] if the session couldn't be exported, in particular
+        /// [error_codes::BUFFER_TOO_SMALL] if `buf` is too small.
+        pub fn serialize_session(&self, buf: &mut [u8]) -> Result<usize, TlsError> {
+            unsafe { save_session(self.session.ssl_context, buf) }
+        }
+
+        /// Returns whether the peer closed the connection cleanly, by sending a close_notify alert.
+        ///
+        /// Once a read reported the end of the connection, this tells a graceful shutdown apart from
+        /// a stream that ended abruptly, which could also be a truncation attack.
+        pub fn peer_closed_cleanly(&self) -> bool {
+            self.session.peer_closed
+        }
+
+        /// Read and discard the application data until the stream ends, returning the number of
+        /// discarded bytes.
+        ///
+        /// Use this before closing the connection, by dropping the session or with
+        /// [AsyncConnectedSession::into_session]. Closing a TCP socket while received data wasn't
+        /// read makes many TCP stacks answer with a reset instead of a regular close, which can
+        /// race with the close_notify alert sent by [AsyncConnectedSession::into_session] and
+        /// makes some peers report an error. Dropping the session doesn't send the alert.
+        ///
+        /// The data that mbedtls and the receive buffer hold is read first, then the stream is
+        /// read until it ends or a read times out, see [AsyncConnectedSession::set_timeout].
+        /// Without a timeout, this waits until the peer closes the connection, so set one or wrap
+        /// the call in `with_timeout` to only discard what arrives in time. If the peer's
+        /// close_notify alert is among the data, draining stops there and
+        /// [AsyncConnectedSession::peer_closed_cleanly] returns true.
+        pub async fn drain(&mut self) -> Result<usize, TlsError> {
+            let mut discarded = 0;
+            let mut buf = [0u8; 64];
+            loop {
+                match embedded_io_async::Read::read(self, &mut buf).await {
+                    Ok(0) | Err(TlsError::Eof) => break, // eof
+                    Ok(len) => discarded += len,         // data
+                    Err(TlsError::Timeout) => break,     // no data in time
+                    Err(err) => return Err(err),         // error
+                }
+            }
+            Ok(discarded)
+        }
+
+        /// Returns the algorithm the peer's certificate was signed with by its issuer.
+        ///
+        /// This helps to diagnose peers that reject a chain, e.g. because it is signed with SHA-1.
+        /// The signature algorithm negotiated for the handshake messages itself isn't exposed by
+        /// mbedtls.
+        ///
+        /// Returns [None] if the peer didn't send a certificate (e.g. a client without client
+        /// authentication) or if the algorithm isn't known.
+        pub fn peer_sig_algorithm(&self) -> Option<SignatureAlgorithm> {
+            unsafe { peer_sig_algorithm(self.session.ssl_context) }
+        }
+
+        /// Returns the TLS extensions in use on this connection, to debug interoperability issues.
+        /// See [ExtensionFlags].
+        pub fn negotiated_extensions(&self) -> ExtensionFlags {
+            unsafe { ExtensionFlags::from_mbed_tls(self.session.ssl_context) }
+        }
+
+        /// Returns the compression of the records, which is always [Compression::None].
+        ///
+        /// This allows asserting that compression is off, e.g. for an audit. See [Compression].
+        pub fn compression(&self) -> Compression {
+            Compression::None
+        }
+
+        /// Returns the key exchange of the negotiated ciphersuite, e.g. to reject connections that
+        /// aren't [forward secret](KeyExchange::is_forward_secret).
+        ///
+        /// Returns [None] if the key exchange isn't known.
+        pub fn key_exchange(&self) -> Option<KeyExchange> {
+            unsafe { key_exchange(self.session.ssl_context) }
+        }
+
+        /// Returns the result of the verification of the peer's certificate.
+        ///
+        /// The flags are empty if the certificate was verified successfully. With
+        /// [VerifyMode::Optional], they tell why the verification failed. Returns [None] if no
+        /// verification result is available.
+        pub fn verify_result(&self) -> Option<VerifyFlags> {
+            match unsafe { mbedtls_ssl_get_verify_result(self.session.ssl_context) } {
+                u32::MAX => None,
+                flags => Some(VerifyFlags::from_bits(flags)),
+            }
+        }
+
+        /// Returns the largest amount of data a single `write` can send in one record.
+        ///
+        /// This takes the negotiated maximum fragment length and the overhead of the ciphersuite
+        /// into account. Larger writes are split into several records.
+        ///
+        /// The record_size_limit extension (RFC 8449) isn't supported, as it is still experimental in
+        /// mbedtls and disabled in the precompiled libraries, so a TLS 1.3 peer can't be asked to send
+        /// smaller records.
+        pub fn max_out_record_payload(&self) -> Result<usize, TlsError> {
+            let res = unsafe { mbedtls_ssl_get_max_out_record_payload(self.session.ssl_context) };
+            if res < 0 {
+                Err(TlsError::MbedTlsError(res))
+            } else {
+                Ok(res as usize)
+            }
+        }
+
+        /// Renegotiate the session, performing a new handshake over the established connection.
+        ///
+        /// Renegotiation must have been enabled with [Session::with_renegotiation] on both sides,
+        /// and is only supported by TLS 1.2.
+        pub async fn renegotiate(&mut self) -> Result<(), TlsError> {
+            let session = &mut self.session;
+            loop {
+                let res = unsafe {
+                    session.set_bio();
+                    mbedtls_ssl_renegotiate(session.ssl_context)
+                };
+                match res {
+                    0 => break,
+                    MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_WANT_WRITE => {
+                        session.drain_tx_buffer().await?;
+                        if res == MBEDTLS_ERR_SSL_WANT_READ {
+                            session.fill_rx_buffer().await?;
+                        }
+                    }
+                    res => return Err(TlsError::MbedTlsError(res)),
+                }
+            }
+            session.drain_tx_buffer().await
+        }
+
+        /// Close the connection and prepare the session for a new connection over `stream`.
+        ///
+        /// This notifies the peer that the connection is closed and resets the session with
+        /// `mbedtls_ssl_session_reset`. The configuration, certificates and keys are kept, so they
+        /// don't have to be parsed again for every connection, e.g. in the accept loop of a
+        /// server. The previous stream is dropped. Use [Session::connect] to perform the handshake
+        /// over the new stream.
+        ///
+        /// # Errors
+        ///
+        /// Returns a [TlsError] if the session couldn't be reset, in which case it is freed.
+        pub async fn into_session(
+            mut self,
+            stream: T,
+        ) -> Result<Session<T, BUFFER_SIZE>, TlsError> {
+            let session = &mut self.session;
+            unsafe {
+                session.set_bio();
+                mbedtls_ssl_close_notify(session.ssl_context);
+            }
+            // Failing to notify the peer doesn't prevent reusing the session
+            session.drain_tx_buffer().await.ok();
+            unsafe { session.reset()? };
+
+            session.stream = stream;
+            Ok(self.session)
+        }
 
-            log::debug!("*** pulled {} bytes from rx-buffer", data.len());
+        /// Set the timeout of reads and of the mbedtls timers.
+        ///
+        /// With a timeout, [read](embedded_io_async::Read::read) fails with [TlsError::Timeout]
+        /// when no data was received in time, like [AsyncConnectedSession::read_with_timeout].
+        /// The timeout is also passed to mbedtls, whose timers are driven by `embassy-time`. mbedtls
+        /// only uses these timers for DTLS retransmissions, so for TLS only reads are affected.
+        /// Use [None] to wait indefinitely, which is the default.
+        pub fn set_timeout(&mut self, timeout: Option<embassy_time::Duration>) {
+            self.session.read_timeout = timeout;
+            let millis = timeout.map_or(0, |timeout| timeout.as_millis().clamp(1, u32::MAX as u64));
+            unsafe { mbedtls_ssl_conf_read_timeout(self.session.ssl_config, millis as u32) }
+        }
 
-            if data.len() == 0 {
-                MBEDTLS_ERR_SSL_WANT_READ
-            } else {
-                data.len() as c_int
-            }
+        /// Read data from the session, giving up if nothing was read within `timeout`.
+        ///
+        /// This overrides the timeout set with [AsyncConnectedSession::set_timeout].
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::Timeout] if the timeout expired before any data could be read.
+        ///
+        /// # Cancellation
+        ///
+        /// Data is only handed over to mbedtls once it was fully read from the underlying stream,
+        /// so a read that times out while waiting for the peer leaves no partial record state
+        /// behind: a partially received record stays buffered and is completed by the next read.
+        /// This relies on the `read` of the underlying stream being cancel-safe, which is the case
+        /// for `embassy-net` sockets. Outgoing data that mbedtls still has to flush is written
+        /// before waiting for the peer, bytes that weren't written yet stay buffered and are sent
+        /// by the next operation.
+        pub async fn read_with_timeout(
+            &mut self,
+            buf: &mut [u8],
+            timeout: embassy_time::Duration,
+        ) -> Result<usize, TlsError> {
+            embassy_time::with_timeout(timeout, self.read_without_timeout(buf))
+                .await
+                .map_err(|_| TlsError::Timeout)?
         }
-    }
 
-    pub struct AsyncConnectedSession<T, const BUFFER_SIZE: usize>
-    where
-        T: embedded_io_async::Read + embedded_io_async::Write,
-    {
-        pub(crate) session: Session<T, BUFFER_SIZE>,
+        /// Read exactly `buf.len()` bytes, giving up if they weren't all read within `timeout`.
+        ///
+        /// The timeout applies to the whole operation, in addition to the timeout of each read set
+        /// with [AsyncConnectedSession::set_timeout]. When it expires, the bytes that were already
+        /// read are lost, so the connection should be closed.
+        ///
+        /// # Errors
+        ///
+        /// Returns [ReadExactError::UnexpectedEof](embedded_io::ReadExactError::UnexpectedEof) if
+        /// the connection was closed before `buf` was filled, and [TlsError::Timeout] if the
+        /// timeout expired.
+        pub async fn read_exact_with_timeout(
+            &mut self,
+            buf: &mut [u8],
+            timeout: embassy_time::Duration,
+        ) -> Result<(), embedded_io::ReadExactError<TlsError>> {
+            embassy_time::with_timeout(timeout, embedded_io_async::Read::read_exact(self, buf))
+                .await
+                .map_err(|_| embedded_io::ReadExactError::Other(TlsError::Timeout))?
+        }
+
+        /// Encrypt and send data, giving up if it couldn't be handed over to mbedtls within
+        /// `timeout`.
+        ///
+        /// Like [write](embedded_io_async::Write::write), at most one record is sent. The
+        /// returned count is the number of bytes of `buf` that mbedtls accepted: they are part of
+        /// a record that is either sent already or still buffered, in which case it is sent by the
+        /// next operation on the session (e.g. [flush](embedded_io_async::Write::flush)). The
+        /// application can resume right after these bytes, as it can't tell how much of the
+        /// encrypted data is on the wire.
+        ///
+        /// # Errors
+        ///
+        /// Returns [TlsError::Timeout] if no data could be accepted in time, because the stream
+        /// didn't take the previously buffered data. mbedtls may hold part of a record for `buf`
+        /// already, so the next write must be called with the same data, e.g. by retrying this
+        /// function.
+        pub async fn write_with_timeout(
+            &mut self,
+            buf: &[u8],
+            timeout: embassy_time::Duration,
+        ) -> Result<usize, TlsError> {
+            let deadline = embassy_time::Instant::now() + timeout;
+            let remaining = || deadline.saturating_duration_since(embassy_time::Instant::now());
+            let session = &mut self.session;
+            loop {
+                // Make room for the record
+                embassy_time::with_timeout(remaining(), session.drain_tx_buffer())
+                    .await
+                    .map_err(|_| TlsError::Timeout)??;
+
+                let res = unsafe {
+                    session.set_bio();
+                    mbedtls_ssl_write(session.ssl_context, buf.as_ptr(), buf.len())
+                };
+                match res {
+                    MBEDTLS_ERR_SSL_WANT_WRITE => continue,
+                    MBEDTLS_ERR_SSL_WANT_READ => {
+                        embassy_time::with_timeout(remaining(), session.fill_rx_buffer())
+                            .await
+                            .map_err(|_| TlsError::Timeout)??;
+                    }
+                    0..=i32::MAX => {
+                        session.io_stats.add_written(res as usize);
+                        // What isn't sent in time stays buffered for the next operation
+                        if let Ok(drained) =
+                            embassy_time::with_timeout(remaining(), session.drain_tx_buffer()).await
+                        {
+                            drained?;
+                        }
+                        return Ok(res as usize);
+                    }
+                    i32::MIN..=-1_i32 => return Err(TlsError::MbedTlsError(res)),
+                }
+            }
+        }
     }
 
     impl<T, const BUFFER_SIZE: usize> embedded_io_async::ErrorType
@@ -995,117 +4869,852 @@ pub mod asynch {
     where
         T: embedded_io_async::Read + embedded_io_async::Write,
     {
+        /// Read decrypted data from the session.
+        ///
+        /// # Cancellation
+        ///
+        /// This is cancel-safe as long as the `read` and `write` of the underlying stream are,
+        /// which is the case for `embassy-net` sockets: when the future is dropped, no data was
+        /// read and the received and outgoing bytes stay buffered for the next call. It can be
+        /// used in `select!`, see also [AsyncConnectedSession::read_with_timeout].
         async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            match self.session.read_timeout {
+                Some(timeout) => self.read_with_timeout(buf, timeout).await,
+                None => self.read_without_timeout(buf).await,
+            }
+        }
+
+        /// Read exactly `buf.len()` bytes, see also [AsyncConnectedSession::read_exact_with_timeout].
+        ///
+        /// # Errors
+        ///
+        /// Returns [ReadExactError::UnexpectedEof](embedded_io::ReadExactError::UnexpectedEof) if
+        /// the connection was closed before `buf` was filled, whether the peer sent a close_notify
+        /// alert or the stream ended.
+        async fn read_exact(
+            &mut self,
+            mut buf: &mut [u8],
+        ) -> Result<(), embedded_io::ReadExactError<Self::Error>> {
+            while !buf.is_empty() {
+                match self.read(buf).await {
+                    Ok(0) | Err(TlsError::Eof) => {
+                        return Err(embedded_io::ReadExactError::UnexpectedEof)
+                    }
+                    Ok(len) => buf = &mut buf[len..],
+                    Err(err) => return Err(embedded_io::ReadExactError::Other(err)),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<T, const BUFFER_SIZE: usize> AsyncConnectedSession<T, BUFFER_SIZE>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+    {
+        async fn read_without_timeout(&mut self, buf: &mut [u8]) -> Result<usize, TlsError> {
             log::debug!("async read called");
             loop {
                 if self.session.eof && self.session.rx_buffer.empty() {
                     return Err(TlsError::Eof);
                 }
+                let new_record = self.bytes_available() == 0;
                 let res = self.session.async_internal_read(buf).await?;
                 match res {
                     MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_RECEIVED_NEW_SESSION_TICKET => {
                         continue
                     } // no data
-                    0..=i32::MAX => return Ok(res as usize), // data
+                    0..=i32::MAX => {
+                        self.session
+                            .io_stats
+                            .add_read(res as usize, new_record && res > 0);
+                        return Ok(res as usize);
+                    } // data
                     i32::MIN..=-1_i32 => return Err(TlsError::MbedTlsError(res)), // error
                 }
             }
         }
+
+        /// Wait until mbedtls holds decrypted data, returning false at the end of the stream
+        async fn wait_for_record(&mut self) -> Result<bool, TlsError> {
+            while self.bytes_available() == 0 {
+                if self.session.eof && self.session.rx_buffer.empty() {
+                    return Ok(false);
+                }
+                // A zero-length read makes mbedtls process the next record without copying it
+                let res = self.session.async_internal_read(&mut []).await?;
+                if res < 0
+                    && res != MBEDTLS_ERR_SSL_WANT_READ
+                    && res != MBEDTLS_ERR_SSL_RECEIVED_NEW_SESSION_TICKET
+                {
+                    return Err(TlsError::MbedTlsError(res));
+                }
+            }
+            Ok(true)
+        }
+    }
+
+    impl<T, const BUFFER_SIZE: usize> embedded_io_async::BufRead
+        for AsyncConnectedSession<T, BUFFER_SIZE>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+    {
+        /// Returns the decrypted data of the current TLS record without copying it.
+        ///
+        /// The returned slice never spans more than one record. Once it has been
+        /// [consumed](embedded_io_async::BufRead::consume), the next call processes the following
+        /// record, waiting for it only then. An empty slice is returned at the end of the stream.
+        /// The timeout set with [AsyncConnectedSession::set_timeout] applies like for reads.
+        async fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+            let new_record = self.bytes_available() == 0;
+            let available = match self.session.read_timeout {
+                Some(timeout) => embassy_time::with_timeout(timeout, self.wait_for_record())
+                    .await
+                    .map_err(|_| TlsError::Timeout)??,
+                None => self.wait_for_record().await?,
+            };
+            if !available {
+                return Ok(&[]);
+            }
+
+            self.session.io_stats.add_read(0, new_record);
+            Ok(unsafe { pending_plaintext(self.session.ssl_context) })
+        }
+
+        fn consume(&mut self, amt: usize) {
+            let amt = usize::min(amt, self.bytes_available());
+            self.session.io_stats.add_read(amt, false);
+            unsafe { consume_plaintext(self.session.ssl_context, amt) }
+        }
     }
 
     impl<T, const BUFFER_SIZE: usize> embedded_io_async::Write for AsyncConnectedSession<T, BUFFER_SIZE>
     where
         T: embedded_io_async::Read + embedded_io_async::Write,
     {
+        /// Encrypt and send data.
+        ///
+        /// # Cancellation
+        ///
+        /// This is **not** cancel-safe: mbedtls may already have accepted `buf` when the future is
+        /// dropped, in which case the data is sent by the next operation on the session. As it
+        /// can't be told whether that happened, retrying the write could send the data twice.
+        /// Nothing is lost or corrupted, but don't cancel writes if duplicates are a problem.
+        ///
+        /// At most one record is sent, so less than `buf.len()` bytes may be written. Use
+        /// `write_all` to send the whole buffer.
+        ///
+        /// # Backpressure
+        ///
+        /// mbedtls never writes to the stream directly, the encrypted data is buffered and then
+        /// written by awaiting the `write` of the stream. When the stream can't take more data
+        /// (e.g. the TX buffer of a socket is full), this waits for the stream instead of retrying,
+        /// so other tasks keep running.
         async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-            let res = self.session.async_internal_write(buf).await?;
-            Ok(res as usize)
+            loop {
+                let res = self.session.async_internal_write(buf).await?;
+                match res {
+                    // The tx buffer is full. mbedtls may already hold part of the record, so it
+                    // has to be called again with the same data once the buffer is drained.
+                    MBEDTLS_ERR_SSL_WANT_WRITE => continue,
+                    MBEDTLS_ERR_SSL_WANT_READ => {
+                        self.session.fill_rx_buffer().await?;
+                    }
+                    0..=i32::MAX => {
+                        self.session.io_stats.add_written(res as usize);
+                        if self.session.immediate_flush {
+                            self.flush().await?;
+                        }
+                        return Ok(res as usize);
+                    } // written
+                    i32::MIN..=-1_i32 => return Err(TlsError::MbedTlsError(res)), // error
+                }
+            }
         }
 
         async fn flush(&mut self) -> Result<(), Self::Error> {
-            self.session
-                .drain_tx_buffer()
-                .await
-                .map_err(|_| TlsError::Unknown)?;
+            self.session.drain_tx_buffer().await?;
 
             self.session
                 .stream
                 .flush()
                 .await
-                .map_err(|_| TlsError::Unknown)
+                .map_err(|err| TlsError::Io(err.kind()))
+        }
+    }
+    /// Timer for the `mbedtls_ssl_set_timer_cb` interface, based on `embassy-time`
+    #[derive(Default)]
+    struct Timer {
+        intermediate: Option<embassy_time::Instant>,
+        fin: Option<embassy_time::Instant>,
+    }
+
+    impl Timer {
+        unsafe extern "C" fn set(ctx: *mut c_void, int_ms: u32, fin_ms: u32) {
+            let timer = &mut *(ctx as *mut Timer);
+            if fin_ms == 0 {
+                // Cancelled
+                *timer = Timer::default();
+            } else {
+                let now = embassy_time::Instant::now();
+                timer.intermediate = Some(now + embassy_time::Duration::from_millis(int_ms as u64));
+                timer.fin = Some(now + embassy_time::Duration::from_millis(fin_ms as u64));
+            }
+        }
+
+        unsafe extern "C" fn get(ctx: *mut c_void) -> c_int {
+            let timer = &*(ctx as *const Timer);
+            let now = embassy_time::Instant::now();
+            match (timer.intermediate, timer.fin) {
+                (_, None) => -1,
+                (_, Some(fin)) if now >= fin => 2,
+                (Some(intermediate), _) if now >= intermediate => 1,
+                _ => 0,
+            }
         }
     }
+
+    /// Ring buffer for the bytes exchanged with the stream.
+    ///
+    /// Consumed bytes free their space immediately, so data can be pushed while older data is
+    /// still waiting to be pulled, without moving the buffered bytes.
     pub(crate) struct BufferedBytes<const BUFFER_SIZE: usize> {
         buffer: [u8; BUFFER_SIZE],
-        write_idx: usize,
         read_idx: usize,
+        len: usize,
     }
 
     impl<const BUFFER_SIZE: usize> Default for BufferedBytes<BUFFER_SIZE> {
         fn default() -> Self {
             Self {
                 buffer: [0u8; BUFFER_SIZE],
-                write_idx: Default::default(),
                 read_idx: Default::default(),
+                len: Default::default(),
             }
         }
     }
 
     impl<const BUFFER_SIZE: usize> BufferedBytes<BUFFER_SIZE> {
+        /// Removes and returns up to `max` buffered bytes.
+        ///
+        /// Only the bytes up to the end of the ring are returned, so call this again to get the
+        /// bytes that wrapped around.
         pub fn pull<'a>(&'a mut self, max: usize) -> &'a [u8] {
-            if self.read_idx == self.write_idx {
-                self.read_idx = 0;
-                self.write_idx = 0;
-            }
+            let start = self.read_idx;
+            let len = usize::min(max, self.peek().len());
+            self.advance(len);
+            &self.buffer[start..][..len]
+        }
 
-            let len = usize::min(max, self.write_idx - self.read_idx);
-            let res = &self.buffer[self.read_idx..][..len];
-            self.read_idx += len;
-            res
+        /// Returns the buffered bytes without removing them, see [BufferedBytes::advance]
+        ///
+        /// Like [BufferedBytes::pull], this stops at the end of the ring.
+        pub fn peek(&self) -> &[u8] {
+            let len = usize::min(self.len, BUFFER_SIZE - self.read_idx);
+            &self.buffer[self.read_idx..][..len]
         }
 
-        pub fn push(&mut self, data: &[u8]) -> Result<(), ()> {
-            if self.read_idx == self.write_idx {
-                self.read_idx = 0;
-                self.write_idx = 0;
-            }
+        /// Removes the first `amt` buffered bytes
+        pub fn advance(&mut self, amt: usize) {
+            let amt = usize::min(amt, self.len);
+            self.len -= amt;
+            self.read_idx = if self.len == 0 {
+                // Start over, so the next bytes are contiguous
+                0
+            } else {
+                (self.read_idx + amt) % BUFFER_SIZE
+            };
+        }
 
-            if self.buffer.len() - self.write_idx < data.len() {
+        pub fn push(&mut self, data: &[u8]) -> Result<(), ()> {
+            if self.remaining() < data.len() {
                 return Err(());
             }
 
-            self.buffer[self.write_idx..][..data.len()].copy_from_slice(data);
-            self.write_idx += data.len();
+            let write_idx = (self.read_idx + self.len) % BUFFER_SIZE;
+            let (first, second) = data.split_at(usize::min(data.len(), BUFFER_SIZE - write_idx));
+            self.buffer[write_idx..][..first.len()].copy_from_slice(first);
+            self.buffer[..second.len()].copy_from_slice(second);
+            self.len += data.len();
 
             Ok(())
         }
 
+        /// Returns how many bytes can be pushed
         pub fn remaining(&self) -> usize {
-            self.buffer.len() - self.write_idx
+            BUFFER_SIZE - self.len
         }
 
         pub fn can_read(&self) -> bool {
-            self.read_idx < self.write_idx
+            self.len > 0
         }
 
-        pub fn empty(&mut self) -> bool {
-            if self.read_idx == self.write_idx {
-                self.read_idx = 0;
-                self.write_idx = 0;
-            }
+        pub fn empty(&self) -> bool {
+            self.len == 0
+        }
+    }
+}
+
+/// Returns the application data mbedtls has already decrypted, but that wasn't read yet.
+///
+/// This is the remainder of the current record, which is only valid until the next operation on
+/// the context.
+unsafe fn pending_plaintext<'a>(ssl_context: *const mbedtls_ssl_context) -> &'a [u8] {
+    let len = mbedtls_ssl_get_bytes_avail(ssl_context);
+    if len == 0 {
+        &[]
+    } else {
+        core::slice::from_raw_parts((*ssl_context).private_in_offt, len)
+    }
+}
+
+/// Marks `amt` bytes of the data returned by [pending_plaintext] as read.
+///
+/// This mirrors what `mbedtls_ssl_read` does after copying data out of the current record.
+unsafe fn consume_plaintext(ssl_context: *mut mbedtls_ssl_context, amt: usize) {
+    let amt = usize::min(amt, mbedtls_ssl_get_bytes_avail(ssl_context));
+    if amt == 0 {
+        return;
+    }
+
+    let ssl = &mut *ssl_context;
+    mbedtls_platform_zeroize(ssl.private_in_offt, amt as u32);
+    ssl.private_in_msglen -= amt;
+    if ssl.private_in_msglen == 0 {
+        // The record is done, let mbedtls fetch the next one
+        ssl.private_in_offt = core::ptr::null_mut();
+        ssl.private_keep_current_message = 0;
+    } else {
+        ssl.private_in_offt = ssl.private_in_offt.add(amt);
+    }
+}
+
+/// Stores `anchors` in `slot` and passes them to the verify callback
+///
+/// The slice reference is kept in an allocation, so it stays at the same address when the
+/// session is moved.
+unsafe fn conf_trust_anchors(
+    ssl_context: *mut mbedtls_ssl_context,
+    ssl_config: *mut mbedtls_ssl_config,
+    slot: &mut *mut &'static [TrustAnchor<'static>],
+    anchors: &'static [TrustAnchor<'static>],
+) -> Result<(), TlsError> {
+    // A client without a hostname would accept a certificate of any host issued by the anchors
+    if (*ssl_config).private_endpoint == MBEDTLS_SSL_IS_CLIENT as u8
+        && (*ssl_context).private_hostname.is_null()
+    {
+        return Err(TlsError::Config(MBEDTLS_ERR_SSL_BAD_INPUT_DATA));
+    }
+    if slot.is_null() {
+        *slot = calloc(1, size_of::<&[TrustAnchor]>() as u32) as *mut _;
+        if slot.is_null() {
+            return Err(TlsError::OutOfMemory);
+        }
+    }
+    (*slot).write(anchors);
+    mbedtls_ssl_conf_verify(ssl_config, Some(verify), *slot as *mut c_void);
+    mbedtls_ssl_conf_authmode(ssl_config, MBEDTLS_SSL_VERIFY_REQUIRED as i32);
+    Ok(())
+}
+
+/// Sets the minimum and maximum TLS version of the config
+unsafe fn conf_version_range(
+    ssl_config: *mut mbedtls_ssl_config,
+    min: TlsVersion,
+    max: TlsVersion,
+) -> Result<(), TlsError> {
+    if min.to_mbed_tls_minor() > max.to_mbed_tls_minor() {
+        return Err(TlsError::Config(MBEDTLS_ERR_SSL_BAD_INPUT_DATA));
+    }
+    mbedtls_ssl_conf_min_version(
+        ssl_config,
+        MBEDTLS_SSL_MAJOR_VERSION_3 as i32,
+        min.to_mbed_tls_minor(),
+    );
+    mbedtls_ssl_conf_max_version(
+        ssl_config,
+        MBEDTLS_SSL_MAJOR_VERSION_3 as i32,
+        max.to_mbed_tls_minor(),
+    );
+    Ok(())
+}
+
+/// Serializes the session of an established connection into `buf`
+unsafe fn save_session(
+    ssl_context: *const mbedtls_ssl_context,
+    buf: &mut [u8],
+) -> Result<usize, TlsError> {
+    let mut session: mbedtls_ssl_session = core::mem::zeroed();
+    mbedtls_ssl_session_init(&mut session);
+    let mut len = 0;
+    let res = error_checked!(mbedtls_ssl_get_session(ssl_context, &mut session)).and_then(|_| {
+        error_checked!(mbedtls_ssl_session_save(
+            &session,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut len
+        ))
+    });
+    mbedtls_ssl_session_free(&mut session);
+    res.map(|_| len)
+}
+
+/// Sets the serialized session in `data` to be resumed by the next handshake
+unsafe fn load_session(ssl_context: *mut mbedtls_ssl_context, data: &[u8]) -> Result<(), TlsError> {
+    let mut session: mbedtls_ssl_session = core::mem::zeroed();
+    mbedtls_ssl_session_init(&mut session);
+    let res = error_checked!(mbedtls_ssl_session_load(
+        &mut session,
+        data.as_ptr(),
+        data.len()
+    ))
+    .and_then(|_| error_checked!(mbedtls_ssl_set_session(ssl_context, &session)));
+    // mbedtls_ssl_set_session copies the session
+    mbedtls_ssl_session_free(&mut session);
+    res
+}
+
+/// Configures `rng` as random number generator of the config
+unsafe fn conf_rng(
+    ssl_config: *mut mbedtls_ssl_config,
+    slot: &mut *mut &'static mut dyn RngCore,
+    rng: &'static mut dyn RngCore,
+) -> Result<(), TlsError> {
+    if slot.is_null() {
+        *slot = calloc(1, size_of::<&mut dyn RngCore>() as u32) as *mut _;
+        if slot.is_null() {
+            return Err(TlsError::OutOfMemory);
+        }
+    }
+    (*slot).write(rng);
+    mbedtls_ssl_conf_rng(ssl_config, Some(session_rng), *slot as *mut c_void);
+    Ok(())
+}
+
+unsafe extern "C" fn session_rng(ctx: *mut c_void, buffer: *mut c_uchar, len: usize) -> c_int {
+    let rng = &mut *(ctx as *mut &mut dyn RngCore);
+    match rng.try_fill_bytes(core::slice::from_raw_parts_mut(buffer, len)) {
+        Ok(()) => 0,
+        Err(_) => MBEDTLS_ERR_ENTROPY_SOURCE_FAILED,
+    }
+}
+
+/// Parses `certificate` and configures it as own certificate, with `private_key` set up to
+/// call `provider` for its operations
+unsafe fn conf_key_provider(
+    ssl_config: *mut mbedtls_ssl_config,
+    own_crt: *mut mbedtls_x509_crt,
+    private_key: *mut mbedtls_pk_context,
+    slot: &mut *mut &'static dyn KeyProvider,
+    certificate: &X509,
+    provider: &'static dyn KeyProvider,
+) -> Result<(), TlsError> {
+    // mbedtls only appends to the list of own certificates, a second pair would be ignored
+    if (*own_crt).raw.len != 0 || !slot.is_null() {
+        return Err(TlsError::IncompleteKeyPair);
+    }
+
+    parse_own_certificate(own_crt, certificate)?;
+
+    *slot = calloc(1, size_of::<&dyn KeyProvider>() as u32) as *mut _;
+    if slot.is_null() {
+        return Err(TlsError::OutOfMemory);
+    }
+    (*slot).write(provider);
+
+    error_checked!(
+        mbedtls_pk_setup_rsa_alt(
+            private_key,
+            *slot as *mut c_void,
+            Some(key_provider_decrypt),
+            Some(key_provider_sign),
+            Some(key_provider_key_len),
+        ),
+        TlsError::Config
+    )?;
+    error_checked!(
+        mbedtls_ssl_conf_own_cert(ssl_config, own_crt, private_key),
+        TlsError::Config
+    )
+}
+
+/// Parses the own certificate (chain) of a session
+unsafe fn parse_own_certificate(
+    certificate: *mut mbedtls_x509_crt,
+    cert: &X509,
+) -> Result<(), TlsError> {
+    match cert.format {
+        CertificateFormat::PEM => error_checked!(
+            mbedtls_x509_crt_parse(certificate, cert.as_ptr(), cert.len()),
+            TlsError::CertificateParse
+        ),
+        CertificateFormat::DER => error_checked!(
+            mbedtls_x509_crt_parse_der_nocopy(certificate, cert.as_ptr(), cert.len()),
+            TlsError::CertificateParse
+        ),
+    }
+}
+
+/// Maps the error of a [KeyProvider] to an mbedtls error code
+fn key_provider_error(err: TlsError) -> c_int {
+    match err {
+        TlsError::MbedTlsError(code) => code,
+        _ => MBEDTLS_ERR_RSA_PRIVATE_FAILED,
+    }
+}
+
+unsafe extern "C" fn key_provider_key_len(ctx: *mut c_void) -> usize {
+    (*(ctx as *const &dyn KeyProvider)).key_len()
+}
+
+unsafe extern "C" fn key_provider_sign(
+    ctx: *mut c_void,
+    _f_rng: Option<unsafe extern "C" fn(*mut c_void, *mut c_uchar, usize) -> c_int>,
+    _p_rng: *mut c_void,
+    md_alg: mbedtls_md_type_t,
+    hashlen: c_uint,
+    hash: *const c_uchar,
+    sig: *mut c_uchar,
+) -> c_int {
+    let provider = *(ctx as *const &dyn KeyProvider);
+    // mbedtls checked that the signature buffer holds `key_len` bytes
+    let hash = core::slice::from_raw_parts(hash, hashlen as usize);
+    let signature = core::slice::from_raw_parts_mut(sig, provider.key_len());
+    match provider.sign(HashAlgorithm::from_mbed_tls(md_alg), hash, signature) {
+        Ok(()) => 0,
+        Err(err) => key_provider_error(err),
+    }
+}
+
+unsafe extern "C" fn key_provider_decrypt(
+    ctx: *mut c_void,
+    olen: *mut usize,
+    input: *const c_uchar,
+    output: *mut c_uchar,
+    output_max_len: usize,
+) -> c_int {
+    let provider = *(ctx as *const &dyn KeyProvider);
+    // mbedtls checked that the input is `key_len` bytes long
+    let input = core::slice::from_raw_parts(input, provider.key_len());
+    let output = core::slice::from_raw_parts_mut(output, output_max_len);
+    match provider.decrypt(input, output) {
+        Ok(len) => {
+            *olen = len;
+            0
+        }
+        Err(err) => key_provider_error(err),
+    }
+}
+
+/// Parses the DH parameters and copies them into the config
+unsafe fn conf_dhm_params(
+    ssl_config: *mut mbedtls_ssl_config,
+    params: &X509,
+) -> Result<(), TlsError> {
+    let mut dhm: mbedtls_dhm_context = core::mem::zeroed();
+    mbedtls_dhm_init(&mut dhm);
+    let res = error_checked!(mbedtls_dhm_parse_dhm(
+        &mut dhm,
+        params.as_ptr(),
+        params.len()
+    ))
+    .and_then(|_| error_checked!(mbedtls_ssl_conf_dh_param_ctx(ssl_config, &mut dhm)));
+    mbedtls_dhm_free(&mut dhm);
+    res
+}
+
+/// Replaces `list` with a new NUL-terminated list of `values`, returning the new list.
+///
+/// mbedtls doesn't copy the lists of curves and signature algorithms given to the config, so
+/// they are kept in an allocation that the session frees on drop.
+unsafe fn replace_list(
+    list: &mut *mut u16,
+    values: impl ExactSizeIterator<Item = u16>,
+) -> Result<*const u16, TlsError> {
+    let new = calloc(values.len() as u32 + 1, size_of::<u16>() as u32) as *mut u16;
+    if new.is_null() {
+        return Err(TlsError::OutOfMemory);
+    }
+    // The calloc'd terminator is MBEDTLS_SSL_IANA_TLS_GROUP_NONE / MBEDTLS_TLS1_3_SIG_NONE
+    for (i, value) in values.enumerate() {
+        new.add(i).write(value);
+    }
+    if !list.is_null() {
+        free(*list as *const _);
+    }
+    *list = new;
+    Ok(new)
+}
+
+/// Returns the ID of the current session of `ssl_context`
+unsafe fn session_id<'a>(ssl_context: *const mbedtls_ssl_context) -> &'a [u8] {
+    match (*ssl_context).private_session.as_ref() {
+        Some(session) => &session.private_id[..session.private_id_len],
+        None => &[],
+    }
+}
+
+/// Returns the signature algorithm of the peer certificate of `ssl_context`
+unsafe fn peer_sig_algorithm(
+    ssl_context: *const mbedtls_ssl_context,
+) -> Option<SignatureAlgorithm> {
+    let crt = mbedtls_ssl_get_peer_cert(ssl_context);
+    if crt.is_null() {
+        return None;
+    }
+    Some(SignatureAlgorithm {
+        key: SignatureKey::from_mbed_tls((*crt).private_sig_pk)?,
+        hash: HashAlgorithm::from_mbed_tls((*crt).private_sig_md)?,
+    })
+}
+
+/// Returns the key exchange of the ciphersuite negotiated by `ssl_context`
+unsafe fn key_exchange(ssl_context: *const mbedtls_ssl_context) -> Option<KeyExchange> {
+    let info =
+        mbedtls_ssl_ciphersuite_from_id(mbedtls_ssl_get_ciphersuite_id_from_ssl(ssl_context));
+    if info.is_null() {
+        return None;
+    }
+    // TLS 1.3 ciphersuites can't be used with earlier versions, and vice versa
+    if (*info).private_min_tls_version as mbedtls_ssl_protocol_version
+        == mbedtls_ssl_protocol_version_MBEDTLS_SSL_VERSION_TLS1_3
+    {
+        Some(KeyExchange::Tls13Ephemeral)
+    } else {
+        KeyExchange::from_mbed_tls((*info).private_key_exchange as mbedtls_key_exchange_type_t)
+    }
+}
+
+/// Returns whether receiving a total of `received` bytes exceeds `limit`, which only applies
+/// until the initial handshake of `ssl_context` completed
+unsafe fn exceeds_handshake_limit(
+    ssl_context: *const mbedtls_ssl_context,
+    limit: Option<usize>,
+    received: usize,
+) -> bool {
+    match limit {
+        // A renegotiation status of 0 (MBEDTLS_SSL_INITIAL_HANDSHAKE) rules out renegotiations
+        Some(limit) => {
+            received > limit
+                && !handshake_over(ssl_context)
+                && (*ssl_context).private_renego_status == 0
+        }
+        None => false,
+    }
+}
+
+/// Sets the version of the record of the first ClientHello of `ssl_context` to TLS 1.0, if `buf`
+/// is the start of that record.
+///
+/// mbedtls has no option for this, so the header is patched in the output buffer of mbedtls
+/// before it is sent. The record version isn't covered by the handshake transcript.
+///
+/// mbedtls passes its own, mutable output buffer to the send callback, starting at
+/// `out_hdr`. `buf` is only compared with `out_hdr` and never accessed, the header is read and
+/// written through `out_hdr`, which mbedtls doesn't access until the callback returns.
+unsafe fn legacy_client_hello_version(
+    ssl_context: *const mbedtls_ssl_context,
+    buf: *const c_uchar,
+    len: usize,
+) {
+    let ssl = &*ssl_context;
+    let hdr = ssl.private_out_hdr;
+    // The header of a record is the content type, the major and minor version and the length
+    if core::ptr::eq(buf, hdr)
+        && len >= 3
+        && (*ssl.private_conf).private_endpoint == MBEDTLS_SSL_IS_CLIENT as u8
+        && *hdr == MBEDTLS_SSL_MSG_HANDSHAKE as c_uchar
+    {
+        *hdr.add(1) = MBEDTLS_SSL_MAJOR_VERSION_3 as c_uchar;
+        *hdr.add(2) = 1;
+    }
+}
+
+/// The secrets of a TLS 1.2 connection that keying material is exported from
+struct KeyExport {
+    master_secret: [u8; 48],
+    // The client random followed by the server random
+    randoms: [u8; 64],
+    // MBEDTLS_SSL_TLS_PRF_NONE until the keys of a connection were exported
+    prf: mbedtls_tls_prf_types,
+}
+
+/// Registers the callback that stores the secrets of `ssl_context` in the heap slot, allocating
+/// it if needed. The slot is only freed when the session is dropped, so the pointer given to
+/// mbedtls stays valid when the session is moved.
+unsafe fn conf_key_export(
+    ssl_context: *mut mbedtls_ssl_context,
+    slot: &mut *mut KeyExport,
+) -> Result<(), TlsError> {
+    if slot.is_null() {
+        // Zeroed memory is a slot without secrets
+        *slot = calloc(1, size_of::<KeyExport>() as u32) as *mut _;
+        if slot.is_null() {
+            return Err(TlsError::OutOfMemory);
+        }
+    }
+    mbedtls_ssl_set_export_keys_cb(ssl_context, Some(store_key_export), *slot as *mut c_void);
+    Ok(())
+}
+
+unsafe extern "C" fn store_key_export(
+    p_expkey: *mut c_void,
+    type_: mbedtls_ssl_key_export_type,
+    secret: *const c_uchar,
+    secret_len: usize,
+    client_random: *const c_uchar,
+    server_random: *const c_uchar,
+    tls_prf_type: mbedtls_tls_prf_types,
+) {
+    // The secrets of TLS 1.3 are the traffic secrets, which keying material isn't exported from
+    if type_ != mbedtls_ssl_key_export_type_MBEDTLS_SSL_KEY_EXPORT_TLS12_MASTER_SECRET
+        || secret_len != 48
+    {
+        return;
+    }
+    let export = &mut *(p_expkey as *mut KeyExport);
+    export
+        .master_secret
+        .copy_from_slice(core::slice::from_raw_parts(secret, 48));
+    export.randoms[..32].copy_from_slice(core::slice::from_raw_parts(client_random, 32));
+    export.randoms[32..].copy_from_slice(core::slice::from_raw_parts(server_random, 32));
+    export.prf = tls_prf_type;
+}
+
+/// Erases the secrets in the slot and frees it
+unsafe fn free_key_export(slot: *mut KeyExport) {
+    if !slot.is_null() {
+        mbedtls_platform_zeroize(slot as *mut u8, size_of::<KeyExport>() as u32);
+        free(slot as *const _);
+    }
+}
+
+/// Derives keying material of the TLS 1.2 connection of `ssl_context` into `out` with the PRF,
+/// from the secrets stored in `slot` (RFC 5705)
+unsafe fn export_keying_material(
+    ssl_context: *const mbedtls_ssl_context,
+    slot: *const KeyExport,
+    label: &str,
+    context: Option<&[u8]>,
+    out: &mut [u8],
+) -> Result<(), TlsError> {
+    let export = match slot.as_ref() {
+        Some(export)
+            if export.prf != mbedtls_tls_prf_types_MBEDTLS_SSL_TLS_PRF_NONE
+                && (*ssl_context).private_tls_version
+                    == mbedtls_ssl_protocol_version_MBEDTLS_SSL_VERSION_TLS1_2 =>
+        {
+            export
+        }
+        _ => return Err(TlsError::MbedTlsError(MBEDTLS_ERR_SSL_BAD_INPUT_DATA)),
+    };
+    if label.contains('\0') || context.map_or(false, |context| context.len() > u16::MAX as usize) {
+        return Err(TlsError::MbedTlsError(MBEDTLS_ERR_SSL_BAD_INPUT_DATA));
+    }
+
+    // The null-terminated label, followed by the seed: the randoms and the length-prefixed context
+    let context_len = context.map_or(0, |context| 2 + context.len());
+    let len = label.len() + 1 + 64 + context_len;
+    let buf = calloc(1, len as u32) as *mut u8;
+    if buf.is_null() {
+        return Err(TlsError::OutOfMemory);
+    }
+    let bytes = core::slice::from_raw_parts_mut(buf, len);
+    let (label_bytes, seed) = bytes.split_at_mut(label.len() + 1);
+    label_bytes[..label.len()].copy_from_slice(label.as_bytes());
+    seed[..64].copy_from_slice(&export.randoms);
+    if let Some(context) = context {
+        seed[64..66].copy_from_slice(&(context.len() as u16).to_be_bytes());
+        seed[66..].copy_from_slice(context);
+    }
+
+    let res = mbedtls_ssl_tls_prf(
+        export.prf,
+        export.master_secret.as_ptr(),
+        export.master_secret.len(),
+        label_bytes.as_ptr() as *const c_char,
+        seed.as_ptr(),
+        seed.len(),
+        out.as_mut_ptr(),
+        out.len(),
+    );
+    free(buf as *const _);
+    error_checked!(res)
+}
+
+/// Returns whether the handshake of `ssl_context` has completed
+unsafe fn handshake_over(ssl_context: *const mbedtls_ssl_context) -> bool {
+    (*ssl_context).private_state == mbedtls_ssl_states_MBEDTLS_SSL_HANDSHAKE_OVER as c_int
+}
 
-            self.read_idx == self.write_idx
+/// Runs the next step of the handshake.
+///
+/// `resumed` is set once the handshake decided whether a previous session is resumed:
+/// - TLS 1.2: after the ServerHello, if the server echoed the session ID offered by the client
+/// - TLS 1.3: after the EncryptedExtensions, if a PSK key exchange mode was negotiated, as mbedtls
+///   then skips the certificate messages and goes on with the server's Finished
+///
+/// `progress` is called with the new state whenever the step changed it.
+unsafe fn handshake_step(
+    ssl_context: *mut mbedtls_ssl_context,
+    resumed: &mut bool,
+    progress: Option<fn(HandshakeState)>,
+) -> i32 {
+    let state = (*ssl_context).private_state;
+    // The session ID offered by the client, the ServerHello replaces it unless it resumes the
+    // session
+    let offered_id = match (*ssl_context).private_session_negotiate.as_ref() {
+        Some(session) if state == mbedtls_ssl_states_MBEDTLS_SSL_SERVER_HELLO as c_int => {
+            Some((session.private_id, session.private_id_len))
+        }
+        _ => None,
+    };
+    let res = mbedtls_ssl_handshake_step(ssl_context);
+    let new_state = (*ssl_context).private_state;
+    if new_state != state {
+        let tls_version = (*ssl_context).private_tls_version;
+        if let (Some((id, id_len)), Some(session)) = (
+            offered_id,
+            (*ssl_context).private_session_negotiate.as_ref(),
+        ) {
+            if tls_version == mbedtls_ssl_protocol_version_MBEDTLS_SSL_VERSION_TLS1_2 {
+                *resumed = id_len != 0
+                    && session.private_id_len == id_len
+                    && session.private_id[..id_len] == id[..id_len];
+            }
+        }
+        if state == mbedtls_ssl_states_MBEDTLS_SSL_ENCRYPTED_EXTENSIONS as c_int
+            && tls_version == mbedtls_ssl_protocol_version_MBEDTLS_SSL_VERSION_TLS1_3
+        {
+            *resumed = new_state == mbedtls_ssl_states_MBEDTLS_SSL_SERVER_FINISHED as c_int;
+        }
+    }
+    if let Some(progress) = progress {
+        if new_state != state {
+            if let Some(new_state) = HandshakeState::from_mbed_tls(new_state) {
+                progress(new_state);
+            }
         }
     }
+    res
 }
 
 unsafe extern "C" fn dbg_print(
-    _arg: *mut c_void,
+    arg: *mut c_void,
     lvl: i32,
     file: *const i8,
     line: i32,
     msg: *const i8,
 ) {
+    let level = if arg.is_null() {
+        DEBUG_LEVEL
+    } else {
+        arg as usize as u32 - 1
+    };
+    if lvl as u32 > level {
+        return;
+    }
+
     let msg = CStr::from_ptr(msg as *const i8);
     let file = CStr::from_ptr(file as *const i8);
     log::info!(
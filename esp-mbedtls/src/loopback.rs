@@ -0,0 +1,162 @@
+//! In-memory transport to connect two sessions without a network.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+/// A bidirectional in-memory pipe, buffering up to `N` bytes in each direction.
+///
+/// [Loopback::split] returns its two ends, which implement the `embedded-io-async` `Read` and
+/// `Write` traits and can be used as the stream of a client and a server
+/// [Session](crate::asynch::Session). As each end waits for the other, both handshakes have to be
+/// driven concurrently, e.g. with `embassy_futures::join`.
+///
+/// This makes it possible to test the TLS configuration (certificates, versions, error mapping,
+/// ...) of both sides entirely in RAM.
+///
+/// # Examples
+/// ```
+/// let loopback = Loopback::<4096>::new();
+/// let (client, server) = loopback.split();
+/// let client = Session::<_, 4096>::new(client, "localhost", Mode::Client, TlsVersion::Tls1_3, client_certificates).unwrap();
+/// let server = Session::<_, 4096>::new(server, "", Mode::Server, TlsVersion::Tls1_3, server_certificates).unwrap();
+/// let (client, server) = join(client.connect(), server.connect()).await;
+/// ```
+pub struct Loopback<const N: usize> {
+    a_to_b: RefCell<Channel<N>>,
+    b_to_a: RefCell<Channel<N>>,
+}
+
+impl<const N: usize> Loopback<N> {
+    /// Create a pipe with both directions empty
+    pub const fn new() -> Self {
+        Self {
+            a_to_b: RefCell::new(Channel::new()),
+            b_to_a: RefCell::new(Channel::new()),
+        }
+    }
+
+    /// Returns the two ends of the pipe. Data written to one end can be read from the other.
+    pub fn split(&self) -> (LoopbackEnd<'_, N>, LoopbackEnd<'_, N>) {
+        (
+            LoopbackEnd {
+                rx: &self.b_to_a,
+                tx: &self.a_to_b,
+            },
+            LoopbackEnd {
+                rx: &self.a_to_b,
+                tx: &self.b_to_a,
+            },
+        )
+    }
+}
+
+impl<const N: usize> Default for Loopback<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One end of a [Loopback]
+///
+/// Dropping an end closes the pipe: the other end reads the remaining data followed by an end of
+/// stream, and its writes fail with [embedded_io::ErrorKind::BrokenPipe].
+pub struct LoopbackEnd<'a, const N: usize> {
+    rx: &'a RefCell<Channel<N>>,
+    tx: &'a RefCell<Channel<N>>,
+}
+
+impl<'a, const N: usize> Drop for LoopbackEnd<'a, N> {
+    fn drop(&mut self) {
+        self.rx.borrow_mut().close();
+        self.tx.borrow_mut().close();
+    }
+}
+
+impl<'a, const N: usize> embedded_io_async::ErrorType for LoopbackEnd<'a, N> {
+    type Error = embedded_io::ErrorKind;
+}
+
+impl<'a, const N: usize> embedded_io_async::Read for LoopbackEnd<'a, N> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        poll_fn(|cx| {
+            let mut channel = self.rx.borrow_mut();
+            if buf.is_empty() {
+                Poll::Ready(Ok(0))
+            } else if channel.len > 0 {
+                let len = usize::min(buf.len(), channel.len);
+                buf[..len].copy_from_slice(&channel.buffer[..len]);
+                let end = channel.len;
+                channel.buffer.copy_within(len..end, 0);
+                channel.len -= len;
+                if let Some(writer) = channel.writer.take() {
+                    writer.wake();
+                }
+                Poll::Ready(Ok(len))
+            } else if channel.closed {
+                Poll::Ready(Ok(0))
+            } else {
+                channel.reader = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl<'a, const N: usize> embedded_io_async::Write for LoopbackEnd<'a, N> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        poll_fn(|cx| {
+            let mut channel = self.tx.borrow_mut();
+            if channel.closed {
+                Poll::Ready(Err(embedded_io::ErrorKind::BrokenPipe))
+            } else if buf.is_empty() {
+                Poll::Ready(Ok(0))
+            } else if channel.len < N {
+                let start = channel.len;
+                let len = usize::min(buf.len(), N - start);
+                channel.buffer[start..][..len].copy_from_slice(&buf[..len]);
+                channel.len += len;
+                if let Some(reader) = channel.reader.take() {
+                    reader.wake();
+                }
+                Poll::Ready(Ok(len))
+            } else {
+                channel.writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Data flowing in one direction of a [Loopback]
+struct Channel<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    closed: bool,
+}
+
+impl<const N: usize> Channel<N> {
+    const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+            reader: None,
+            writer: None,
+            closed: false,
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+        if let Some(writer) = self.writer.take() {
+            writer.wake();
+        }
+    }
+}
@@ -117,6 +117,7 @@ async fn main(spawner: Spawner) -> ! {
         private_key: X509::pem(concat!(include_str!("./certs/private_key.pem"), "\0").as_bytes())
             .ok(),
         password: None,
+        crl: None,
     };
 
     let tls: Session<_, 4096> = Session::new(
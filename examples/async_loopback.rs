@@ -0,0 +1,145 @@
+//! Example connecting a client and a server over a `Loopback`.
+//! Both sessions run on the chip and exchange their records in RAM, so no network is needed.
+//!
+//! The client verifies the certificate of the server against `certs/ca_cert.pem`.
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+#[doc(hidden)]
+pub use esp_hal as hal;
+
+use embassy_executor::Spawner;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use esp_backtrace as _;
+use esp_mbedtls::asynch::Session;
+use esp_mbedtls::loopback::{Loopback, LoopbackEnd};
+use esp_mbedtls::{set_debug, Certificates, Mode, TlsVersion, X509};
+use esp_println::logger::init_logger;
+use esp_println::println;
+
+/// Only used for ROM functions and the heap
+#[allow(unused_imports)]
+use esp_wifi::{initialize, EspWifiInitFor};
+use hal::{
+    clock::ClockControl, peripherals::Peripherals, prelude::*, rng::Rng, system::SystemControl,
+    timer::timg::TimerGroup,
+};
+use static_cell::make_static;
+
+const BUFFER_SIZE: usize = 4096;
+
+#[main]
+async fn main(spawner: Spawner) -> ! {
+    init_logger(log::LevelFilter::Info);
+
+    // Init ESP-WIFI heap for malloc
+    let peripherals = Peripherals::take();
+    let system = SystemControl::new(peripherals.SYSTEM);
+    let clocks = ClockControl::max(system.clock_control).freeze();
+
+    #[cfg(target_arch = "xtensa")]
+    let timer = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1, &clocks, None).timer0;
+    #[cfg(target_arch = "riscv32")]
+    let timer = esp_hal::timer::systimer::SystemTimer::new(peripherals.SYSTIMER).alarm0;
+    let _ = initialize(
+        EspWifiInitFor::Wifi,
+        timer,
+        Rng::new(peripherals.RNG),
+        peripherals.RADIO_CLK,
+        &clocks,
+    )
+    .unwrap();
+
+    let timer_group0 = TimerGroup::new_async(peripherals.TIMG0, &clocks);
+    esp_hal_embassy::init(&clocks, timer_group0);
+
+    set_debug(0);
+
+    let loopback = &*make_static!(Loopback::<BUFFER_SIZE>::new());
+    let (client_end, server_end) = loopback.split();
+
+    // Each end waits for the other, so the server runs in its own task
+    spawner.spawn(server(server_end)).ok();
+
+    let tls: Session<_, BUFFER_SIZE> = Session::new(
+        client_end,
+        "esp-mbedtls.local",
+        Mode::Client,
+        TlsVersion::Tls1_2,
+        Certificates {
+            ca_chain: X509::pem(concat!(include_str!("./certs/ca_cert.pem"), "\0").as_bytes()).ok(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    println!("Client: start tls connect");
+    let mut tls = tls.connect().await.unwrap();
+    println!("Client: connected");
+
+    tls.write_all(b"ping").await.unwrap();
+
+    let mut buffer = [0u8; 4];
+    match tls.read(&mut buffer).await {
+        Ok(len) => println!(
+            "Client: received {:?}",
+            core::str::from_utf8(&buffer[..len])
+        ),
+        Err(e) => println!("Client: read error: {:?}", e),
+    }
+
+    println!("Done");
+    loop {
+        Timer::after(Duration::from_millis(1000)).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn server(stream: LoopbackEnd<'static, BUFFER_SIZE>) {
+    let tls: Session<_, BUFFER_SIZE> = Session::new(
+        stream,
+        "",
+        Mode::Server,
+        TlsVersion::Tls1_2,
+        Certificates {
+            certificate: X509::pem(
+                concat!(include_str!("./certs/certificate.pem"), "\0").as_bytes(),
+            )
+            .ok(),
+            private_key: X509::pem(
+                concat!(include_str!("./certs/private_key.pem"), "\0").as_bytes(),
+            )
+            .ok(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    println!("Server: start tls accept");
+    let mut tls = match tls.connect().await {
+        Ok(tls) => tls,
+        Err(e) => {
+            println!("Server: TLS error: {:?}", e);
+            return;
+        }
+    };
+    println!("Server: connected");
+
+    let mut buffer = [0u8; 4];
+    match tls.read(&mut buffer).await {
+        Ok(len) => println!(
+            "Server: received {:?}",
+            core::str::from_utf8(&buffer[..len])
+        ),
+        Err(e) => {
+            println!("Server: read error: {:?}", e);
+            return;
+        }
+    }
+
+    if let Err(e) = tls.write_all(b"pong").await {
+        println!("Server: write error: {:?}", e);
+    }
+}
@@ -0,0 +1,344 @@
+//! Run tests of the async sessions to ensure their functionality
+//!
+//! The clients and servers of the tests are connected over a `Loopback`, so no network is needed.
+//! Each test panics if it fails.
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+#[doc(hidden)]
+pub use esp_hal as hal;
+
+use core::cell::Cell;
+use core::pin::pin;
+
+use embassy_executor::Spawner;
+use embassy_futures::join::{join, join3};
+use embassy_futures::select::{select, Either};
+use embassy_futures::{poll_once, yield_now};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use esp_backtrace as _;
+use esp_mbedtls::asynch::{AsyncConnectedSession, Session};
+use esp_mbedtls::loopback::{Loopback, LoopbackEnd};
+use esp_mbedtls::{set_debug, Certificates, Mode, TlsError, TlsVersion, TrustAnchor, X509};
+use esp_println::{logger::init_logger, println};
+
+/// Only used for ROM functions and the heap
+#[allow(unused_imports)]
+use esp_wifi::{initialize, EspWifiInitFor};
+use hal::{
+    clock::ClockControl, peripherals::Peripherals, prelude::*, rng::Rng, system::SystemControl,
+    timer::timg::TimerGroup,
+};
+
+const BUFFER_SIZE: usize = 4096;
+
+/// The anchor of `certs/ca_cert.pem`, which issued the certificate of the server
+static CA_ANCHOR: [TrustAnchor<'static>; 1] = [TrustAnchor {
+    subject_dn: include_bytes!("./certs/ca_subject.der"),
+    spki: include_bytes!("./certs/ca_spki.der"),
+}];
+
+#[main]
+async fn main(_spawner: Spawner) -> ! {
+    init_logger(log::LevelFilter::Info);
+
+    // Init ESP-WIFI heap for malloc
+    let peripherals = Peripherals::take();
+    let system = SystemControl::new(peripherals.SYSTEM);
+    let clocks = ClockControl::max(system.clock_control).freeze();
+
+    #[cfg(target_arch = "xtensa")]
+    let timer = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1, &clocks, None).timer0;
+    #[cfg(target_arch = "riscv32")]
+    let timer = esp_hal::timer::systimer::SystemTimer::new(peripherals.SYSTIMER).alarm0;
+    let _ = initialize(
+        EspWifiInitFor::Wifi,
+        timer,
+        Rng::new(peripherals.RNG),
+        peripherals.RADIO_CLK,
+        &clocks,
+    )
+    .unwrap();
+
+    let timer_group0 = TimerGroup::new_async(peripherals.TIMG0, &clocks);
+    esp_hal_embassy::init(&clocks, timer_group0);
+
+    set_debug(0);
+
+    println!("Testing a handshake after a failed one");
+    handshake_after_failure().await;
+    println!("Testing trust anchors without a CA chain");
+    trust_anchors_without_ca_chain().await;
+    println!("Testing dropped handshakes");
+    drop_handshake().await;
+    println!("Testing writes over a stream taking a few bytes at a time");
+    write_all_short_writes().await;
+    println!("Testing cancelled reads");
+    cancelled_reads().await;
+    println!("Testing writes to a slow peer");
+    slow_peer().await;
+    println!("Testing a server name that is dropped before the handshake");
+    short_lived_servername().await;
+    println!("Testing a client without SNI");
+    client_without_sni().await;
+
+    println!("All tests passed");
+    loop {}
+}
+
+/// The certificates of the server, for the name `esp-mbedtls.local`
+fn server_certificates() -> Certificates<'static> {
+    Certificates {
+        certificate: X509::pem(concat!(include_str!("./certs/certificate.pem"), "\0").as_bytes())
+            .ok(),
+        private_key: X509::pem(concat!(include_str!("./certs/private_key.pem"), "\0").as_bytes())
+            .ok(),
+        ..Default::default()
+    }
+}
+
+/// The certificates of a client verifying the server
+fn client_certificates() -> Certificates<'static> {
+    Certificates {
+        ca_chain: X509::pem(concat!(include_str!("./certs/ca_cert.pem"), "\0").as_bytes()).ok(),
+        ..Default::default()
+    }
+}
+
+/// A client verifying the certificate of the server
+fn client<S>(stream: S) -> Session<S, BUFFER_SIZE> {
+    Session::new(
+        stream,
+        "esp-mbedtls.local",
+        Mode::Client,
+        TlsVersion::Tls1_2,
+        client_certificates(),
+    )
+    .unwrap()
+}
+
+fn server<S>(stream: S) -> Session<S, BUFFER_SIZE> {
+    Session::new(
+        stream,
+        "",
+        Mode::Server,
+        TlsVersion::Tls1_2,
+        server_certificates(),
+    )
+    .unwrap()
+}
+
+/// A connected client and server
+type Connected<'a, const N: usize> = (
+    AsyncConnectedSession<LoopbackEnd<'a, N>, BUFFER_SIZE>,
+    AsyncConnectedSession<LoopbackEnd<'a, N>, BUFFER_SIZE>,
+);
+
+/// Connect a client and a server over `loopback`
+async fn connect<const N: usize>(loopback: &Loopback<N>) -> Connected<'_, N> {
+    let (client_end, server_end) = loopback.split();
+    let (client, server) = join(client(client_end).connect(), server(server_end).connect()).await;
+    (client.unwrap(), server.unwrap())
+}
+
+/// A session whose handshake failed can perform a new handshake over a new stream
+async fn handshake_after_failure() {
+    let broken = Loopback::<BUFFER_SIZE>::new();
+    let (client_end, server_end) = broken.split();
+    let mut client = client(client_end);
+
+    // Send the ClientHello, then drop the handshake while it waits for the server
+    assert!(poll_once(client.handshake()).is_pending());
+    // The server is gone, so continuing the handshake fails
+    drop(server_end);
+    assert!(client.handshake().await.is_err());
+
+    // Nothing of the failed handshake reaches the new stream
+    let loopback = Loopback::<BUFFER_SIZE>::new();
+    let (client_end, server_end) = loopback.split();
+    let mut client = client.with_stream(client_end);
+    let (client_res, server_res) = join(client.handshake(), server(server_end).connect()).await;
+    client_res.unwrap();
+    server_res.unwrap();
+}
+
+/// A client verifies the server with trust anchors alone, including its name
+async fn trust_anchors_without_ca_chain() {
+    for (servername, expected) in [
+        ("esp-mbedtls.local", Ok(())),
+        ("other.local", Err(TlsError::HostnameMismatch)),
+    ] {
+        let loopback = Loopback::<BUFFER_SIZE>::new();
+        let (client_end, server_end) = loopback.split();
+        let client = Session::<_, BUFFER_SIZE>::new(
+            client_end,
+            servername,
+            Mode::Client,
+            TlsVersion::Tls1_2,
+            Certificates::default(),
+        )
+        .unwrap()
+        .with_trust_anchors(&CA_ANCHOR)
+        .unwrap();
+        let (client_res, _) = join(client.connect(), server(server_end).connect()).await;
+        assert_eq!(client_res.map(|_| ()), expected);
+    }
+}
+
+/// Dropping a handshake in any state frees its session once
+async fn drop_handshake() {
+    for steps in 0..8 {
+        let loopback = Loopback::<BUFFER_SIZE>::new();
+        let (client_end, server_end) = loopback.split();
+        let mut client = pin!(client(client_end).connect());
+        let mut server = pin!(server(server_end).connect());
+        for _ in 0..steps {
+            if poll_once(client.as_mut()).is_ready() || poll_once(server.as_mut()).is_ready() {
+                break;
+            }
+        }
+    }
+
+    // Freeing a session twice would corrupt the heap, which makes the next handshake fail
+    let loopback = Loopback::<BUFFER_SIZE>::new();
+    connect(&loopback).await;
+}
+
+/// Data larger than a record arrives intact over a stream that takes a few bytes per write
+async fn write_all_short_writes() {
+    let loopback = Loopback::<64>::new();
+    let (mut client, mut server) = connect(&loopback).await;
+
+    let mut message = [0u8; 17 * 1024];
+    for (i, byte) in message.iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    let mut received = [0u8; 17 * 1024];
+    let (_, read_res) = join(
+        async {
+            client.write_all(&message).await.unwrap();
+            client.flush().await.unwrap();
+        },
+        server.read_exact(&mut received),
+    )
+    .await;
+    read_res.unwrap();
+    assert!(received == message);
+}
+
+/// Reads cancelled in the middle of a record don't lose or corrupt data
+async fn cancelled_reads() {
+    // The records arrive in pieces of 16 bytes
+    let loopback = Loopback::<16>::new();
+    let (mut client, mut server) = connect(&loopback).await;
+
+    let mut message = [0u8; 1000];
+    for (i, byte) in message.iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    let (_, received) = join(
+        async {
+            // Records of different sizes
+            for chunk in message.chunks(150) {
+                client.write_all(chunk).await.unwrap();
+            }
+            client.flush().await.unwrap();
+        },
+        async {
+            let mut received = [0u8; 1000];
+            let mut len = 0;
+            while len < received.len() {
+                // Like a timeout with `select!`, the read is dropped if it can't complete right away
+                if let Either::First(res) =
+                    select(server.read(&mut received[len..]), yield_now()).await
+                {
+                    len += res.unwrap();
+                }
+            }
+            received
+        },
+    )
+    .await;
+    assert!(received == message);
+}
+
+/// A write waiting for a peer that reads slowly lets the other tasks run
+async fn slow_peer() {
+    let loopback = Loopback::<16>::new();
+    let (mut client, mut server) = connect(&loopback).await;
+
+    let message = [0x5a; 1000];
+    let written = Cell::new(false);
+    let ticks = Cell::new(0);
+    join3(
+        async {
+            // One record at a time, read by the peer every 10 ms
+            for chunk in message.chunks(100) {
+                client.write_all(chunk).await.unwrap();
+            }
+            client.flush().await.unwrap();
+            written.set(true);
+        },
+        async {
+            let mut received = [0u8; 1000];
+            let mut len = 0;
+            while len < received.len() {
+                Timer::after(Duration::from_millis(10)).await;
+                len += server.read(&mut received[len..]).await.unwrap();
+            }
+            assert!(received == message);
+        },
+        async {
+            while !written.get() {
+                ticks.set(ticks.get() + 1);
+                yield_now().await;
+            }
+        },
+    )
+    .await;
+    // A write spinning on the full stream would have blocked this task until it was done
+    assert!(ticks.get() > 1);
+}
+
+/// The server name only has to live while the session is created
+async fn short_lived_servername() {
+    let loopback = Loopback::<BUFFER_SIZE>::new();
+    let (client_end, server_end) = loopback.split();
+    let client = {
+        let mut servername = heapless::String::<32>::new();
+        servername.push_str("esp-mbedtls.local").unwrap();
+        let client = Session::<_, BUFFER_SIZE>::new(
+            client_end,
+            &servername,
+            Mode::Client,
+            TlsVersion::Tls1_2,
+            client_certificates(),
+        )
+        .unwrap();
+        // Overwrite the name, a session still pointing to it would fail the hostname check
+        servername.clear();
+        servername
+            .push_str("xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx")
+            .unwrap();
+        client
+    };
+
+    let (client_res, server_res) = join(client.connect(), server(server_end).connect()).await;
+    client_res.unwrap();
+    server_res.unwrap();
+}
+
+/// A server presents its certificate to a client that doesn't send a server name
+async fn client_without_sni() {
+    let loopback = Loopback::<BUFFER_SIZE>::new();
+    let (client_end, server_end) = loopback.split();
+    let client =
+        Session::<_, BUFFER_SIZE>::new_insecure_client(client_end, "", TlsVersion::Tls1_2).unwrap();
+
+    // Without a certificate, the server couldn't agree on a ciphersuite with the client
+    let (client_res, server_res) = join(client.connect(), server(server_end).connect()).await;
+    client_res.unwrap();
+    server_res.unwrap();
+}
@@ -16,8 +16,8 @@ use embassy_net::{Config, IpListenEndpoint, Stack, StackResources};
 use embassy_executor::Spawner;
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
-use esp_mbedtls::{asynch::Session, set_debug, Certificates, Mode, TlsVersion};
-use esp_mbedtls::{TlsError, X509};
+use esp_mbedtls::{asynch::TlsAcceptor, set_debug, Certificates, HardwareRsa, TlsVersion};
+use esp_mbedtls::{error_codes, TlsError, X509};
 use esp_println::logger::init_logger;
 use esp_println::{print, println};
 use esp_wifi::wifi::{
@@ -38,7 +38,7 @@ const PASSWORD: &str = env!("PASSWORD");
 async fn main(spawner: Spawner) -> ! {
     init_logger(log::LevelFilter::Info);
 
-    let mut peripherals = Peripherals::take();
+    let peripherals = Peripherals::take();
     let system = SystemControl::new(peripherals.SYSTEM);
     let clocks = ClockControl::max(system.clock_control).freeze();
 
@@ -102,6 +102,23 @@ async fn main(spawner: Spawner) -> ! {
 
     let mut socket = TcpSocket::new(&stack, &mut rx_buffer, &mut tx_buffer);
     socket.set_timeout(Some(Duration::from_secs(10)));
+
+    let _rsa = HardwareRsa::new(peripherals.RSA);
+    let acceptor = TlsAcceptor::new(
+        TlsVersion::Tls1_2,
+        Certificates {
+            // Use self-signed certificates
+            certificate: X509::pem(
+                concat!(include_str!("./certs/certificate.pem"), "\0").as_bytes(),
+            )
+            .ok(),
+            private_key: X509::pem(
+                concat!(include_str!("./certs/private_key.pem"), "\0").as_bytes(),
+            )
+            .ok(),
+            ..Default::default()
+        },
+    );
     loop {
         println!("Waiting for connection...");
         let r = socket
@@ -123,29 +140,8 @@ async fn main(spawner: Spawner) -> ! {
 
         let mut buffer = [0u8; 1024];
         let mut pos = 0;
-        let tls: Session<_, 4096> = Session::new(
-            &mut socket,
-            "",
-            Mode::Server,
-            TlsVersion::Tls1_2,
-            Certificates {
-                // Use self-signed certificates
-                certificate: X509::pem(
-                    concat!(include_str!("./certs/certificate.pem"), "\0").as_bytes(),
-                )
-                .ok(),
-                private_key: X509::pem(
-                    concat!(include_str!("./certs/private_key.pem"), "\0").as_bytes(),
-                )
-                .ok(),
-                ..Default::default()
-            },
-        )
-        .unwrap()
-        .with_hardware_rsa(&mut peripherals.RSA);
-
         println!("Start tls connect");
-        match tls.connect().await {
+        match acceptor.accept::<_, 4096>(&mut socket).await {
             Ok(mut connected_session) => {
                 log::info!("Got session");
                 loop {
@@ -192,11 +188,11 @@ async fn main(spawner: Spawner) -> ! {
 
                 drop(connected_session);
             }
-            Err(TlsError::MbedTlsError(-30592)) => {
+            Err(TlsError::MbedTlsError(error_codes::FATAL_ALERT_MESSAGE)) => {
                 println!("Fatal message: Please enable the exception for a self-signed certificate in your browser");
             }
             Err(error) => {
-                panic!("{:?}", error);
+                println!("TLS error: {:?}", error);
             }
         }
         println!("Closing socket");
@@ -34,7 +34,7 @@ use embassy_executor::Spawner;
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
 use esp_mbedtls::{asynch::Session, set_debug, Certificates, Mode, TlsVersion};
-use esp_mbedtls::{TlsError, X509};
+use esp_mbedtls::{error_codes, TlsError, X509};
 use esp_println::logger::init_logger;
 use esp_println::{print, println};
 use esp_wifi::wifi::{
@@ -214,7 +214,7 @@ async fn main(spawner: Spawner) -> ! {
             Err(TlsError::NoClientCertificate) => {
                 println!("Error: No client certificates given. Please provide client certificates during your request");
             }
-            Err(TlsError::MbedTlsError(-30592)) => {
+            Err(TlsError::MbedTlsError(error_codes::FATAL_ALERT_MESSAGE)) => {
                 println!("Fatal message: Please enable the exception for a self-signed certificate in your browser");
             }
             Err(error) => {
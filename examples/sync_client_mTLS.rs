@@ -114,6 +114,7 @@ fn main() -> ! {
         private_key: X509::pem(concat!(include_str!("./certs/private_key.pem"), "\0").as_bytes())
             .ok(),
         password: None,
+        crl: None,
     };
 
     let tls = Session::new(
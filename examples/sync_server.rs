@@ -11,7 +11,7 @@ pub use esp_hal as hal;
 
 use embedded_io::*;
 use esp_backtrace as _;
-use esp_mbedtls::{set_debug, Mode, TlsError, TlsVersion, X509};
+use esp_mbedtls::{error_codes, set_debug, Mode, TlsError, TlsVersion, X509};
 use esp_mbedtls::{Certificates, Session};
 use esp_println::{logger::init_logger, print, println};
 use esp_wifi::{
@@ -182,7 +182,7 @@ fn main() -> ! {
 
                     drop(connected_session);
                 }
-                Err(TlsError::MbedTlsError(-30592)) => {
+                Err(TlsError::MbedTlsError(error_codes::FATAL_ALERT_MESSAGE)) => {
                     println!("Fatal message: Please enable the exception for a self-signed certificate in your browser");
                 }
                 Err(error) => {